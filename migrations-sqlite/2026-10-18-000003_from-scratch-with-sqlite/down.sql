@@ -0,0 +1,7 @@
+DROP TRIGGER scribbles_fts_update;
+DROP TRIGGER scribbles_fts_delete;
+DROP TRIGGER scribbles_fts_insert;
+DROP TABLE scribbles_fts;
+DROP TABLE scribbles;
+DROP TABLE tags;
+DROP TABLE taggings;
@@ -0,0 +1,40 @@
+CREATE TABLE scribbles (
+    id         INTEGER PRIMARY KEY,
+    created_at BIGINT NOT NULL,
+    updated_at BIGINT,
+    text       TEXT NOT NULL
+);
+
+CREATE TABLE tags (
+    id         INTEGER PRIMARY KEY,
+    created_at BIGINT NOT NULL,
+    text       TEXT UNIQUE NOT NULL
+);
+
+CREATE TABLE taggings (
+    id          INTEGER PRIMARY KEY,
+    created_at  BIGINT NOT NULL,
+    scribble_id BIGINT NOT NULL,
+    tag_id      BIGINT NOT NULL,
+    UNIQUE (scribble_id, tag_id)
+);
+
+CREATE INDEX taggings_scribbleid_tagid ON taggings (scribble_id, tag_id);
+CREATE INDEX taggings_tagid_scribbleid ON taggings (tag_id, scribble_id);
+CREATE INDEX scribbles_createdat_id ON scribbles (created_at DESC, id DESC);
+
+-- Full-text index over scribbles.text, kept in sync by the triggers below
+CREATE VIRTUAL TABLE scribbles_fts USING fts5 (text, content='scribbles', content_rowid='id');
+
+CREATE TRIGGER scribbles_fts_insert AFTER INSERT ON scribbles BEGIN
+    INSERT INTO scribbles_fts (rowid, text) VALUES (new.id, new.text);
+END;
+
+CREATE TRIGGER scribbles_fts_delete AFTER DELETE ON scribbles BEGIN
+    INSERT INTO scribbles_fts (scribbles_fts, rowid, text) VALUES ('delete', old.id, old.text);
+END;
+
+CREATE TRIGGER scribbles_fts_update AFTER UPDATE OF text ON scribbles BEGIN
+    INSERT INTO scribbles_fts (scribbles_fts, rowid, text) VALUES ('delete', old.id, old.text);
+    INSERT INTO scribbles_fts (rowid, text) VALUES (new.id, new.text);
+END;
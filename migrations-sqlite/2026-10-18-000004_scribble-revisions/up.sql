@@ -0,0 +1,12 @@
+CREATE TABLE scribble_revisions (
+    id          INTEGER PRIMARY KEY,
+    created_at  BIGINT NOT NULL,
+    scribble_id BIGINT NOT NULL,
+    revision    INTEGER NOT NULL,
+    text        TEXT NOT NULL,
+    UNIQUE (scribble_id, revision)
+);
+
+-- Existing scribbles start their history with their current text
+INSERT INTO scribble_revisions (created_at, scribble_id, revision, text)
+    SELECT COALESCE(updated_at, created_at), id, 1, text FROM scribbles;
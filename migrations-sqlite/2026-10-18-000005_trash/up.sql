@@ -0,0 +1,4 @@
+-- Deleted scribbles stay in the trash until purged
+ALTER TABLE scribbles ADD COLUMN deleted_at BIGINT;
+
+CREATE INDEX scribbles_deletedat ON scribbles (deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,27 @@
+CREATE TABLE scribble_revisions_old (
+    id          INTEGER PRIMARY KEY,
+    created_at  BIGINT NOT NULL,
+    scribble_id BIGINT NOT NULL,
+    revision    INTEGER NOT NULL,
+    text        TEXT NOT NULL,
+    UNIQUE (scribble_id, revision)
+);
+INSERT INTO scribble_revisions_old (id, created_at, scribble_id, revision, text)
+    SELECT id, created_at, scribble_id, revision, text FROM scribble_revisions;
+DROP TABLE scribble_revisions;
+ALTER TABLE scribble_revisions_old RENAME TO scribble_revisions;
+
+CREATE TABLE taggings_old (
+    id          INTEGER PRIMARY KEY,
+    created_at  BIGINT NOT NULL,
+    scribble_id BIGINT NOT NULL,
+    tag_id      BIGINT NOT NULL,
+    UNIQUE (scribble_id, tag_id)
+);
+INSERT INTO taggings_old (id, created_at, scribble_id, tag_id)
+    SELECT id, created_at, scribble_id, tag_id FROM taggings;
+DROP TABLE taggings;
+ALTER TABLE taggings_old RENAME TO taggings;
+
+CREATE INDEX taggings_scribbleid_tagid ON taggings (scribble_id, tag_id);
+CREATE INDEX taggings_tagid_scribbleid ON taggings (tag_id, scribble_id);
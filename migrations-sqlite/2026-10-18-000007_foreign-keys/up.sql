@@ -0,0 +1,33 @@
+-- Remove the orphans left behind while there were no foreign keys
+DELETE FROM taggings WHERE scribble_id NOT IN (SELECT id FROM scribbles);
+DELETE FROM taggings WHERE tag_id NOT IN (SELECT id FROM tags);
+DELETE FROM scribble_revisions WHERE scribble_id NOT IN (SELECT id FROM scribbles);
+
+-- SQLite cannot add constraints to a table, so both are rebuilt
+CREATE TABLE taggings_new (
+    id          INTEGER PRIMARY KEY,
+    created_at  BIGINT NOT NULL,
+    scribble_id BIGINT NOT NULL REFERENCES scribbles (id) ON DELETE CASCADE,
+    tag_id      BIGINT NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+    UNIQUE (scribble_id, tag_id)
+);
+INSERT INTO taggings_new (id, created_at, scribble_id, tag_id)
+    SELECT id, created_at, scribble_id, tag_id FROM taggings;
+DROP TABLE taggings;
+ALTER TABLE taggings_new RENAME TO taggings;
+
+CREATE INDEX taggings_scribbleid_tagid ON taggings (scribble_id, tag_id);
+CREATE INDEX taggings_tagid_scribbleid ON taggings (tag_id, scribble_id);
+
+CREATE TABLE scribble_revisions_new (
+    id          INTEGER PRIMARY KEY,
+    created_at  BIGINT NOT NULL,
+    scribble_id BIGINT NOT NULL REFERENCES scribbles (id) ON DELETE CASCADE,
+    revision    INTEGER NOT NULL,
+    text        TEXT NOT NULL,
+    UNIQUE (scribble_id, revision)
+);
+INSERT INTO scribble_revisions_new (id, created_at, scribble_id, revision, text)
+    SELECT id, created_at, scribble_id, revision, text FROM scribble_revisions;
+DROP TABLE scribble_revisions;
+ALTER TABLE scribble_revisions_new RENAME TO scribble_revisions;
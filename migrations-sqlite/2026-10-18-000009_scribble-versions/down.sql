@@ -0,0 +1 @@
+ALTER TABLE scribbles DROP COLUMN version;
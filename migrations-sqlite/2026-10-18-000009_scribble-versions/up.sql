@@ -0,0 +1,3 @@
+-- Counts the changes to a scribble, so that clients can tell whether the
+-- copy they edited is still current
+ALTER TABLE scribbles ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
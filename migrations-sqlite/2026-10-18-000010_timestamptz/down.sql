@@ -0,0 +1,13 @@
+UPDATE scribbles SET
+    created_at = CAST(strftime('%s', substr(created_at, 1, 19)) AS INTEGER) * 1000000000 + CAST(substr(created_at, 21, 6) AS INTEGER) * 1000,
+    updated_at = CAST(strftime('%s', substr(updated_at, 1, 19)) AS INTEGER) * 1000000000 + CAST(substr(updated_at, 21, 6) AS INTEGER) * 1000,
+    deleted_at = CAST(strftime('%s', substr(deleted_at, 1, 19)) AS INTEGER) * 1000000000 + CAST(substr(deleted_at, 21, 6) AS INTEGER) * 1000,
+    expires_at = CAST(strftime('%s', substr(expires_at, 1, 19)) AS INTEGER) * 1000000000 + CAST(substr(expires_at, 21, 6) AS INTEGER) * 1000;
+
+UPDATE tags SET created_at = CAST(strftime('%s', substr(created_at, 1, 19)) AS INTEGER) * 1000000000 + CAST(substr(created_at, 21, 6) AS INTEGER) * 1000;
+
+UPDATE taggings SET created_at = CAST(strftime('%s', substr(created_at, 1, 19)) AS INTEGER) * 1000000000 + CAST(substr(created_at, 21, 6) AS INTEGER) * 1000;
+
+UPDATE scribble_revisions SET created_at = CAST(strftime('%s', substr(created_at, 1, 19)) AS INTEGER) * 1000000000 + CAST(substr(created_at, 21, 6) AS INTEGER) * 1000;
+
+UPDATE idempotency_keys SET created_at = CAST(strftime('%s', substr(created_at, 1, 19)) AS INTEGER) * 1000000000 + CAST(substr(created_at, 21, 6) AS INTEGER) * 1000;
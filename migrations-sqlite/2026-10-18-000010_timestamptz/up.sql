@@ -0,0 +1,17 @@
+-- Timestamps were nanoseconds since the epoch. SQLite has no type for them,
+-- so they become RFC 3339 text in UTC with microseconds, which sorts in the
+-- same order. The columns keep their declared type, whose integer affinity
+-- leaves such text alone.
+UPDATE scribbles SET
+    created_at = strftime('%Y-%m-%dT%H:%M:%S', created_at / 1000000000, 'unixepoch') || printf('.%06dZ', created_at / 1000 % 1000000),
+    updated_at = strftime('%Y-%m-%dT%H:%M:%S', updated_at / 1000000000, 'unixepoch') || printf('.%06dZ', updated_at / 1000 % 1000000),
+    deleted_at = strftime('%Y-%m-%dT%H:%M:%S', deleted_at / 1000000000, 'unixepoch') || printf('.%06dZ', deleted_at / 1000 % 1000000),
+    expires_at = strftime('%Y-%m-%dT%H:%M:%S', expires_at / 1000000000, 'unixepoch') || printf('.%06dZ', expires_at / 1000 % 1000000);
+
+UPDATE tags SET created_at = strftime('%Y-%m-%dT%H:%M:%S', created_at / 1000000000, 'unixepoch') || printf('.%06dZ', created_at / 1000 % 1000000);
+
+UPDATE taggings SET created_at = strftime('%Y-%m-%dT%H:%M:%S', created_at / 1000000000, 'unixepoch') || printf('.%06dZ', created_at / 1000 % 1000000);
+
+UPDATE scribble_revisions SET created_at = strftime('%Y-%m-%dT%H:%M:%S', created_at / 1000000000, 'unixepoch') || printf('.%06dZ', created_at / 1000 % 1000000);
+
+UPDATE idempotency_keys SET created_at = strftime('%Y-%m-%dT%H:%M:%S', created_at / 1000000000, 'unixepoch') || printf('.%06dZ', created_at / 1000 % 1000000);
@@ -0,0 +1 @@
+DROP INDEX scribbles_createdat_id;
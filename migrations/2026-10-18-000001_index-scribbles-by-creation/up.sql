@@ -0,0 +1 @@
+CREATE INDEX scribbles_createdat_id ON scribbles (created_at DESC, id DESC);
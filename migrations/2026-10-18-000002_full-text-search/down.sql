@@ -0,0 +1,2 @@
+DROP INDEX scribbles_texttsv;
+ALTER TABLE scribbles DROP COLUMN text_tsv;
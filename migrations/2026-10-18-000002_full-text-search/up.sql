@@ -0,0 +1,6 @@
+-- The 'simple' configuration does no stemming, so that notes written in any
+-- language are indexed word by word.
+ALTER TABLE scribbles
+    ADD COLUMN text_tsv TSVECTOR GENERATED ALWAYS AS (to_tsvector('simple', text)) STORED;
+
+CREATE INDEX scribbles_texttsv ON scribbles USING GIN (text_tsv);
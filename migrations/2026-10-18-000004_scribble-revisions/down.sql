@@ -0,0 +1 @@
+DROP TABLE scribble_revisions;
@@ -0,0 +1,3 @@
+DELETE FROM scribbles WHERE deleted_at IS NOT NULL;
+DROP INDEX scribbles_deletedat;
+ALTER TABLE scribbles DROP COLUMN deleted_at;
@@ -0,0 +1,3 @@
+ALTER TABLE tags DROP COLUMN ttl;
+DROP INDEX scribbles_expiresat;
+ALTER TABLE scribbles DROP COLUMN expires_at;
@@ -0,0 +1,7 @@
+ALTER TABLE scribbles ADD COLUMN expires_at BIGINT;
+
+CREATE INDEX scribbles_expiresat ON scribbles (expires_at) WHERE expires_at IS NOT NULL;
+
+-- Scribbles tagged with a tag having a TTL, in seconds, expire that long
+-- after being tagged
+ALTER TABLE tags ADD COLUMN ttl BIGINT;
@@ -0,0 +1,3 @@
+ALTER TABLE scribble_revisions DROP CONSTRAINT scribble_revisions_scribble_id_fkey;
+ALTER TABLE taggings DROP CONSTRAINT taggings_tag_id_fkey;
+ALTER TABLE taggings DROP CONSTRAINT taggings_scribble_id_fkey;
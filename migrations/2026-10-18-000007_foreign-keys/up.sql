@@ -0,0 +1,11 @@
+-- Remove the orphans left behind while there were no foreign keys
+DELETE FROM taggings WHERE scribble_id NOT IN (SELECT id FROM scribbles);
+DELETE FROM taggings WHERE tag_id NOT IN (SELECT id FROM tags);
+DELETE FROM scribble_revisions WHERE scribble_id NOT IN (SELECT id FROM scribbles);
+
+ALTER TABLE taggings
+    ADD CONSTRAINT taggings_scribble_id_fkey FOREIGN KEY (scribble_id) REFERENCES scribbles (id) ON DELETE CASCADE,
+    ADD CONSTRAINT taggings_tag_id_fkey FOREIGN KEY (tag_id) REFERENCES tags (id) ON DELETE CASCADE;
+
+ALTER TABLE scribble_revisions
+    ADD CONSTRAINT scribble_revisions_scribble_id_fkey FOREIGN KEY (scribble_id) REFERENCES scribbles (id) ON DELETE CASCADE;
@@ -0,0 +1,12 @@
+-- Responses to write requests sent with an Idempotency-Key header, which
+-- are replayed when the request is retried. The status and body stay NULL
+-- while the first request is still being handled.
+CREATE TABLE idempotency_keys (
+    key         TEXT PRIMARY KEY,
+    created_at  BIGINT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    status      INTEGER,
+    body        TEXT
+);
+
+CREATE INDEX idempotency_keys_createdat ON idempotency_keys (created_at);
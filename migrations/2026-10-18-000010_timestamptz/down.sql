@@ -0,0 +1,17 @@
+ALTER TABLE scribbles
+    ALTER COLUMN created_at TYPE BIGINT USING EXTRACT(EPOCH FROM created_at) * 1000000000,
+    ALTER COLUMN updated_at TYPE BIGINT USING EXTRACT(EPOCH FROM updated_at) * 1000000000,
+    ALTER COLUMN deleted_at TYPE BIGINT USING EXTRACT(EPOCH FROM deleted_at) * 1000000000,
+    ALTER COLUMN expires_at TYPE BIGINT USING EXTRACT(EPOCH FROM expires_at) * 1000000000;
+
+ALTER TABLE tags
+    ALTER COLUMN created_at TYPE BIGINT USING EXTRACT(EPOCH FROM created_at) * 1000000000;
+
+ALTER TABLE taggings
+    ALTER COLUMN created_at TYPE BIGINT USING EXTRACT(EPOCH FROM created_at) * 1000000000;
+
+ALTER TABLE scribble_revisions
+    ALTER COLUMN created_at TYPE BIGINT USING EXTRACT(EPOCH FROM created_at) * 1000000000;
+
+ALTER TABLE idempotency_keys
+    ALTER COLUMN created_at TYPE BIGINT USING EXTRACT(EPOCH FROM created_at) * 1000000000;
@@ -0,0 +1,19 @@
+-- Timestamps were nanoseconds since the epoch, which timestamptz keeps to
+-- the microsecond
+ALTER TABLE scribbles
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING TIMESTAMPTZ 'epoch' + created_at / 1000 * INTERVAL '1 microsecond',
+    ALTER COLUMN updated_at TYPE TIMESTAMPTZ USING TIMESTAMPTZ 'epoch' + updated_at / 1000 * INTERVAL '1 microsecond',
+    ALTER COLUMN deleted_at TYPE TIMESTAMPTZ USING TIMESTAMPTZ 'epoch' + deleted_at / 1000 * INTERVAL '1 microsecond',
+    ALTER COLUMN expires_at TYPE TIMESTAMPTZ USING TIMESTAMPTZ 'epoch' + expires_at / 1000 * INTERVAL '1 microsecond';
+
+ALTER TABLE tags
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING TIMESTAMPTZ 'epoch' + created_at / 1000 * INTERVAL '1 microsecond';
+
+ALTER TABLE taggings
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING TIMESTAMPTZ 'epoch' + created_at / 1000 * INTERVAL '1 microsecond';
+
+ALTER TABLE scribble_revisions
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING TIMESTAMPTZ 'epoch' + created_at / 1000 * INTERVAL '1 microsecond';
+
+ALTER TABLE idempotency_keys
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING TIMESTAMPTZ 'epoch' + created_at / 1000 * INTERVAL '1 microsecond';
@@ -0,0 +1,9 @@
+-- Other texts tags go by, such as `js` for `javascript`, which are used in
+-- place of the tag when tagging
+CREATE TABLE tag_aliases (
+    alias      TEXT PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL,
+    tag_id     BIGINT NOT NULL REFERENCES tags (id) ON DELETE CASCADE
+);
+
+CREATE INDEX tag_aliases_tagid ON tag_aliases (tag_id);
@@ -0,0 +1,164 @@
+//! Ordered lists of operations applied in a single transaction, as sent by
+//! clients replaying what they did offline.
+
+use chrono::{DateTime, Utc};
+use diesel::Connection;
+
+use crate::models::{Scribble, Tagging};
+use crate::{Error, Result};
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    Create {
+        text:       String,
+        #[serde(default, deserialize_with = "crate::timestamp::deserialize_option")]
+        expires_at: Option<DateTime<Utc>>,
+    },
+    /// Fails with `VersionConflict` unless the scribble is at `version`, if
+    /// given, and likewise for `Delete`
+    Update {
+        scribble_id: i64,
+        text:        String,
+        version:     Option<i32>,
+    },
+    Delete {
+        scribble_id: i64,
+        version:     Option<i32>,
+    },
+    Tag {
+        scribble_id: i64,
+        tag_texts:   Vec<String>,
+    },
+    Untag {
+        scribble_id: i64,
+        tag_text:    String,
+    },
+}
+
+/// What an operation did, named after it.
+#[derive(Debug, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Outcome {
+    Create {
+        scribble: Scribble,
+    },
+    Update {
+        scribble: Scribble,
+    },
+    Delete,
+    Tag {
+        taggings: Vec<Tagging>,
+    },
+    Untag,
+}
+
+/// How a batch deals with failing operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Stops at the first failure and rolls back everything.
+    #[default]
+    AllOrNothing,
+    /// Rolls back just the failed operations and carries on.
+    BestEffort,
+}
+
+/// Whether the results of a batch were kept, which is not the case if an
+/// operation failed in `Mode::AllOrNothing`.
+pub fn committed(mode: Mode, results: &[Result<Outcome>]) -> bool {
+    mode == Mode::BestEffort || results.iter().all(Result::is_ok)
+}
+
+/// Runs `apply` on each operation within a transaction, each operation
+/// getting a savepoint of its own, and returns the results of the operations
+/// attempted.
+pub(crate) fn run<C, F>(conn: &C, operations: &[Operation], mode: Mode, apply: F) -> Result<Vec<Result<Outcome>>>
+    where C: Connection,
+          F: Fn(&Operation) -> Result<Outcome>,
+{
+    let mut results = Vec::with_capacity(operations.len());
+    let result = conn.transaction(|| {
+        for operation in operations {
+            let result = conn.transaction(|| apply(operation));
+            let failed = result.is_err();
+            results.push(result);
+            if failed && mode == Mode::AllOrNothing {
+                return Err(Error::DatabaseError(diesel::result::Error::RollbackTransaction));
+            }
+        }
+        Ok(())
+    });
+
+    match result {
+        Ok(()) | Err(Error::DatabaseError(diesel::result::Error::RollbackTransaction)) => Ok(results),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::connection::SimpleConnection;
+    use diesel::dsl::sql;
+    use diesel::prelude::*;
+    use diesel::sql_types::{Nullable, Text};
+    use diesel::sqlite::SqliteConnection;
+
+    fn create(text: &str) -> Operation {
+        Operation::Create { text: text.to_owned(), expires_at: None }
+    }
+
+    /// Runs a batch that writes the text of every creation to a table, the
+    /// creation of `fail` failing once written, and returns what was kept.
+    fn run_texts(operations: &[Operation], mode: Mode) -> (Vec<bool>, Option<String>) {
+        let conn = SqliteConnection::establish(":memory:").unwrap();
+        conn.batch_execute("CREATE TABLE texts (text TEXT NOT NULL);").unwrap();
+
+        let results = run(&conn, operations, mode, |operation| match operation {
+            Operation::Create { text, .. } => {
+                diesel::sql_query("INSERT INTO texts (text) VALUES (?);")
+                    .bind::<Text, _>(text)
+                    .execute(&conn)?;
+                if text == "fail" {
+                    return Err(Error::ScribbleNotFound);
+                }
+                Ok(Outcome::Delete)
+            },
+            _ => unreachable!(),
+        }).unwrap();
+        let kept = diesel::select(sql::<Nullable<Text>>("(SELECT group_concat(text, ',') FROM texts)"))
+            .get_result(&conn)
+            .unwrap();
+        (results.iter().map(Result::is_ok).collect(), kept)
+    }
+
+    #[test]
+    fn all_or_nothing_rolls_back_everything_on_failure() {
+        let operations = [create("a"), create("fail"), create("b")];
+        let (succeeded, kept) = run_texts(&operations, Mode::AllOrNothing);
+        assert_eq!(succeeded, vec![true, false]);
+        assert_eq!(kept, None);
+
+        let (succeeded, kept) = run_texts(&operations[..1], Mode::AllOrNothing);
+        assert_eq!(succeeded, vec![true]);
+        assert_eq!(kept.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn best_effort_keeps_what_succeeded() {
+        let operations = [create("a"), create("fail"), create("b")];
+        let (succeeded, kept) = run_texts(&operations, Mode::BestEffort);
+        assert_eq!(succeeded, vec![true, false, true]);
+        assert_eq!(kept.as_deref(), Some("a,b"));
+    }
+
+    #[test]
+    fn tells_whether_results_were_kept() {
+        let failed = vec![Ok(Outcome::Delete), Err(Error::ScribbleNotFound)];
+        assert!(!committed(Mode::AllOrNothing, &failed));
+        assert!(committed(Mode::BestEffort, &failed));
+        assert!(committed(Mode::AllOrNothing, &[Ok(Outcome::Delete)]));
+    }
+}
@@ -0,0 +1,184 @@
+//! Line based unified diffs, for comparing revisions of a scribble.
+
+use std::cmp;
+use std::fmt::Write;
+
+/// Lines of context around every change
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Same,
+    Removed,
+    Added,
+}
+
+/// Pairs every line with how it changed, along with its 0-based position in
+/// the old and new text.
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(Op, &'a str, usize, usize)> {
+    // Only the middle part between a common prefix and suffix needs the
+    // quadratic table, which keeps small edits to long scribbles cheap
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    // lcs[i][j] is the length of the longest common subsequence of a[i..]
+    // and b[j..]
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            }
+            else {
+                cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines: Vec<_> = (0..prefix).map(|k| (Op::Same, old[k], k, k)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            lines.push((Op::Same, a[i], prefix + i, prefix + j));
+            i += 1;
+            j += 1;
+        }
+        else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push((Op::Removed, a[i], prefix + i, prefix + j));
+            i += 1;
+        }
+        else {
+            lines.push((Op::Added, b[j], prefix + i, prefix + j));
+            j += 1;
+        }
+    }
+    for k in 0..suffix {
+        lines.push((Op::Same, old[old.len() - suffix + k], old.len() - suffix + k, new.len() - suffix + k));
+    }
+    lines
+}
+
+/// Formats a hunk range, which by convention starts at the line before an
+/// empty range.
+fn range(start: usize, count: usize) -> String {
+    if count == 0 {
+        format!("{},0", start)
+    }
+    else {
+        format!("{},{}", start + 1, count)
+    }
+}
+
+/// Returns the unified diff from `old` to `new`, which is empty if they have
+/// the same lines.
+pub fn unified(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let lines = diff_lines(&old_lines, &new_lines);
+
+    let changes: Vec<usize> = lines.iter()
+        .enumerate()
+        .filter(|(_, line)| line.0 != Op::Same)
+        .map(|(k, _)| k)
+        .collect();
+    if changes.is_empty() {
+        return String::new();
+    }
+
+    // Group changes into hunks whose context would touch or overlap
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &k in &changes {
+        let start = k.saturating_sub(CONTEXT);
+        let end = cmp::min(k + CONTEXT + 1, lines.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut out = String::new();
+    writeln!(out, "--- {}", old_name).unwrap();
+    writeln!(out, "+++ {}", new_name).unwrap();
+    for (start, end) in hunks {
+        let hunk = &lines[start..end];
+        let old_count = hunk.iter().filter(|line| line.0 != Op::Added).count();
+        let new_count = hunk.iter().filter(|line| line.0 != Op::Removed).count();
+        let (_, _, old_start, new_start) = hunk[0];
+        writeln!(out, "@@ -{} +{} @@", range(old_start, old_count), range(new_start, new_count)).unwrap();
+        for (op, text, _, _) in hunk {
+            let marker = match op {
+                Op::Same => ' ',
+                Op::Removed => '-',
+                Op::Added => '+',
+            };
+            writeln!(out, "{}{}", marker, text).unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(lines: std::ops::RangeInclusive<usize>) -> Vec<String> {
+        lines.map(|n| n.to_string()).collect()
+    }
+
+    fn hunk_headers(diff: &str) -> Vec<&str> {
+        diff.lines().filter(|line| line.starts_with("@@")).collect()
+    }
+
+    #[test]
+    fn shows_changes_with_context() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let new = "a\nb\nc\nd\nE\nf\ng\nh\ni\nj\n";
+        assert_eq!(unified(old, new, "old", "new"), "\
+--- old
++++ new
+@@ -2,7 +2,7 @@
+ b
+ c
+ d
+-e
++E
+ f
+ g
+ h
+");
+        assert_eq!(unified(old, old, "old", "new"), "");
+    }
+
+    #[test]
+    fn merges_hunks_whose_context_touches() {
+        let old = numbered(1..=20);
+        let mut new = old.clone();
+        new[2] = "x".to_owned();
+        new[9] = "y".to_owned();
+        let diff = unified(&old.join("\n"), &new.join("\n"), "old", "new");
+        assert_eq!(hunk_headers(&diff), vec!["@@ -1,13 +1,13 @@"]);
+
+        let mut new = old.clone();
+        new[2] = "x".to_owned();
+        new[10] = "y".to_owned();
+        let diff = unified(&old.join("\n"), &new.join("\n"), "old", "new");
+        assert_eq!(hunk_headers(&diff), vec!["@@ -1,6 +1,6 @@", "@@ -8,7 +8,7 @@"]);
+    }
+
+    #[test]
+    fn empty_ranges_start_at_the_line_before() {
+        assert_eq!(unified("", "a\nb\n", "old", "new"), "--- old\n+++ new\n@@ -0,0 +1,2 @@\n+a\n+b\n");
+        assert_eq!(unified("a\nb\n", "", "old", "new"), "--- old\n+++ new\n@@ -1,2 +0,0 @@\n-a\n-b\n");
+        assert_eq!(unified("", "", "old", "new"), "");
+    }
+
+    #[test]
+    fn a_missing_trailing_newline_is_no_change() {
+        assert_eq!(unified("a\nb", "a\nb\n", "old", "new"), "");
+        assert_eq!(unified("a\nb", "a\nc", "old", "new"), "--- old\n+++ new\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n");
+    }
+}
@@ -0,0 +1,96 @@
+//! Integrity problems that the schema does not rule out, as reported by
+//! `forghetti doctor`.
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+
+use crate::models::Tag;
+use crate::tag_rules::FoldPlan;
+
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum Finding {
+    /// A tagging of a scribble or with a tag that does not exist.
+    /// Repaired by deleting the tagging.
+    OrphanTagging {
+        tagging_id:  i64,
+        scribble_id: i64,
+        tag_id:      i64,
+    },
+    /// A tag without any taggings nor a TTL policy. Repaired by deleting it.
+    UnusedTag {
+        tag_id: i64,
+        text:   String,
+    },
+    /// A scribble updated before it was created. Repaired by setting
+    /// `updated_at` to `created_at`.
+    UpdatedBeforeCreated {
+        scribble_id: i64,
+        created_at:  DateTime<Utc>,
+        updated_at:  DateTime<Utc>,
+    },
+    /// Tags that are the same once normalized, oldest first, or a tag alone
+    /// whose text is not normalized. Repaired by folding them together as
+    /// `forghetti tag fold` does.
+    SimilarTags {
+        tags: Vec<Tag>,
+    },
+    /// A scribble outside of the trash with nothing but whitespace. Repaired
+    /// by moving it to the trash.
+    EmptyScribble {
+        scribble_id: i64,
+    },
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Finding::OrphanTagging { tagging_id, scribble_id, tag_id } => {
+                write!(f, "tagging {} refers to scribble {} and tag {}, which do not both exist", tagging_id, scribble_id, tag_id)
+            },
+            Finding::UnusedTag { tag_id, text } => {
+                write!(f, "tag {} {:?} is not used", tag_id, text)
+            },
+            Finding::UpdatedBeforeCreated { scribble_id, .. } => {
+                write!(f, "scribble {} was updated before it was created", scribble_id)
+            },
+            Finding::SimilarTags { tags } if tags.len() == 1 => {
+                write!(f, "tag {} {:?} is not normalized", tags[0].id, tags[0].text)
+            },
+            Finding::SimilarTags { tags } => {
+                let texts: Vec<String> = tags.iter().map(|tag| format!("{:?}", tag.text)).collect();
+                write!(f, "tags {} are the same once normalized", texts.join(", "))
+            },
+            Finding::EmptyScribble { scribble_id } => {
+                write!(f, "scribble {} is empty", scribble_id)
+            },
+        }
+    }
+}
+
+/// Groups the tags that `plan` folds together, oldest first, leaving out
+/// those it does not touch.
+pub(crate) fn similar_tags(plan: &FoldPlan, mut tags: Vec<Tag>) -> Vec<Vec<Tag>> {
+    tags.sort_by_key(|tag| tag.id);
+    let mut groups: Vec<(String, Vec<Tag>)> = Vec::new();
+    for tag in tags {
+        let into = match plan.folds.iter().find(|fold| fold.from == tag.text || fold.into == tag.text) {
+            Some(fold) => fold.into.clone(),
+            None => continue,
+        };
+        match groups.iter_mut().find(|(found, _)| *found == into) {
+            Some((_, group)) => group.push(tag),
+            None => groups.push((into, vec![tag])),
+        }
+    }
+    groups.into_iter()
+        .map(|(_, group)| group)
+        .collect()
+}
+
+/// Whether the text of a scribble has nothing but whitespace.
+pub(crate) fn is_empty(text: &str) -> bool {
+    text.trim().is_empty()
+}
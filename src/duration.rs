@@ -0,0 +1,34 @@
+//! Durations written as a whole number and a unit: `90s`, `30m`, `24h`, `7d`
+//! or `2w`.
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::timestamp;
+use crate::{Error, Result};
+
+/// Parses a duration like `7d`, failing with `InvalidDuration` otherwise.
+pub fn parse(s: &str) -> Result<Duration> {
+    let invalid = || Error::InvalidDuration(s.to_owned());
+
+    let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let (count, unit) = s.split_at(split);
+    let count: i64 = count.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    count.checked_mul(seconds)
+        .filter(|&seconds| seconds <= Duration::max_value().num_seconds())
+        .map(Duration::seconds)
+        .ok_or_else(invalid)
+}
+
+/// Returns the time `ttl` from now, capped at the end of time for very long
+/// durations.
+pub fn from_now(ttl: Duration) -> DateTime<Utc> {
+    timestamp::saturating_add(timestamp::now(), ttl)
+}
@@ -0,0 +1,25 @@
+//! Idempotency keys, with which clients retry write requests without
+//! writing twice.
+//!
+//! The first request with a key claims it and stores its response once done.
+//! A retry with the same key gets that response back instead of running
+//! again, for as long as the key is kept.
+
+use crate::models::IdempotencyKey;
+use crate::{Error, Result};
+
+
+/// Decides what a request with a key already claimed gets: the stored
+/// response if it is a retry of the same request, or an error if the key was
+/// used for another request or the first one is still in progress.
+pub(crate) fn replay(claimed: IdempotencyKey, fingerprint: &str) -> Result<IdempotencyKey> {
+    if claimed.fingerprint != fingerprint {
+        Err(Error::IdempotencyKeyReused)
+    }
+    else if claimed.status.is_none() {
+        Err(Error::RequestInProgress)
+    }
+    else {
+        Ok(claimed)
+    }
+}
@@ -1,33 +1,282 @@
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 
 pub mod schema;
 pub mod models;
+pub mod batch;
+pub mod diff;
+pub mod doctor;
+pub mod duration;
+pub mod idempotency;
+pub mod period;
+pub mod query;
 pub mod server;
+pub mod sort;
+pub mod tag_filter;
+pub mod tag_rules;
+pub mod tag_tree;
+pub mod store;
+pub mod timestamp;
 
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
 
 use chrono::prelude::*;
+use diesel::backend::Backend;
+use diesel::pg::Pg;
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::deserialize::FromSql;
+use diesel::serialize::ToSql;
+use diesel::sql_types::{BigInt, Bool, HasSqlType, Integer, Text};
+use diesel::sqlite::Sqlite;
 use dotenv::dotenv;
 use r2d2;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
 
-use self::models::{Scribble, NewScribble, Tag, NewTag, Tagging};
+use self::batch::{Operation, Outcome};
+use self::doctor::Finding;
+use self::models::{IdempotencyKey, NewIdempotencyKey, Scribble, NewScribble, ScribbleRevision, Tag, NewTag, TagAlias, NewTagAlias, Tagging, NewTagging, TaggingResult, TaggingStatus, TagUsage, SearchHit};
+use self::period::Period;
+use self::query::Query;
+use self::sort::{KeySql, Sort};
+use self::tag_filter::TagFilter;
+use self::tag_rules::{FoldPlan, TagRules};
+use self::store::ScribbleStore;
+use self::timestamp::{SqlTimestamp, Timestamptz};
 
 
 #[derive(Debug)]
 pub enum Error {
     DatabaseError(diesel::result::Error),
+    PoolError(r2d2::Error),
+    ScribbleNotFound,
+    RevisionNotFound,
     TagExists,
+    TagNotFound,
+    TagInUse,
+    TagBelowItself,
+    InvalidTag(String),
+    AliasExists,
+    AliasNotFound,
     AlreadyTagged,
+    NotTagged,
+    InvalidCursor,
+    InvalidQuery(query::ParseError),
+    InvalidDuration(String),
+    InvalidTime(String),
+    InvalidTimeZone(String),
+    InvalidSort(String),
+    IdempotencyKeyReused,
+    RequestInProgress,
+    /// The scribble is not at the version expected, but at this one
+    VersionConflict(Box<Scribble>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DatabaseError(e) => write!(f, "database error: {}", e),
+            Error::PoolError(e) => write!(f, "connection pool error: {}", e),
+            Error::ScribbleNotFound => write!(f, "scribble not found"),
+            Error::RevisionNotFound => write!(f, "revision not found"),
+            Error::TagExists => write!(f, "tag already exists"),
+            Error::TagNotFound => write!(f, "tag not found"),
+            Error::TagInUse => write!(f, "tag is still in use"),
+            Error::TagBelowItself => write!(f, "tag cannot be moved below itself"),
+            Error::InvalidTag(s) => write!(f, "invalid tag {:?}, every part between slashes needs text made of allowed characters", s),
+            Error::AliasExists => write!(f, "alias already exists"),
+            Error::AliasNotFound => write!(f, "alias not found"),
+            Error::AlreadyTagged => write!(f, "scribble is already tagged"),
+            Error::NotTagged => write!(f, "scribble is not tagged"),
+            Error::InvalidCursor => write!(f, "invalid cursor"),
+            Error::InvalidQuery(e) => write!(f, "invalid query: {}", e),
+            Error::InvalidDuration(s) => write!(f, "invalid duration: {:?}", s),
+            Error::InvalidTime(s) => write!(f, "invalid time {:?}, expected RFC 3339, YYYY-MM-DD, `now`, `today`, `yesterday` or a duration ago", s),
+            Error::InvalidTimeZone(s) => write!(f, "invalid time zone {:?}, expected `local`, `UTC` or an offset like `+02:00`", s),
+            Error::InvalidSort(s) => write!(f, "invalid sort {:?}, expected `created`, `updated`, `length` or `tags`, with a leading `-` for descending order", s),
+            Error::IdempotencyKeyReused => write!(f, "idempotency key was used for another request"),
+            Error::RequestInProgress => write!(f, "request with this idempotency key is still in progress"),
+            Error::VersionConflict(current) => write!(f, "scribble was changed, it is now at version {}", current.version),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<query::ParseError> for Error {
+    fn from(e: query::ParseError) -> Error {
+        Error::InvalidQuery(e)
+    }
+}
+
+impl From<diesel::result::Error> for Error {
+    fn from(e: diesel::result::Error) -> Error {
+        Error::DatabaseError(e)
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// An opaque position in a listing of scribbles, which holds the order of
+/// the listing and the sort key and `id` of a scribble, ties being broken by
+/// `id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    sort: Sort,
+    /// The sort key, in microseconds since the epoch for times
+    value: i64,
+    id: i64,
+}
+
+impl Cursor {
+    fn of(sort: Sort, (scribble, value): &(Scribble, i64)) -> Cursor {
+        Cursor {
+            sort,
+            value: *value,
+            id: scribble.id,
+        }
+    }
+
+    /// The order of the listing the cursor belongs to.
+    pub fn sort(&self) -> Sort {
+        self.sort
+    }
+
+    /// The sort key as a time, if it is one.
+    fn time(&self) -> Option<DateTime<Utc>> {
+        if self.sort.key.is_time() {
+            timestamp::from_micros(self.value)
+        }
+        else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}{:016x}{:016x}", self.sort.code(), self.value as u64, self.id as u64)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cursor> {
+        if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(Error::InvalidCursor);
+        }
+        // Cursors without a sort code held the time in nanoseconds, and then
+        // in microseconds, so there is no telling where they point to
+        if s.len() != 34 {
+            return Err(Error::InvalidCursor);
+        }
+        let code = u8::from_str_radix(&s[..2], 16).map_err(|_| Error::InvalidCursor)?;
+        let sort = Sort::from_code(code).ok_or(Error::InvalidCursor)?;
+        let value = u64::from_str_radix(&s[2..18], 16).map_err(|_| Error::InvalidCursor)?;
+        let id = u64::from_str_radix(&s[18..], 16).map_err(|_| Error::InvalidCursor)?;
+        let cursor = Cursor {
+            sort,
+            value: value as i64,
+            id: id as i64,
+        };
+        if sort.key.is_time() && cursor.time().is_none() {
+            return Err(Error::InvalidCursor);
+        }
+        Ok(cursor)
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cursor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Cursor, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Where a page of the listing starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Seek {
+    /// Scribbles coming after the cursor in the listing, which are older in
+    /// the default order.
+    Before(Cursor),
+    /// Scribbles coming before the cursor in the listing.
+    After(Cursor),
+}
+
+impl Seek {
+    pub fn cursor(self) -> Cursor {
+        match self {
+            Seek::Before(cursor) | Seek::After(cursor) => cursor,
+        }
+    }
+}
+
+/// A page of scribbles, in the order they were listed in.
+///
+/// `next_cursor` points to the scribbles further down the listing and
+/// `prev_cursor` to those before; each is `None` when there is nothing more
+/// in that direction.
+#[derive(Debug, Serialize)]
+pub struct Page<T = Scribble> {
+    pub scribbles: Vec<T>,
+    pub next_cursor: Option<Cursor>,
+    pub prev_cursor: Option<Cursor>,
+}
+
+impl Page {
+    /// Builds a page from rows fetched in seek order along with their sort
+    /// key, with at most one row more than `size` telling that there is
+    /// another page.
+    pub(crate) fn from_rows(mut rows: Vec<(Scribble, i64)>, sort: Sort, size: Option<usize>, seek: Option<Seek>) -> Page {
+        let more = match size {
+            Some(size) if rows.len() > size => {
+                rows.truncate(size);
+                true
+            },
+            _ => false,
+        };
+        let (newer, older) = match seek {
+            None => (false, more),
+            Some(Seek::Before(_)) => (true, more),
+            Some(Seek::After(_)) => {
+                rows.reverse();
+                (more, true)
+            },
+        };
+        Page {
+            next_cursor: if older { rows.last().map(|row| Cursor::of(sort, row)) } else { None },
+            prev_cursor: if newer { rows.first().map(|row| Cursor::of(sort, row)) } else { None },
+            scribbles: rows.into_iter().map(|(scribble, _)| scribble).collect(),
+        }
+    }
+}
+
+impl<T> Page<T> {
+    pub fn map<U, F: FnMut(T) -> U>(self, f: F) -> Page<U> {
+        Page {
+            scribbles: self.scribbles.into_iter().map(f).collect(),
+            next_cursor: self.next_cursor,
+            prev_cursor: self.prev_cursor,
+        }
+    }
+}
+
 pub fn establish_connection() -> PgConnection {
     dotenv().ok();
 
@@ -48,18 +297,152 @@ pub fn new_connection_pool() -> Pool<ConnectionManager<PgConnection>> {
         .expect("Failed to create pool.")
 }
 
-pub fn create_scribble<'a>(conn: &PgConnection, text: &'a str) -> Result<Scribble> {
-    use self::schema::scribbles;
+/// Opens the store for `DATABASE_URL`, which may point to either PostgreSQL
+/// or SQLite, normalizing tags as set in the environment, see `tag_rules`.
+pub fn establish_store() -> Arc<dyn ScribbleStore> {
+    dotenv().ok();
+
+    let database_url = env::var("DATABASE_URL")
+        .expect("DATABASE_URL must be set");
+    let rules = TagRules::from_env()
+        .unwrap_or_else(|e| panic!("Invalid TAG_NORMALIZATION: {}", e));
+    store::open(&database_url, rules)
+}
+
+/// The SQL types of the schema, which the backends sharing the queries below
+/// all have.
+pub trait SqlBackend: Backend + HasSqlType<BigInt> + HasSqlType<Bool> + HasSqlType<Integer> + HasSqlType<Text> + HasSqlType<Timestamptz> {}
+
+impl SqlBackend for Pg {}
+
+impl SqlBackend for Sqlite {}
+
+/// What the SQL backends spell differently within the queries they share:
+/// inserts, as SQLite takes no `DEFAULT` among values and returns no rows
+/// from them, and the SQL scribbles are sorted and filtered by.
+pub trait Dialect: Connection {
+    fn insert_scribble(&self, new_scribble: &NewScribble) -> QueryResult<Scribble>;
+    fn insert_tag(&self, new_tag: &NewTag) -> QueryResult<Tag>;
+    /// Inserts a tag unless there is one with the same text, which a
+    /// concurrent request may have created.
+    fn insert_tag_if_missing(&self, new_tag: &NewTag) -> QueryResult<()>;
+    /// Inserts a tagging unless the scribble is tagged with the tag already,
+    /// returning `None` then.
+    fn insert_tagging(&self, new_tagging: &NewTagging) -> QueryResult<Option<Tagging>>;
+    fn insert_tag_alias(&self, new_alias: &NewTagAlias) -> QueryResult<()>;
+    /// Inserts an idempotency key unless it is there already, and tells
+    /// whether it was inserted.
+    fn insert_idempotency_key(&self, new_key: &NewIdempotencyKey) -> QueryResult<bool>;
+    fn sort_sql(key: sort::Key) -> KeySql;
+    /// Narrows down `query` to the scribbles matching all the clauses of
+    /// `filter`.
+    fn apply_query<'a>(filter: &'a Query, query: schema::scribbles::BoxedQuery<'a, Self::Backend>) -> schema::scribbles::BoxedQuery<'a, Self::Backend>;
+}
+
+impl Dialect for PgConnection {
+    fn insert_scribble(&self, new_scribble: &NewScribble) -> QueryResult<Scribble> {
+        use self::schema::scribbles;
+
+        diesel::insert_into(scribbles::table)
+            .values(new_scribble)
+            .get_result(self)
+    }
+
+    fn insert_tag(&self, new_tag: &NewTag) -> QueryResult<Tag> {
+        use self::schema::tags;
+
+        diesel::insert_into(tags::table)
+            .values(new_tag)
+            .get_result(self)
+    }
+
+    fn insert_tag_if_missing(&self, new_tag: &NewTag) -> QueryResult<()> {
+        use self::schema::tags;
+
+        diesel::insert_into(tags::table)
+            .values(new_tag)
+            .on_conflict(tags::text)
+            .do_nothing()
+            .execute(self)?;
+        Ok(())
+    }
+
+    fn insert_tagging(&self, new_tagging: &NewTagging) -> QueryResult<Option<Tagging>> {
+        use self::schema::taggings;
+
+        diesel::insert_into(taggings::table)
+            .values(new_tagging)
+            .on_conflict((taggings::scribble_id, taggings::tag_id))
+            .do_nothing()
+            .get_result(self)
+            .optional()
+    }
+
+    fn insert_tag_alias(&self, new_alias: &NewTagAlias) -> QueryResult<()> {
+        use self::schema::tag_aliases;
+
+        diesel::insert_into(tag_aliases::table)
+            .values(new_alias)
+            .execute(self)?;
+        Ok(())
+    }
+
+    fn insert_idempotency_key(&self, new_key: &NewIdempotencyKey) -> QueryResult<bool> {
+        use self::schema::idempotency_keys;
+
+        let inserted = diesel::insert_into(idempotency_keys::table)
+            .values(new_key)
+            .on_conflict_do_nothing()
+            .execute(self)?;
+        Ok(inserted == 1)
+    }
+
+    fn sort_sql(key: sort::Key) -> KeySql {
+        use self::sort::Key;
+
+        match key {
+            Key::Created => KeySql {
+                order: "scribbles.created_at",
+                value: "(EXTRACT(EPOCH FROM scribbles.created_at) * 1000000)::bigint",
+            },
+            Key::Updated => KeySql {
+                order: "COALESCE(scribbles.updated_at, scribbles.created_at)",
+                value: "(EXTRACT(EPOCH FROM COALESCE(scribbles.updated_at, scribbles.created_at)) * 1000000)::bigint",
+            },
+            Key::Length => KeySql {
+                order: "char_length(scribbles.text)",
+                value: "char_length(scribbles.text)::bigint",
+            },
+            Key::Tags => KeySql {
+                order: sort::TAG_COUNT_SQL,
+                value: sort::TAG_COUNT_SQL,
+            },
+        }
+    }
+
+    fn apply_query<'a>(filter: &'a Query, query: schema::scribbles::BoxedQuery<'a, Pg>) -> schema::scribbles::BoxedQuery<'a, Pg> {
+        filter.apply(query)
+    }
+}
 
-    let now = Utc::now();
+/// Creates a scribble, which is forgotten at `expires_at` if given.
+pub fn create_scribble<C, DB>(conn: &C, text: &str, expires_at: Option<DateTime<Utc>>) -> Result<Scribble>
+    where C: Dialect<Backend = DB>,
+          DB: SqlBackend,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    let now = timestamp::now();
     let new_scribble = NewScribble {
-        created_at: now.timestamp_nanos(),
+        created_at: SqlTimestamp(now),
         text: text,
+        expires_at: expires_at.map(SqlTimestamp),
     };
 
-    let result = diesel::insert_into(scribbles::table)
-        .values(&new_scribble)
-        .get_result(conn);
+    let result = conn.transaction(|| {
+        let created = conn.insert_scribble(&new_scribble)?;
+        record_revision(conn, created.id, created.created_at, &created.text)?;
+        Ok(created)
+    });
 
     match result {
         Err(e) => {
@@ -71,53 +454,396 @@ pub fn create_scribble<'a>(conn: &PgConnection, text: &'a str) -> Result<Scribbl
     }
 }
 
-pub fn update_scribble<'a>(conn: &PgConnection, scribble_id: i64, new_text: &'a str) -> Result<Scribble> {
+/// Returns a scribble unless it is in the trash or has expired.
+pub fn get_scribble<C, DB>(conn: &C, scribble_id: i64) -> Result<Scribble>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i32: FromSql<Integer, DB>,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
     use self::schema::scribbles::dsl::*;
 
-    let now = Utc::now();
-    let result = diesel::update(scribbles.find(scribble_id))
-        .set((updated_at.eq(now.timestamp_nanos()),
-              text.eq(new_text)))
-        .get_result(conn);
+    let result = scribbles
+        .find(scribble_id)
+        .filter(deleted_at.is_null())
+        .filter(expires_at.is_null().or(expires_at.gt(SqlTimestamp(timestamp::now()))))
+        .first(conn);
 
     match result {
+        Err(diesel::result::Error::NotFound) => {
+            Err(Error::ScribbleNotFound)
+        },
         Err(e) => {
             Err(Error::DatabaseError(e))
         },
-        Ok(updated) => {
-            Ok(updated)
+        Ok(found) => {
+            Ok(found)
         },
     }
 }
 
-pub fn delete_scribble<'a>(conn: &PgConnection, scribble_id: i64) -> Result<()> {
+/// Replaces the text of a scribble, provided that it is at
+/// `expected_version` if given.
+pub fn update_scribble<C, DB>(conn: &C, scribble_id: i64, new_text: &str, expected_version: Option<i32>) -> Result<Scribble>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          bool: ToSql<Bool, DB>,
+          i32: FromSql<Integer, DB>,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    use self::schema::scribbles::dsl::*;
+
+    let now = timestamp::now();
+    conn.transaction(|| {
+        // Without an expected version, the comparison is NULL and any
+        // version matches
+        let updated = diesel::update(scribbles
+                                     .find(scribble_id)
+                                     .filter(deleted_at.is_null())
+                                     .filter(expires_at.is_null().or(expires_at.gt(SqlTimestamp(now))))
+                                     .filter(version.nullable().eq(expected_version).or(expected_version.is_none())))
+            .set((updated_at.eq(SqlTimestamp(now)),
+                  text.eq(new_text),
+                  version.eq(version + 1)))
+            .execute(conn)?;
+        if updated == 0 {
+            return Err(version_conflict(conn, scribble_id));
+        }
+        record_revision(conn, scribble_id, now, new_text)?;
+        Ok(scribbles.find(scribble_id).first(conn)?)
+    })
+}
+
+/// Moves a scribble to the trash, from which it can be undeleted until the
+/// trash is emptied, provided that it is at `expected_version` if given.
+pub fn delete_scribble<C, DB>(conn: &C, scribble_id: i64, expected_version: Option<i32>) -> Result<()>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          bool: ToSql<Bool, DB>,
+          i32: FromSql<Integer, DB>,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
     use self::schema::scribbles::dsl::*;
 
-    let result = diesel::delete(scribbles.find(scribble_id))
+    let now = timestamp::now();
+    let result = diesel::update(scribbles
+                                .find(scribble_id)
+                                .filter(deleted_at.is_null())
+                                .filter(expires_at.is_null().or(expires_at.gt(SqlTimestamp(now))))
+                                .filter(version.nullable().eq(expected_version).or(expected_version.is_none())))
+        .set((deleted_at.eq(SqlTimestamp(now)),
+              version.eq(version + 1)))
         .execute(conn);
 
     match result {
         Err(e) => {
             Err(Error::DatabaseError(e))
         },
+        Ok(0) => {
+            Err(version_conflict(conn, scribble_id))
+        },
         Ok(_) => {
             Ok(())
         },
     }
 }
 
-pub fn create_tag<'a>(conn: &PgConnection, text: &'a str) -> Result<Tag> {
-    use self::schema::tags;
+/// Tells why a scribble could not be changed: either it is not there, or it
+/// is at another version than expected.
+fn version_conflict<C, DB>(conn: &C, scribble_id: i64) -> Error
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i32: FromSql<Integer, DB>,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    use self::schema::scribbles::dsl::*;
+
+    let current = scribbles
+        .find(scribble_id)
+        .filter(deleted_at.is_null())
+        .filter(expires_at.is_null().or(expires_at.gt(SqlTimestamp(timestamp::now()))))
+        .first(conn);
+
+    match current {
+        Err(diesel::result::Error::NotFound) => {
+            Error::ScribbleNotFound
+        },
+        Err(e) => {
+            Error::DatabaseError(e)
+        },
+        Ok(current) => {
+            Error::VersionConflict(Box::new(current))
+        },
+    }
+}
+
+pub fn undelete_scribble<C, DB>(conn: &C, scribble_id: i64) -> Result<Scribble>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i32: FromSql<Integer, DB>,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    use self::schema::scribbles::dsl::*;
+
+    let result = conn.transaction(|| {
+        let restored = diesel::update(scribbles.find(scribble_id).filter(deleted_at.is_not_null()))
+            .set((deleted_at.eq(None::<SqlTimestamp>),
+                  version.eq(version + 1)))
+            .execute(conn)?;
+        if restored == 0 {
+            return Err(diesel::result::Error::NotFound);
+        }
+        scribbles.find(scribble_id).first(conn)
+    });
+
+    match result {
+        Err(diesel::result::Error::NotFound) => {
+            Err(Error::ScribbleNotFound)
+        },
+        Err(e) => {
+            Err(Error::DatabaseError(e))
+        },
+        Ok(restored) => {
+            Ok(restored)
+        },
+    }
+}
+
+/// Returns the scribbles in the trash, most recently deleted first.
+pub fn trash<C, DB>(conn: &C) -> Result<Vec<Scribble>>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i32: FromSql<Integer, DB>,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    use self::schema::scribbles::dsl::*;
+
+    let result = scribbles
+        .filter(deleted_at.is_not_null())
+        .order((deleted_at.desc(), id.desc()))
+        .load::<Scribble>(conn);
+
+    match result {
+        Err(e) => {
+            Err(Error::DatabaseError(e))
+        },
+        Ok(selected) => {
+            Ok(selected)
+        },
+    }
+}
+
+/// Deletes the scribbles trashed before `deleted_before`, or all of them,
+/// for good, their taggings and revisions going along by cascade. Returns
+/// how many were purged.
+pub fn empty_trash<C, DB>(conn: &C, deleted_before: Option<DateTime<Utc>>) -> Result<usize>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    use diesel::sql_types::Nullable;
+
+    let purged = diesel::sql_query("DELETE FROM scribbles WHERE deleted_at IS NOT NULL AND ($1 IS NULL OR deleted_at < $1);")
+        .bind::<Nullable<Timestamptz>, _>(deleted_before.map(SqlTimestamp))
+        .execute(conn)?;
+    Ok(purged)
+}
+
+/// Deletes the scribbles that expired by `now` for good, their taggings and
+/// revisions going along by cascade. Returns how many were purged.
+pub fn purge_expired<C, DB>(conn: &C, now: DateTime<Utc>) -> Result<usize>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    use self::schema::scribbles::dsl::*;
+
+    let purged = diesel::delete(scribbles.filter(expires_at.le(SqlTimestamp(now))))
+        .execute(conn)?;
+    Ok(purged)
+}
+
+/// Claims an idempotency key for the request described by `fingerprint`,
+/// forgetting it first if it was created before `expired_before`. Returns the
+/// key with the response to replay if it was claimed already.
+pub fn claim_idempotency_key<C, DB>(conn: &C, key: &str, fingerprint: &str, expired_before: DateTime<Utc>) -> Result<Option<IdempotencyKey>>
+    where C: Dialect<Backend = DB>,
+          DB: SqlBackend,
+          i32: FromSql<Integer, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    use self::schema::idempotency_keys;
+
+    let now = timestamp::now();
+    conn.transaction(|| {
+        diesel::delete(idempotency_keys::table
+                       .find(key)
+                       .filter(idempotency_keys::created_at.lt(SqlTimestamp(expired_before))))
+            .execute(conn)?;
+        let claimed = conn.insert_idempotency_key(&NewIdempotencyKey {
+            key,
+            created_at: SqlTimestamp(now),
+            fingerprint,
+        })?;
+        if claimed {
+            return Ok(None);
+        }
+
+        let claimed = idempotency_keys::table.find(key).first(conn)?;
+        idempotency::replay(claimed, fingerprint).map(Some)
+    })
+}
+
+/// Stores the response to replay for a claimed idempotency key.
+pub fn save_idempotent_response<C, DB>(conn: &C, key: &str, status: i32, body: &str) -> Result<()>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+{
+    use self::schema::idempotency_keys;
+
+    diesel::update(idempotency_keys::table.find(key))
+        .set((idempotency_keys::status.eq(status), idempotency_keys::body.eq(body)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Gives up a claimed idempotency key, so that the request can be retried.
+pub fn release_idempotency_key<C, DB>(conn: &C, key: &str) -> Result<()>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+{
+    use self::schema::idempotency_keys;
+
+    diesel::delete(idempotency_keys::table.find(key))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Forgets the idempotency keys created before `created_before` and returns
+/// how many there were.
+pub fn purge_idempotency_keys<C, DB>(conn: &C, created_before: DateTime<Utc>) -> Result<usize>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    use self::schema::idempotency_keys::dsl::*;
+
+    let purged = diesel::delete(idempotency_keys.filter(created_at.lt(SqlTimestamp(created_before))))
+        .execute(conn)?;
+    Ok(purged)
+}
+
+/// Appends `text` as the next revision of a scribble.
+fn record_revision<C, DB>(conn: &C, scribble_id: i64, created_at: DateTime<Utc>, text: &str) -> QueryResult<()>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    // SQLite numbers `$1` and the like in the order they first appear, which
+    // is what they mean to PostgreSQL as long as they appear in order
+    diesel::sql_query("INSERT INTO scribble_revisions (created_at, scribble_id, revision, text) SELECT $1, $2, COALESCE(MAX(revision), 0) + 1, $3 FROM scribble_revisions WHERE scribble_id = $2;")
+        .bind::<Timestamptz, _>(SqlTimestamp(created_at))
+        .bind::<BigInt, _>(scribble_id)
+        .bind::<Text, _>(text)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Returns the revisions of a scribble, oldest first.
+pub fn revisions<C, DB>(conn: &C, scribble_id: i64) -> Result<Vec<ScribbleRevision>>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i32: FromSql<Integer, DB>,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    use self::schema::scribble_revisions;
+
+    let result = scribble_revisions::table
+        .filter(scribble_revisions::scribble_id.eq(scribble_id))
+        .order(scribble_revisions::revision)
+        .load::<ScribbleRevision>(conn);
+
+    match result {
+        Err(e) => {
+            Err(Error::DatabaseError(e))
+        },
+        Ok(ref selected) if selected.is_empty() => {
+            Err(Error::ScribbleNotFound)
+        },
+        Ok(selected) => {
+            Ok(selected)
+        },
+    }
+}
 
-    let now = Utc::now();
+/// Makes the text of an older revision current again, as a new revision.
+pub fn restore_revision<C, DB>(conn: &C, scribble_id: i64, revision: i32) -> Result<Scribble>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          bool: ToSql<Bool, DB>,
+          i32: FromSql<Integer, DB>,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    use self::schema::scribble_revisions;
+
+    conn.transaction(|| {
+        let restored = scribble_revisions::table
+            .filter(scribble_revisions::scribble_id.eq(scribble_id))
+            .filter(scribble_revisions::revision.eq(revision))
+            .first::<ScribbleRevision>(conn);
+        match restored {
+            Err(diesel::result::Error::NotFound) => Err(Error::RevisionNotFound),
+            Err(e) => Err(Error::DatabaseError(e)),
+            Ok(restored) => update_scribble(conn, scribble_id, &restored.text, None),
+        }
+    })
+}
+
+/// Creates a tag, failing with `TagExists` if the text is taken by a tag or
+/// an alias.
+pub fn create_tag<C, DB>(conn: &C, text: &str) -> Result<Tag>
+    where C: Dialect<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    if find_alias(conn, text)?.is_some() {
+        return Err(Error::TagExists);
+    }
+    let now = timestamp::now();
     let new_tag = NewTag {
-        created_at: now.timestamp_nanos(),
+        created_at: SqlTimestamp(now),
         text: text,
     };
 
-    let result = diesel::insert_into(tags::table)
-        .values(&new_tag)
-        .get_result(conn);
+    // On PostgreSQL a failed statement aborts the transaction it is in, unless
+    // it rolls back to a savepoint of its own
+    let result = conn.transaction(|| conn.insert_tag(&new_tag));
 
     match result {
         Err(e) => {
@@ -139,44 +865,600 @@ pub fn create_tag<'a>(conn: &PgConnection, text: &'a str) -> Result<Tag> {
     }
 }
 
-pub fn tag_scribble<'a>(conn: &PgConnection, scribble_id: i64, tag_text: &'a str) -> Result<Tagging> {
-    use diesel::sql_types::{BigInt, Text};
+/// Tags a scribble with every one of `tag_texts`, creating the tags that do
+/// not exist yet. Either all the taggings are made or none of them.
+pub fn tag_scribble<C, DB>(conn: &C, scribble_id: i64, tag_texts: &[String]) -> Result<Vec<Tagging>>
+    where C: Dialect<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    conn.transaction(|| {
+        tag_scribbles(conn, &[scribble_id], tag_texts)?
+            .into_iter()
+            .map(|result| result.tagging.ok_or(Error::AlreadyTagged))
+            .collect()
+    })
+}
+
+/// Tells whether all of `scribble_ids` are there, neither in the trash nor
+/// expired.
+fn all_live<C, DB>(conn: &C, scribble_ids: &[i64]) -> Result<bool>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    use self::schema::scribbles::dsl::*;
+
+    let wanted: HashSet<&i64> = scribble_ids.iter().collect();
+    let live: i64 = scribbles
+        .filter(id.eq_any(scribble_ids))
+        .filter(deleted_at.is_null())
+        .filter(expires_at.is_null().or(expires_at.gt(SqlTimestamp(timestamp::now()))))
+        .count()
+        .get_result(conn)?;
+    Ok(live as usize == wanted.len())
+}
+
+/// Tags each of `scribble_ids` with each of `tag_texts` in one transaction,
+/// creating the tags that do not exist yet. Pairs that are already tagged
+/// are reported as such instead of failing the rest.
+pub fn tag_scribbles<C, DB>(conn: &C, scribble_ids: &[i64], tag_texts: &[String]) -> Result<Vec<TaggingResult>>
+    where C: Dialect<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    use diesel::result::Error as DieselError;
+    use diesel::result::DatabaseErrorKind;
+
+    conn.transaction(|| {
+        if !all_live(conn, scribble_ids)? {
+            return Err(Error::ScribbleNotFound);
+        }
+        let now = timestamp::now();
+        let mut found = Vec::new();
+        for tag_text in tag_texts {
+            let tag_text = resolve_alias(conn, tag_text)?;
+            conn.insert_tag_if_missing(&NewTag {
+                created_at: SqlTimestamp(now),
+                text: &tag_text,
+            })?;
+            found.push(find_tag(conn, &tag_text)?);
+        }
+
+        let mut results = Vec::new();
+        for &scribble_id in scribble_ids {
+            for tag in &found {
+                let result = conn.insert_tagging(&NewTagging {
+                    created_at: SqlTimestamp(now),
+                    scribble_id,
+                    tag_id: tag.id,
+                });
+
+                let (status, tagging) = match result {
+                    Err(DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _)) => {
+                        return Err(Error::ScribbleNotFound);
+                    },
+                    Err(e) => {
+                        return Err(Error::DatabaseError(e));
+                    },
+                    Ok(None) => {
+                        (TaggingStatus::AlreadyTagged, None)
+                    },
+                    Ok(Some(tagging)) => {
+                        expire_by_tag(conn, scribble_id, tag)?;
+                        (TaggingStatus::Tagged, Some(tagging))
+                    },
+                };
+                results.push(TaggingResult {
+                    scribble_id,
+                    tag_text: tag.text.clone(),
+                    status,
+                    tagging,
+                });
+            }
+        }
+        Ok(results)
+    })
+}
+
+/// Brings the expiry of a scribble forward to the TTL of a tag it was just
+/// tagged with, if the tag has one.
+fn expire_by_tag<C, DB>(conn: &C, scribble_id: i64, tag: &Tag) -> Result<()>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    use self::schema::scribbles;
+
+    if let Some(ttl) = tag.ttl {
+        let expiry = SqlTimestamp(duration::from_now(chrono::Duration::milliseconds(ttl.saturating_mul(1000))));
+        diesel::update(scribbles::table
+                       .find(scribble_id)
+                       .filter(scribbles::expires_at.is_null().or(scribbles::expires_at.gt(expiry))))
+            .set(scribbles::expires_at.eq(expiry))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Sets or clears the TTL of a tag, creating the tag if needed. It applies
+/// to scribbles tagged from then on.
+pub fn set_tag_ttl<C, DB>(conn: &C, tag_text: &str, new_ttl: Option<i64>) -> Result<Tag>
+    where C: Dialect<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    use self::schema::tags::dsl::*;
+
+    conn.transaction(|| {
+        let tag_text = resolve_alias(conn, tag_text)?;
+        match create_tag(conn, &tag_text) {
+            Ok(_) | Err(Error::TagExists) => {},
+            Err(e) => return Err(e),
+        }
+        diesel::update(tags.filter(text.eq(&tag_text)))
+            .set(ttl.eq(new_ttl))
+            .execute(conn)?;
+        find_tag(conn, &tag_text)
+    })
+}
+
+/// Returns the tag `alias` stands for, if it is an alias.
+fn find_alias<C, DB>(conn: &C, alias: &str) -> Result<Option<Tag>>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    use self::schema::{tag_aliases, tags};
+
+    let found = tag_aliases::table
+        .inner_join(tags::table)
+        .filter(tag_aliases::alias.eq(alias))
+        .select(tags::all_columns)
+        .first(conn)
+        .optional()?;
+    Ok(found)
+}
+
+/// Returns the text of the tag `tag_text` stands for if it is an alias, or
+/// `tag_text` itself otherwise.
+pub(crate) fn resolve_alias<C, DB>(conn: &C, tag_text: &str) -> Result<String>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    Ok(find_alias(conn, tag_text)?.map_or_else(|| tag_text.to_owned(), |tag| tag.text))
+}
+
+fn find_tag<C, DB>(conn: &C, tag_text: &str) -> Result<Tag>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    use self::schema::tags::dsl::*;
+
+    let result = tags
+        .filter(text.eq(tag_text))
+        .first(conn);
 
-    match create_tag(&conn, &tag_text) {
-        Ok(_) | Err(Error::TagExists) => {
-            let now = Utc::now();
-            let result = diesel::sql_query("INSERT INTO taggings (created_at, scribble_id, tag_id) VALUES ($1, $2, (SELECT id FROM tags WHERE text = $3)) RETURNING *;")
-                .bind::<BigInt, _>(now.timestamp_nanos())
-                .bind::<BigInt, _>(scribble_id)
-                .bind::<Text, _>(tag_text)
-                .get_result(conn);
+    match result {
+        Err(diesel::result::Error::NotFound) => {
+            Err(Error::TagNotFound)
+        },
+        Err(e) => {
+            Err(Error::DatabaseError(e))
+        },
+        Ok(found) => {
+            Ok(found)
+        },
+    }
+}
 
-            match result {
-                Err(e) => {
-                    use diesel::result::Error as DieselError;
-                    use diesel::result::DatabaseErrorKind;
+pub fn untag_scribble<C, DB>(conn: &C, scribble_id: i64, tag_text: &str) -> Result<()>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    use self::schema::taggings;
 
-                    match e {
-                        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
-                            Err(Error::AlreadyTagged)
-                        },
-                        _ => {
-                            Err(Error::DatabaseError(e))
-                        },
-                    }
+    let tag = find_tag(conn, &resolve_alias(conn, tag_text)?)?;
+    let result = diesel::delete(taggings::table
+                                .filter(taggings::scribble_id.eq(scribble_id))
+                                .filter(taggings::tag_id.eq(tag.id)))
+        .execute(conn);
+
+    match result {
+        Err(e) => {
+            Err(Error::DatabaseError(e))
+        },
+        Ok(0) => {
+            Err(Error::NotTagged)
+        },
+        Ok(_) => {
+            Ok(())
+        },
+    }
+}
+
+/// Renames a tag along with the tags below it, `from/x` becoming `to/x`,
+/// even if `from` itself is not a tag.
+pub fn rename_tag<C, DB>(conn: &C, from: &str, to: &str) -> Result<Option<Tag>>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    use self::schema::tags;
+
+    conn.transaction(|| {
+        let from = &resolve_alias(conn, from)?;
+        if tag_tree::is_below(to, from) {
+            return Err(Error::TagBelowItself);
+        }
+        let below: Vec<Tag> = tags::table
+            .filter(tag_tree::below(from))
+            .load(conn)?;
+        // A parent may only be there as the start of the tags below it
+        let renamed = match rename_single_tag(conn, from, to) {
+            Err(Error::TagNotFound) if !below.is_empty() => None,
+            result => Some(result?),
+        };
+        for tag in below {
+            rename_single_tag(conn, &tag.text, &tag_tree::moved(&tag.text, from, to))?;
+        }
+        Ok(renamed)
+    })
+}
+
+fn rename_single_tag<C, DB>(conn: &C, from: &str, to: &str) -> Result<Tag>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    use self::schema::tags::dsl::*;
+
+    let tag = find_tag(conn, from)?;
+    if find_alias(conn, to)?.is_some() {
+        return Err(Error::TagExists);
+    }
+    let result = diesel::update(tags.find(tag.id))
+        .set(text.eq(to))
+        .execute(conn);
+
+    match result {
+        Err(e) => {
+            use diesel::result::Error as DieselError;
+            use diesel::result::DatabaseErrorKind;
+
+            match e {
+                DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                    Err(Error::TagExists)
                 },
-                Ok(created) => {
-                    Ok(created)
+                _ => {
+                    Err(Error::DatabaseError(e))
                 },
             }
         },
-        Err(e) => {
-            Err(e)
+        Ok(_) => {
+            Ok(tags.find(tag.id).first(conn)?)
         },
     }
 }
 
-pub fn tags(conn: &PgConnection) -> Result<Vec<Tag>> {
+/// Moves all the scribbles and aliases of `from` over to `into`, or to the
+/// tag it is an alias of, and deletes `from`. If `into` does not exist yet,
+/// this is the same as renaming.
+pub fn merge_tags<C, DB>(conn: &C, from: &str, into: &str) -> Result<Tag>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    use self::schema::{tag_aliases, tags, taggings};
+
+    conn.transaction(|| {
+        let source = find_tag(conn, &resolve_alias(conn, from)?)?;
+        let into = resolve_alias(conn, into)?;
+        let target = match find_tag(conn, &into) {
+            Err(Error::TagNotFound) => return rename_single_tag(conn, &source.text, &into),
+            result => result?,
+        };
+        if source.id == target.id {
+            return Ok(target);
+        }
+
+        // Scribbles tagged with both keep their existing tagging, as moving
+        // it would violate UNIQUE (scribble_id, tag_id)
+        diesel::sql_query("DELETE FROM taggings WHERE tag_id = $1 AND scribble_id IN (SELECT scribble_id FROM taggings WHERE tag_id = $2);")
+            .bind::<BigInt, _>(source.id)
+            .bind::<BigInt, _>(target.id)
+            .execute(conn)?;
+        diesel::update(taggings::table.filter(taggings::tag_id.eq(source.id)))
+            .set(taggings::tag_id.eq(target.id))
+            .execute(conn)?;
+        diesel::update(tag_aliases::table.filter(tag_aliases::tag_id.eq(source.id)))
+            .set(tag_aliases::tag_id.eq(target.id))
+            .execute(conn)?;
+        diesel::delete(tags::table.find(source.id))
+            .execute(conn)?;
+
+        Ok(target)
+    })
+}
+
+/// Deletes a tag, which must not be in use unless `force` is given, in which
+/// case its taggings go along by cascade.
+pub fn delete_tag<C, DB>(conn: &C, tag_text: &str, force: bool) -> Result<()>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    use self::schema::{tags, taggings};
+
+    conn.transaction(|| {
+        let tag = find_tag(conn, &resolve_alias(conn, tag_text)?)?;
+        let used = taggings::table
+            .filter(taggings::tag_id.eq(tag.id))
+            .count()
+            .get_result::<i64>(conn)?;
+        if used > 0 && !force {
+            return Err(Error::TagInUse);
+        }
+
+        diesel::delete(tags::table.find(tag.id))
+            .execute(conn)?;
+
+        Ok(())
+    })
+}
+
+/// Folds tags together as planned by `tag_rules::plan_folds`, all at once,
+/// and returns the plan.
+pub fn fold_tags<C, DB>(conn: &C, rules: &TagRules) -> Result<FoldPlan>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    conn.transaction(|| {
+        let plan = tag_rules::plan_folds(rules, &tags(conn)?, &tag_aliases(conn)?);
+        apply_folds(conn, &plan)?;
+        Ok(plan)
+    })
+}
+
+fn apply_folds<C, DB>(conn: &C, plan: &FoldPlan) -> Result<()>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    for fold in &plan.folds {
+        merge_tags(conn, &fold.from, &fold.into)?;
+    }
+    Ok(())
+}
+
+pub fn tag_aliases<C, DB>(conn: &C) -> Result<Vec<TagAlias>>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    use self::schema::{tag_aliases, tags};
+
+    let aliases = tag_aliases::table
+        .inner_join(tags::table)
+        .select((tag_aliases::alias, tag_aliases::created_at, tags::all_columns))
+        .order(tag_aliases::alias)
+        .load(conn)?;
+    Ok(aliases)
+}
+
+/// Makes `alias` stand for the tag `tag_text`, or for the tag that is itself
+/// an alias of. An alias cannot have the text of a tag.
+pub fn alias_tag<C, DB>(conn: &C, alias: &str, tag_text: &str) -> Result<TagAlias>
+    where C: Dialect<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    conn.transaction(|| {
+        let tag = find_tag(conn, &resolve_alias(conn, tag_text)?)?;
+        match find_tag(conn, alias) {
+            Err(Error::TagNotFound) => {},
+            Ok(_) => return Err(Error::TagExists),
+            Err(e) => return Err(e),
+        }
+
+        let now = timestamp::now();
+        let result = conn.insert_tag_alias(&NewTagAlias {
+            alias,
+            created_at: SqlTimestamp(now),
+            tag_id: tag.id,
+        });
+
+        match result {
+            Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::UniqueViolation, _)) => {
+                Err(Error::AliasExists)
+            },
+            Err(e) => {
+                Err(Error::DatabaseError(e))
+            },
+            Ok(_) => {
+                Ok(TagAlias { alias: alias.to_owned(), created_at: now, tag })
+            },
+        }
+    })
+}
+
+pub fn unalias_tag<C, DB>(conn: &C, alias: &str) -> Result<()>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+{
+    use self::schema::tag_aliases;
+
+    let deleted = diesel::delete(tag_aliases::table.find(alias))
+        .execute(conn)?;
+    if deleted == 0 {
+        return Err(Error::AliasNotFound);
+    }
+    Ok(())
+}
+
+/// Applies `operations` in order within one transaction, see `batch::run`.
+/// Tag texts are normalized by `rules` first.
+pub fn batch<C, DB>(conn: &C, rules: &TagRules, operations: &[Operation], mode: batch::Mode) -> Result<Vec<Result<Outcome>>>
+    where C: Dialect<Backend = DB>,
+          DB: SqlBackend,
+          bool: ToSql<Bool, DB>,
+          i32: FromSql<Integer, DB>,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    batch::run(conn, operations, mode, |operation| match operation {
+        Operation::Create { text, expires_at } => {
+            create_scribble(conn, text, *expires_at).map(|scribble| Outcome::Create { scribble })
+        },
+        Operation::Update { scribble_id, text, version } => {
+            update_scribble(conn, *scribble_id, text, *version).map(|scribble| Outcome::Update { scribble })
+        },
+        Operation::Delete { scribble_id, version } => {
+            delete_scribble(conn, *scribble_id, *version).map(|()| Outcome::Delete)
+        },
+        Operation::Tag { scribble_id, tag_texts } => {
+            rules.normalize_all(tag_texts)
+                .and_then(|tag_texts| tag_scribble(conn, *scribble_id, &tag_texts))
+                .map(|taggings| Outcome::Tag { taggings })
+        },
+        Operation::Untag { scribble_id, tag_text } => {
+            rules.normalize(tag_text)
+                .and_then(|tag_text| untag_scribble(conn, *scribble_id, &tag_text))
+                .map(|()| Outcome::Untag)
+        },
+    })
+}
+
+/// Looks for problems the schema does not rule out and, if `repair` is
+/// given, fixes them all within the same transaction.
+pub fn check_integrity<C, DB>(conn: &C, rules: &TagRules, repair: bool) -> Result<Vec<Finding>>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    use diesel::dsl::not;
+    use self::schema::{scribbles, tags, taggings};
+
+    conn.transaction(|| {
+        let orphans = taggings::table
+            .filter(not(taggings::scribble_id.eq_any(scribbles::table.select(scribbles::id)))
+                    .or(not(taggings::tag_id.eq_any(tags::table.select(tags::id)))))
+            .order(taggings::id)
+            .load::<Tagging>(conn)?;
+        // Tags with a TTL are policies for tagging later on
+        let unused = tags::table
+            .filter(not(tags::id.eq_any(taggings::table.select(taggings::tag_id))))
+            .filter(tags::ttl.is_null())
+            .order(tags::id)
+            .load::<Tag>(conn)?;
+        let backdated = scribbles::table
+            .filter(scribbles::updated_at.lt(scribbles::created_at.nullable()))
+            .order(scribbles::id)
+            .select((scribbles::id, scribbles::created_at, scribbles::updated_at))
+            .load::<(i64, DateTime<Utc>, Option<DateTime<Utc>>)>(conn)?;
+        let all_tags = tags::table.order(tags::id).load::<Tag>(conn)?;
+        let plan = tag_rules::plan_folds(rules, &all_tags, &tag_aliases(conn)?);
+        let similar = doctor::similar_tags(&plan, all_tags);
+        let empty: Vec<i64> = scribbles::table
+            .filter(scribbles::deleted_at.is_null())
+            .order(scribbles::id)
+            .select((scribbles::id, scribbles::text))
+            .load::<(i64, String)>(conn)?
+            .into_iter()
+            .filter(|(_, text)| doctor::is_empty(text))
+            .map(|(id, _)| id)
+            .collect();
+
+        if repair {
+            let orphan_ids: Vec<i64> = orphans.iter().map(|tagging| tagging.id).collect();
+            diesel::delete(taggings::table.filter(taggings::id.eq_any(&orphan_ids)))
+                .execute(conn)?;
+            apply_folds(conn, &plan)?;
+            // Merging may have put an unused tag to use
+            let unused_ids: Vec<i64> = unused.iter().map(|tag| tag.id).collect();
+            diesel::delete(tags::table
+                           .filter(tags::id.eq_any(&unused_ids))
+                           .filter(not(tags::id.eq_any(taggings::table.select(taggings::tag_id)))))
+                .execute(conn)?;
+            let backdated_ids: Vec<i64> = backdated.iter().map(|(id, _, _)| *id).collect();
+            diesel::update(scribbles::table.filter(scribbles::id.eq_any(&backdated_ids)))
+                .set(scribbles::updated_at.eq(scribbles::created_at.nullable()))
+                .execute(conn)?;
+            diesel::update(scribbles::table.filter(scribbles::id.eq_any(&empty)))
+                .set(scribbles::deleted_at.eq(SqlTimestamp(timestamp::now())))
+                .execute(conn)?;
+        }
+
+        let mut findings = Vec::new();
+        findings.extend(orphans.into_iter().map(|tagging| Finding::OrphanTagging {
+            tagging_id: tagging.id,
+            scribble_id: tagging.scribble_id,
+            tag_id: tagging.tag_id,
+        }));
+        findings.extend(unused.into_iter().map(|tag| Finding::UnusedTag {
+            tag_id: tag.id,
+            text: tag.text,
+        }));
+        findings.extend(backdated.into_iter().map(|(scribble_id, created_at, updated_at)| Finding::UpdatedBeforeCreated {
+            scribble_id,
+            created_at,
+            updated_at: updated_at.unwrap_or(created_at),
+        }));
+        findings.extend(similar.into_iter().map(|tags| Finding::SimilarTags { tags }));
+        findings.extend(empty.into_iter().map(|scribble_id| Finding::EmptyScribble { scribble_id }));
+        Ok(findings)
+    })
+}
+
+pub fn tags<C, DB>(conn: &C) -> Result<Vec<Tag>>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
     use self::schema::tags::dsl::*;
 
     let result = tags.load::<Tag>(conn);
@@ -191,27 +1473,146 @@ pub fn tags(conn: &PgConnection) -> Result<Vec<Tag>> {
     }
 }
 
-pub fn list(conn: &PgConnection, size: Option<usize>) -> Result<Vec<Scribble>> {
+/// Returns every tag with its usage by scribbles outside of the trash that
+/// have not expired, the most used first.
+pub fn tag_usage<C, DB>(conn: &C) -> Result<Vec<TagUsage>>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    let result = diesel::sql_query("SELECT tags.*, COUNT(taggings.id) AS count, MAX(taggings.created_at) AS last_used_at FROM tags LEFT JOIN (SELECT taggings.* FROM taggings JOIN scribbles ON scribbles.id = taggings.scribble_id WHERE scribbles.deleted_at IS NULL AND (scribbles.expires_at IS NULL OR scribbles.expires_at > $1)) taggings ON taggings.tag_id = tags.id GROUP BY tags.id ORDER BY count DESC, tags.text;")
+        .bind::<Timestamptz, _>(SqlTimestamp(timestamp::now()))
+        .get_results(conn);
+
+    match result {
+        Err(e) => {
+            Err(Error::DatabaseError(e))
+        },
+        Ok(selected) => {
+            Ok(selected)
+        },
+    }
+}
+
+/// Lists scribbles outside of the trash that have not expired, in the order
+/// of `sort`.
+pub fn list<C, DB>(conn: &C, size: Option<usize>, seek: Option<Seek>, filter: Option<&Query>, period: &Period, tags: &TagFilter, sort: Sort) -> Result<Page>
+    where C: Dialect<Backend = DB>,
+          DB: SqlBackend,
+          i32: FromSql<Integer, DB>,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    use diesel::dsl::sql;
     use self::schema::scribbles::dsl::*;
 
-    let result = scribbles.load::<Scribble>(conn);
+    let key_sql = C::sort_sql(sort.key);
+    let now = timestamp::now();
+    let mut query = scribbles
+        .filter(deleted_at.is_null())
+        .filter(expires_at.is_null().or(expires_at.gt(SqlTimestamp(now))))
+        .into_boxed();
+    if let Some(filter) = filter {
+        query = C::apply_query(filter, query);
+    }
+    query = period.apply(query);
+    query = tags.apply(query);
+    query = sort.apply(&key_sql, seek, query)?;
+    let mut query = query.select((schema::scribbles::all_columns, sql::<BigInt>(key_sql.value)));
+    if let Some(size) = size {
+        // Fetch one extra row to know whether there is another page
+        query = query.limit(size as i64 + 1);
+    }
+
+    let result = query.load::<(Scribble, i64)>(conn);
 
     match result {
         Err(e) => {
             Err(Error::DatabaseError(e))
         },
         Ok(selected) => {
-            Ok(selected)
+            Ok(Page::from_rows(selected, sort, size, seek))
         },
     }
 }
 
-pub fn tags_of(conn: &PgConnection, scribble_id: i64) -> Result<Vec<Tag>> {
-    use diesel::sql_types::BigInt;
+/// A page of search results, best match first.
+#[derive(Debug, Serialize)]
+pub struct SearchPage {
+    pub hits: Vec<SearchHit>,
+    pub next_offset: Option<usize>,
+}
 
-    let result = diesel::sql_query("SELECT tags.* FROM tags, taggings WHERE taggings.scribble_id = $1 AND taggings.tag_id = tags.id;")
-        .bind::<BigInt, _>(scribble_id)
-        .get_results(conn);
+impl SearchPage {
+    pub(crate) fn from_rows(mut rows: Vec<SearchHit>, size: Option<usize>, offset: usize) -> SearchPage {
+        let next_offset = match size {
+            Some(size) if rows.len() > size => {
+                rows.truncate(size);
+                Some(offset + size)
+            },
+            _ => None,
+        };
+        SearchPage {
+            hits: rows,
+            next_offset,
+        }
+    }
+}
+
+/// Searches scribbles for `query`, which is given in the web search syntax of
+/// PostgreSQL (`"exact phrase"`, `or`, `-excluded`).
+///
+/// Snippets mark matched words with `<mark>` and `</mark>`; the rest of the
+/// text is not escaped.
+pub fn search(conn: &PgConnection, query: &str, size: Option<usize>, offset: usize) -> Result<SearchPage> {
+    use diesel::sql_types::Nullable;
+
+    // Fetch one extra row to know whether there is another page
+    let limit = size.map(|size| size as i64 + 1);
+    let result = diesel::sql_query("SELECT scribbles.*, ts_rank(text_tsv, query) AS rank, ts_headline('simple', text, query, 'StartSel=<mark>, StopSel=</mark>, MaxFragments=3') AS snippet FROM scribbles, websearch_to_tsquery('simple', $1) query WHERE text_tsv @@ query AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > $4) ORDER BY rank DESC, created_at DESC, id DESC LIMIT $2 OFFSET $3;")
+        .bind::<Text, _>(query)
+        .bind::<Nullable<BigInt>, _>(limit)
+        .bind::<BigInt, _>(offset as i64)
+        .bind::<Timestamptz, _>(timestamp::now())
+        .get_results::<SearchHit>(conn);
+
+    match result {
+        Err(e) => {
+            Err(Error::DatabaseError(e))
+        },
+        Ok(selected) => {
+            Ok(SearchPage::from_rows(selected, size, offset))
+        },
+    }
+}
+
+
+pub fn tags_of<C, DB>(conn: &C, scribble_id: i64) -> Result<Vec<Tag>>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+          SqlTimestamp: ToSql<Timestamptz, DB>,
+{
+    use self::schema::{scribbles, tags, taggings};
+
+    let live = scribbles::table
+        .filter(scribbles::deleted_at.is_null())
+        .filter(scribbles::expires_at.is_null().or(scribbles::expires_at.gt(SqlTimestamp(timestamp::now()))))
+        .select(scribbles::id);
+    let tag_ids = taggings::table
+        .filter(taggings::scribble_id.eq(scribble_id))
+        .filter(taggings::scribble_id.eq_any(live))
+        .select(taggings::tag_id);
+    let result = tags::table
+        .filter(tags::id.eq_any(tag_ids))
+        .load::<Tag>(conn);
 
     match result {
         Err(e) => {
@@ -222,3 +1623,71 @@ pub fn tags_of(conn: &PgConnection, scribble_id: i64) -> Result<Vec<Tag>> {
         },
     }
 }
+
+/// Returns the tags of each of `scribble_ids` with a query per 500 of them,
+/// leaving out the scribbles without any.
+pub fn tags_of_many<C, DB>(conn: &C, scribble_ids: &[i64]) -> Result<HashMap<i64, Vec<Tag>>>
+    where C: Connection<Backend = DB>,
+          DB: SqlBackend,
+          i64: FromSql<BigInt, DB>,
+          String: FromSql<Text, DB>,
+          DateTime<Utc>: FromSql<Timestamptz, DB>,
+{
+    use self::schema::{tags, taggings};
+
+    let mut tags_of = HashMap::new();
+    // Every id is bound separately and older versions of SQLite allow only
+    // 999 parameters per statement
+    for chunk in scribble_ids.chunks(500) {
+        let selected = taggings::table
+            .inner_join(tags::table.on(tags::id.eq(taggings::tag_id)))
+            .filter(taggings::scribble_id.eq_any(chunk))
+            .order(tags::text)
+            .select((taggings::scribble_id, tags::all_columns))
+            .load::<(i64, Tag)>(conn)?;
+        for (scribble_id, tag) in selected {
+            tags_of.entry(scribble_id).or_insert_with(Vec::new).push(tag);
+        }
+    }
+    Ok(tags_of)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::sort::Key;
+
+    #[test]
+    fn cursors_round_trip() {
+        let time = Utc.ymd(2019, 3, 1).and_hms_micro(9, 30, 0, 123_456);
+        let cursors = [
+            Cursor { sort: Sort::default(), value: timestamp::to_micros(time), id: 42 },
+            Cursor { sort: Sort { key: Key::Updated, descending: false }, value: timestamp::to_micros(time), id: 7 },
+            Cursor { sort: Sort { key: Key::Length, descending: true }, value: 0, id: i64::MAX },
+        ];
+        for cursor in &cursors {
+            assert_eq!(cursor.to_string().parse::<Cursor>().unwrap(), *cursor);
+        }
+        assert_eq!(cursors[0].to_string().len(), 34);
+        assert_eq!(cursors[0].time(), Some(time));
+        assert_eq!(cursors[2].time(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_cursors() {
+        let invalid = [
+            "",
+            "0123",
+            "zz000000000000000000000000000000002a",
+            // Without a sort code, as before times were in microseconds
+            "158a7a5dbc6a4e00000000000000002a",
+            // No sort has the code 0xff
+            "ff0000000000000000000000000000002a",
+            // Too far in the future to be a time
+            "007fffffffffffffff000000000000002a",
+        ];
+        for s in &invalid {
+            assert!(matches!(s.parse::<Cursor>(), Err(Error::InvalidCursor)), "{:?}", s);
+        }
+    }
+}
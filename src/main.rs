@@ -3,14 +3,20 @@ extern crate env_logger;
 extern crate log;
 extern crate structopt;
 
+use std::env;
 use std::io;
 use std::io::prelude::*;
 use std::os::unix::fs::MetadataExt;
+use std::process;
+use std::sync::Arc;
 
+use chrono::prelude::*;
 use diesel::prelude::*;
 use structopt::StructOpt;
+use structopt::clap;
 
 use forghetti::{self,models};
+use forghetti::store::{ScribbleStore, MemoryStore};
 
 
 #[derive(Debug, StructOpt)]
@@ -18,24 +24,102 @@ use forghetti::{self,models};
 enum Args {
     #[structopt(name = "add")]
     Add {
+        /// Forget the scribble after this long, e.g. `90m`, `24h` or `7d`
+        #[structopt(long = "ttl", parse(try_from_str = "forghetti::duration::parse"))]
+        ttl: Option<chrono::Duration>,
+        /// Do nothing if this was already done with the same key, as when retrying
+        #[structopt(long = "idempotency-key")]
+        idempotency_key: Option<String>,
         text: Vec<String>,
     },
+    /// Print a scribble along with its timestamps and tags
+    #[structopt(name = "show")]
+    Show {
+        scribble_id: i64,
+    },
     #[structopt(name = "update")]
     Update {
+        /// Refuse to update the scribble unless it is still at this version
+        #[structopt(long = "expect-version")]
+        expect_version: Option<i32>,
+        /// Do nothing if this was already done with the same key, as when retrying
+        #[structopt(long = "idempotency-key")]
+        idempotency_key: Option<String>,
         scribble_id: i64,
         text: Vec<String>,
     },
+    /// List the revisions of a scribble
+    #[structopt(name = "history")]
+    History {
+        scribble_id: i64,
+    },
+    /// Show the changes between two revisions, by default the latest edit
+    #[structopt(name = "diff")]
+    Diff {
+        scribble_id: i64,
+        from: Option<i32>,
+        to: Option<i32>,
+    },
+    /// Make the text of an older revision current again
+    #[structopt(name = "restore")]
+    Restore {
+        /// Do nothing if this was already done with the same key, as when retrying
+        #[structopt(long = "idempotency-key")]
+        idempotency_key: Option<String>,
+        scribble_id: i64,
+        revision: i32,
+    },
+    /// Move a scribble to the trash
     #[structopt(name = "delete")]
     Delete {
+        /// Do nothing if this was already done with the same key, as when retrying
+        #[structopt(long = "idempotency-key")]
+        idempotency_key: Option<String>,
         scribble_id: i64,
     },
+    /// Bring a scribble back from the trash
+    #[structopt(name = "undelete")]
+    Undelete {
+        /// Do nothing if this was already done with the same key, as when retrying
+        #[structopt(long = "idempotency-key")]
+        idempotency_key: Option<String>,
+        scribble_id: i64,
+    },
+    /// List the scribbles in the trash
+    #[structopt(name = "trash")]
+    Trash {
+        /// Delete everything in the trash for good instead
+        #[structopt(long = "empty")]
+        empty: bool,
+        /// With `--empty`, do nothing if this was already done with the same key, as when retrying
+        #[structopt(long = "idempotency-key", requires = "empty")]
+        idempotency_key: Option<String>,
+    },
+    /// Tag a scribble, or manage tags with a subcommand
     #[structopt(name = "tag")]
     Tag {
+        /// Do nothing if this was already done with the same key, as when retrying
+        #[structopt(long = "idempotency-key")]
+        idempotency_key: Option<String>,
+        tag: Option<String>,
+        scribble_id: Option<i64>,
+        #[structopt(subcommand)]
+        command: Option<TagCommand>,
+    },
+    #[structopt(name = "untag")]
+    Untag {
+        /// Do nothing if this was already done with the same key, as when retrying
+        #[structopt(long = "idempotency-key")]
+        idempotency_key: Option<String>,
         tag: String,
         scribble_id: i64,
     },
     #[structopt(name = "tags")]
-    Tags,
+    Tags {
+        /// Show the tags as trees by their paths, with how many times each was used along with those below it
+        #[structopt(long = "tree")]
+        tree: bool,
+    },
     #[structopt(name = "tags-of")]
     TagsOf {
         scribble_id: i64,
@@ -44,22 +128,192 @@ enum Args {
     List {
         #[structopt(short = "n", long = "size")]
         size: Option<usize>,
+        /// List scribbles older than this cursor
+        #[structopt(long = "before", conflicts_with = "after")]
+        before: Option<forghetti::Cursor>,
+        /// List scribbles newer than this cursor
+        #[structopt(long = "after")]
+        after: Option<forghetti::Cursor>,
+        /// Only list scribbles matching this query, e.g. `tag:work -tag:done after:yesterday`
+        #[structopt(short = "q", long = "query")]
+        query: Option<String>,
+        /// Only list scribbles from this time on: RFC 3339, YYYY-MM-DD, `today`, `yesterday` or a duration ago like `3d`
+        #[structopt(long = "since")]
+        since: Option<String>,
+        /// Only list scribbles from before this time, given as for `--since`
+        #[structopt(long = "until")]
+        until: Option<String>,
+        /// Apply `--since` and `--until` to the time scribbles were `created` or last `updated`
+        #[structopt(long = "by", default_value = "created")]
+        by: forghetti::period::Field,
+        /// Time zone of the dates in `--since`, `--until` and `--query`: `local`, `UTC` or an offset like `+02:00`
+        #[structopt(long = "tz", default_value = "local", env = "FORGHETTI_TZ")]
+        tz: forghetti::period::Zone,
+        /// Order by `created`, `updated`, `length` or `tags`, descending with a leading `-`; defaults to the order of the cursor or else to `-created`
+        #[structopt(long = "sort", raw(allow_hyphen_values = "true"))]
+        sort: Option<forghetti::sort::Sort>,
+        /// Only list scribbles with this tag; all of them if repeated
+        #[structopt(short = "t", long = "tag", raw(number_of_values = "1"))]
+        tags: Vec<String>,
+        /// Only list scribbles with at least one of the tags given this way
+        #[structopt(long = "any-tag", raw(number_of_values = "1"))]
+        any_tags: Vec<String>,
+        /// Leave out scribbles with this tag
+        #[structopt(long = "not-tag", raw(number_of_values = "1"))]
+        not_tags: Vec<String>,
+        /// Only list scribbles without tags
+        #[structopt(long = "untagged")]
+        untagged: bool,
+        /// Take the tags above to include those below them, `project` including `project/bugs`
+        #[structopt(long = "descendants")]
+        descendants: bool,
+    },
+    #[structopt(name = "search")]
+    Search {
+        #[structopt(short = "n", long = "size")]
+        size: Option<usize>,
+        #[structopt(long = "offset", default_value = "0")]
+        offset: usize,
+        query: Vec<String>,
+    },
+    /// Check the database for inconsistencies
+    #[structopt(name = "doctor")]
+    Doctor {
+        /// Fix all the problems found at once, or none if any fix fails
+        #[structopt(long = "repair")]
+        repair: bool,
+        /// Print the findings as JSON
+        #[structopt(long = "json")]
+        json: bool,
     },
     #[structopt(name = "serve")]
     Serve {
         #[structopt(long = "host", default_value = "0.0.0.0")]
         host: String,
+        /// Keep scribbles in memory instead of DATABASE_URL, losing them on exit
+        #[structopt(long = "memory")]
+        memory: bool,
+        /// Days after which scribbles in the trash are deleted for good, 0 to keep them
+        #[structopt(long = "trash-retention-days", default_value = "30", env = "TRASH_RETENTION_DAYS")]
+        trash_retention_days: i64,
+        /// How long to answer retries of requests with the same Idempotency-Key header with the first response
+        #[structopt(long = "idempotency-window", raw(default_value = "DEFAULT_IDEMPOTENCY_WINDOW"), env = "IDEMPOTENCY_WINDOW",
+                    parse(try_from_str = "forghetti::duration::parse"))]
+        idempotency_window: chrono::Duration,
+        /// Write timestamps in responses as nanoseconds since the epoch, as older clients expect
+        #[structopt(long = "legacy-timestamps")]
+        legacy_timestamps: bool,
         #[structopt(name = "PORT")]
         port: u16,
     },
 }
 
+#[derive(Debug, StructOpt)]
+enum TagCommand {
+    /// Rename a tag
+    #[structopt(name = "rename")]
+    Rename {
+        from: String,
+        to: String,
+    },
+    /// Move all scribbles of a tag over to another one and delete it
+    #[structopt(name = "merge")]
+    Merge {
+        from: String,
+        into: String,
+    },
+    /// Delete an unused tag
+    #[structopt(name = "rm")]
+    Remove {
+        tag: String,
+        /// Delete the tag even if scribbles are tagged with it
+        #[structopt(short = "f", long = "force")]
+        force: bool,
+    },
+    /// Forget scribbles after this long once they are tagged, or never if omitted
+    #[structopt(name = "ttl")]
+    Ttl {
+        tag: String,
+        #[structopt(parse(try_from_str = "forghetti::duration::parse"))]
+        ttl: Option<chrono::Duration>,
+    },
+    /// Make another text stand for a tag when tagging, e.g. `js` for `javascript`
+    #[structopt(name = "alias")]
+    Alias {
+        alias: String,
+        tag: String,
+    },
+    /// Forget an alias
+    #[structopt(name = "unalias")]
+    Unalias {
+        alias: String,
+    },
+    /// List aliases along with the tags they stand for
+    #[structopt(name = "aliases")]
+    Aliases,
+    /// Merge tags that are the same once normalized as set by TAG_NORMALIZATION and TAG_CHARACTERS
+    #[structopt(name = "fold")]
+    Fold {
+        /// Only print the merges that would be made
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+}
+
+const DEFAULT_IDEMPOTENCY_WINDOW: &str = "24h";
+
+/// Runs a write command, unless it was already run with the same idempotency
+/// key within the window set by `IDEMPOTENCY_WINDOW`. Commands print nothing
+/// worth replaying, so only the fact that they were run is kept.
+fn idempotent<T>(store: &dyn ScribbleStore, key: Option<&str>, fingerprint: &str, write: impl FnOnce() -> forghetti::Result<T>) -> forghetti::Result<()> {
+    let key = match key {
+        Some(key) => key,
+        None => return write().map(|_| ()),
+    };
+
+    let window = env::var("IDEMPOTENCY_WINDOW").unwrap_or_else(|_| DEFAULT_IDEMPOTENCY_WINDOW.to_owned());
+    let expired_before = Utc::now() - forghetti::duration::parse(&window).unwrap();
+    match store.claim_idempotency_key(key, fingerprint, expired_before) {
+        Ok(None) => {},
+        Ok(Some(_)) => {
+            eprintln!("Already done with idempotency key {:?}", key);
+            return Ok(());
+        },
+        Err(e) => {
+            eprintln!("Cannot use idempotency key {:?}: {}", key, e);
+            process::exit(1);
+        },
+    }
+
+    let result = write();
+    match &result {
+        Ok(_) => store.save_idempotent_response(key, 0, "").unwrap(),
+        Err(_) => store.release_idempotency_key(key).unwrap(),
+    }
+    result.map(|_| ())
+}
+
+/// Formats a timestamp as local time.
+fn format_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// Prints tags indented by their depth in the hierarchy.
+fn print_tag_tree(nodes: &[forghetti::tag_tree::TagNode], depth: usize) {
+    for node in nodes {
+        println!("{:indent$}{} ({})", "", node.name, node.total, indent = depth * 2);
+        print_tag_tree(&node.children, depth + 1);
+    }
+}
+
 fn main() {
     env_logger::init();
 
     let args = Args::from_args();
+    // Tells apart the commands using the same idempotency key
+    let fingerprint = format!("{:?}", args);
     match args {
-        Args::Add { text } => {
+        Args::Add { ttl, idempotency_key, text } => {
             let text = if text.is_empty() {
                 let mut buf = String::new();
                 io::stdin().read_to_string(&mut buf).unwrap();
@@ -68,11 +322,41 @@ fn main() {
             else {
                 text.join(" ")
             };
+            let fingerprint = format!("{} {:?}", fingerprint, text);
+
+            let store = forghetti::establish_store();
+            idempotent(&*store, idempotency_key.as_deref(), &fingerprint, || {
+                store.create_scribble(&text, ttl.map(forghetti::duration::from_now))
+            }).unwrap();
+        },
+        Args::Show { scribble_id } => {
+            let store = forghetti::establish_store();
+            let scribble = match store.get_scribble(scribble_id) {
+                Err(forghetti::Error::ScribbleNotFound) => {
+                    eprintln!("No scribble with id {}", scribble_id);
+                    process::exit(1);
+                },
+                result => result.unwrap(),
+            };
+            let tags: Vec<String> = store.tags_of(scribble_id).unwrap()
+                .into_iter()
+                .map(|tag| tag.text)
+                .collect();
 
-            let conn = forghetti::establish_connection();
-            forghetti::create_scribble(&conn, &text).unwrap();
+            println!("id:      {}", scribble.id);
+            println!("created: {}", format_timestamp(scribble.created_at));
+            if let Some(updated_at) = scribble.updated_at {
+                println!("updated: {}", format_timestamp(updated_at));
+            }
+            if let Some(expires_at) = scribble.expires_at {
+                println!("expires: {}", format_timestamp(expires_at));
+            }
+            println!("version: {}", scribble.version);
+            println!("tags:    {}", tags.join(", "));
+            println!();
+            println!("{}", scribble.text);
         },
-        Args::Update { scribble_id, text } => {
+        Args::Update { expect_version, idempotency_key, scribble_id, text } => {
             let text = if text.is_empty() {
                 let mut buf = String::new();
                 io::stdin().read_to_string(&mut buf).unwrap();
@@ -81,39 +365,220 @@ fn main() {
             else {
                 text.join(" ")
             };
+            let fingerprint = format!("{} {:?}", fingerprint, text);
 
-            let conn = forghetti::establish_connection();
-            forghetti::update_scribble(&conn, scribble_id, &text).unwrap();
+            let store = forghetti::establish_store();
+            let updated = idempotent(&*store, idempotency_key.as_deref(), &fingerprint, || {
+                store.update_scribble(scribble_id, &text, expect_version)
+            });
+            match updated {
+                Err(forghetti::Error::VersionConflict(current)) => {
+                    eprintln!("Scribble {} was changed since version {}, it is now at version {}:",
+                              scribble_id, expect_version.unwrap_or_default(), current.version);
+                    eprintln!("{}", current.text);
+                    process::exit(1);
+                },
+                result => result.unwrap(),
+            }
+        },
+        Args::History { scribble_id } => {
+            let store = forghetti::establish_store();
+            for revision in store.revisions(scribble_id).unwrap() {
+                let summary = revision.text.lines().next().unwrap_or("");
+                println!("{:4} {}: {:?}", revision.revision, format_timestamp(revision.created_at), summary);
+            }
+        },
+        Args::Diff { scribble_id, from, to } => {
+            let store = forghetti::establish_store();
+            print!("{}", store.diff_revisions(scribble_id, from, to).unwrap());
+        },
+        Args::Restore { idempotency_key, scribble_id, revision } => {
+            let store = forghetti::establish_store();
+            idempotent(&*store, idempotency_key.as_deref(), &fingerprint, || store.restore_revision(scribble_id, revision)).unwrap();
         },
-        Args::Delete { scribble_id } => {
-            let conn = forghetti::establish_connection();
-            forghetti::delete_scribble(&conn, scribble_id).unwrap();
+        Args::Delete { idempotency_key, scribble_id } => {
+            let store = forghetti::establish_store();
+            idempotent(&*store, idempotency_key.as_deref(), &fingerprint, || store.delete_scribble(scribble_id, None)).unwrap();
         },
-        Args::Tag { tag, scribble_id } => {
-            let conn = forghetti::establish_connection();
-            forghetti::tag_scribble(&conn, scribble_id, &tag).unwrap();
+        Args::Undelete { idempotency_key, scribble_id } => {
+            let store = forghetti::establish_store();
+            idempotent(&*store, idempotency_key.as_deref(), &fingerprint, || store.undelete_scribble(scribble_id)).unwrap();
         },
-        Args::Tags => {
-            let conn = forghetti::establish_connection();
-            for tag in forghetti::tags(&conn).unwrap() {
+        Args::Trash { empty: true, idempotency_key } => {
+            let store = forghetti::establish_store();
+            idempotent(&*store, idempotency_key.as_deref(), &fingerprint, || {
+                let purged = store.empty_trash(None)?;
+                eprintln!("Deleted {} scribbles for good", purged);
+                Ok(purged)
+            }).unwrap();
+        },
+        Args::Trash { empty: false, .. } => {
+            let store = forghetti::establish_store();
+            for scribble in store.trash().unwrap() {
+                let deleted_at = format_timestamp(scribble.deleted_at.unwrap());
+                println!("{:19}: {} {:?}", scribble.id, deleted_at, &scribble.text);
+            }
+        },
+        Args::Tag { idempotency_key, command: Some(command), .. } => {
+            let store = forghetti::establish_store();
+            let key = idempotency_key.as_deref();
+            match command {
+                TagCommand::Rename { from, to } => {
+                    idempotent(&*store, key, &fingerprint, || store.rename_tag(&from, &to)).unwrap();
+                },
+                TagCommand::Merge { from, into } => {
+                    idempotent(&*store, key, &fingerprint, || store.merge_tags(&from, &into)).unwrap();
+                },
+                TagCommand::Remove { tag, force } => {
+                    idempotent(&*store, key, &fingerprint, || store.delete_tag(&tag, force)).unwrap();
+                },
+                TagCommand::Ttl { tag, ttl } => {
+                    idempotent(&*store, key, &fingerprint, || store.set_tag_ttl(&tag, ttl.map(|ttl| ttl.num_seconds()))).unwrap();
+                },
+                TagCommand::Alias { alias, tag } => {
+                    idempotent(&*store, key, &fingerprint, || store.alias_tag(&alias, &tag)).unwrap();
+                },
+                TagCommand::Unalias { alias } => {
+                    idempotent(&*store, key, &fingerprint, || store.unalias_tag(&alias)).unwrap();
+                },
+                TagCommand::Aliases => {
+                    for alias in store.tag_aliases().unwrap() {
+                        println!("{} -> {}", alias.alias, alias.tag.text);
+                    }
+                },
+                TagCommand::Fold { dry_run } => {
+                    let rules = forghetti::tag_rules::TagRules::from_env().unwrap();
+                    let plan = if dry_run {
+                        forghetti::tag_rules::plan_folds(&rules, &store.tags().unwrap(), &store.tag_aliases().unwrap())
+                    }
+                    else {
+                        store.fold_tags(&rules).unwrap()
+                    };
+                    for text in &plan.invalid {
+                        eprintln!("Cannot normalize tag {:?}, leaving it as it is", text);
+                    }
+                    for fold in &plan.folds {
+                        println!("{:?} -> {:?}", fold.from, fold.into);
+                    }
+                    if !dry_run {
+                        eprintln!("Folded {} tags", plan.folds.len());
+                    }
+                },
+            }
+        },
+        Args::Tag { idempotency_key, tag: Some(tag), scribble_id: Some(scribble_id), command: None } => {
+            let store = forghetti::establish_store();
+            idempotent(&*store, idempotency_key.as_deref(), &fingerprint, || store.tag_scribble(scribble_id, &[tag])).unwrap();
+        },
+        Args::Tag { .. } => {
+            let message = "The following required arguments were not provided:\n    <tag>\n    <scribble_id>";
+            clap::Error::with_description(message, clap::ErrorKind::MissingRequiredArgument).exit();
+        },
+        Args::Untag { idempotency_key, tag, scribble_id } => {
+            let store = forghetti::establish_store();
+            idempotent(&*store, idempotency_key.as_deref(), &fingerprint, || store.untag_scribble(scribble_id, &tag)).unwrap();
+        },
+        Args::Tags { tree: true } => {
+            let store = forghetti::establish_store();
+            print_tag_tree(&forghetti::tag_tree::build(store.tag_usage().unwrap()), 0);
+        },
+        Args::Tags { tree: false } => {
+            let store = forghetti::establish_store();
+            for tag in store.tags().unwrap() {
                 println!("{}", &tag.text);
             }
         },
         Args::TagsOf { scribble_id } => {
-            let conn = forghetti::establish_connection();
-            for tag in forghetti::tags_of(&conn, scribble_id).unwrap() {
+            let store = forghetti::establish_store();
+            for tag in store.tags_of(scribble_id).unwrap() {
                 println!("{}", &tag.text);
             }
         },
-        Args::List { size } => {
-            let conn = forghetti::establish_connection();
-            for scribble in forghetti::list(&conn, size).unwrap() {
+        Args::List { size, before, after, query, since, until, by, tz, sort, tags, any_tags, not_tags, untagged, descendants } => {
+            let seek = match (before, after) {
+                (Some(cursor), _) => Some(forghetti::Seek::Before(cursor)),
+                (_, Some(cursor)) => Some(forghetti::Seek::After(cursor)),
+                (None, None) => None,
+            };
+            let query = query.map(|query| forghetti::query::parse(&query, tz).unwrap_or_else(|e| {
+                let message = format!("Invalid value for '--query <query>': {}", e);
+                clap::Error::with_description(&message, clap::ErrorKind::InvalidValue).exit()
+            }));
+            let period = forghetti::period::Period::parse(since.as_deref(), until.as_deref(), by, tz).unwrap();
+            let sort = sort.or_else(|| seek.map(|seek| seek.cursor().sort())).unwrap_or_default();
+            let tags = forghetti::tag_filter::TagFilter {
+                all: tags,
+                any: any_tags,
+                none: not_tags,
+                untagged,
+                descendants,
+            };
+
+            let store = forghetti::establish_store();
+            let page = store.list(size, seek, query.as_ref(), &period, &tags, sort).unwrap();
+            for scribble in &page.scribbles {
                 println!("{:19}: {:?}", scribble.id, &scribble.text);
             }
+            // Cursors go to stderr so that stdout stays one scribble per line
+            if let Some(cursor) = page.prev_cursor {
+                eprintln!("prev: {}", cursor);
+            }
+            if let Some(cursor) = page.next_cursor {
+                eprintln!("next: {}", cursor);
+            }
         },
-        Args::Serve { host, port } => {
-            let pool = forghetti::new_connection_pool();
-            forghetti::server::start(&host, port, pool);
+        Args::Search { size, offset, query } => {
+            let store = forghetti::establish_store();
+            let page = store.search(&query.join(" "), size, offset).unwrap();
+            for hit in &page.hits {
+                let snippet = hit.snippet.replace("<mark>", "\x1b[1m").replace("</mark>", "\x1b[0m");
+                println!("{:19}: {}", hit.scribble.id, snippet);
+            }
+            if let Some(offset) = page.next_offset {
+                eprintln!("next: --offset {}", offset);
+            }
+        },
+        Args::Doctor { repair, json } => {
+            let store = forghetti::establish_store();
+            let findings = store.check_integrity(repair).unwrap();
+            if json {
+                let report = serde_json::json!({
+                    "findings": findings,
+                    "repaired": repair,
+                });
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            }
+            else {
+                for finding in &findings {
+                    println!("{}", finding);
+                }
+            }
+            if findings.is_empty() {
+                eprintln!("No problems found");
+            }
+            else if repair {
+                eprintln!("Repaired {} problems", findings.len());
+            }
+            else {
+                eprintln!("Found {} problems, run with --repair to fix them", findings.len());
+                process::exit(1);
+            }
+        },
+        Args::Serve { host, memory, trash_retention_days, idempotency_window, legacy_timestamps, port } => {
+            let store: Arc<dyn ScribbleStore> = if memory {
+                let rules = forghetti::tag_rules::TagRules::from_env().unwrap();
+                Arc::new(MemoryStore::new().with_tag_rules(rules))
+            }
+            else {
+                forghetti::establish_store()
+            };
+            let trash_retention = if trash_retention_days > 0 {
+                Some(chrono::Duration::days(trash_retention_days))
+            }
+            else {
+                None
+            };
+            forghetti::server::start(&host, port, store, trash_retention, idempotency_window, legacy_timestamps);
         },
     }
 }
@@ -1,44 +1,85 @@
-use crate::schema::{scribbles, tags, taggings};
+use crate::schema::{idempotency_keys, scribbles, scribble_revisions, tag_aliases, tags, taggings};
 
+use chrono::{DateTime, Utc};
 use diesel::{Queryable, QueryableByName, Insertable};
+use diesel::sql_types::{BigInt, Float, Nullable, Text};
 
+use crate::timestamp::{SqlTimestamp, Timestamptz};
 
-#[derive(Queryable, QueryableByName, Serialize, Deserialize, Debug)]
+
+#[derive(Queryable, QueryableByName, Serialize, Deserialize, Clone, Debug)]
 #[table_name="scribbles"]
 pub struct Scribble {
     pub id:         i64,
-    pub created_at: i64,
-    pub updated_at: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: Option<DateTime<Utc>>,
     pub text:       String,
+    /// When the scribble was moved to the trash
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// When the scribble is forgotten, unless it is kept forever
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Counts the updates, deletions and undeletions of the scribble
+    pub version:    i32,
 }
 
 #[derive(Insertable, Debug)]
 #[table_name="scribbles"]
 pub struct NewScribble<'a> {
-    pub created_at: i64,
+    pub created_at: SqlTimestamp,
     pub text:       &'a str,
+    pub expires_at: Option<SqlTimestamp>,
 }
 
-#[derive(Queryable, QueryableByName, Serialize, Deserialize, Debug)]
+/// The text of a scribble as of one edit. Revisions are numbered from 1 for
+/// each scribble, the latest being its current text.
+#[derive(Queryable, QueryableByName, Serialize, Deserialize, Clone, Debug)]
+#[table_name="scribble_revisions"]
+pub struct ScribbleRevision {
+    pub id:          i64,
+    pub created_at:  DateTime<Utc>,
+    pub scribble_id: i64,
+    pub revision:    i32,
+    pub text:        String,
+}
+
+#[derive(Queryable, QueryableByName, Serialize, Deserialize, Clone, Debug)]
 #[table_name="tags"]
 pub struct Tag {
     pub id:         i64,
-    pub created_at: i64,
+    pub created_at: DateTime<Utc>,
     pub text:       String,
+    /// Seconds after which scribbles tagged with this tag expire
+    pub ttl:        Option<i64>,
 }
 
 #[derive(Insertable, Debug)]
 #[table_name="tags"]
 pub struct NewTag<'a> {
-    pub created_at: i64,
+    pub created_at: SqlTimestamp,
     pub text:       &'a str,
 }
 
-#[derive(Queryable, QueryableByName, Serialize, Deserialize, Debug)]
+/// Another text a tag goes by, which is used in its place when tagging.
+#[derive(Queryable, Serialize, Clone, Debug)]
+pub struct TagAlias {
+    pub alias:      String,
+    pub created_at: DateTime<Utc>,
+    pub tag:        Tag,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name="tag_aliases"]
+pub struct NewTagAlias<'a> {
+    pub alias:      &'a str,
+    pub created_at: SqlTimestamp,
+    pub tag_id:     i64,
+}
+
+#[derive(Queryable, QueryableByName, Serialize, Deserialize, Clone, Debug)]
 #[table_name="taggings"]
 pub struct Tagging {
     pub id:          i64,
-    pub created_at:  i64,
+    pub created_at:  DateTime<Utc>,
     pub scribble_id: i64,
     pub tag_id:      i64,
 }
@@ -46,7 +87,74 @@ pub struct Tagging {
 #[derive(Insertable, Debug)]
 #[table_name="taggings"]
 pub struct NewTagging {
-    pub created_at:  i64,
+    pub created_at:  SqlTimestamp,
     pub scribble_id: i64,
     pub tag_id:      i64,
 }
+
+#[derive(QueryableByName, Serialize, Debug)]
+pub struct SearchHit {
+    #[diesel(embed)]
+    pub scribble: Scribble,
+    #[sql_type = "Float"]
+    pub rank:     f32,
+    #[sql_type = "Text"]
+    pub snippet:  String,
+}
+
+/// A tag along with how many scribbles carry it and when it was last put on
+/// one.
+#[derive(QueryableByName, Serialize, Clone, Debug)]
+pub struct TagUsage {
+    #[diesel(embed)]
+    #[serde(flatten)]
+    pub tag:          Tag,
+    #[sql_type = "BigInt"]
+    pub count:        i64,
+    #[sql_type = "Nullable<Timestamptz>"]
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TaggedScribble {
+    #[serde(flatten)]
+    pub scribble: Scribble,
+    pub tags:     Vec<Tag>,
+}
+
+/// What became of one pair of scribble and tag when tagging in bulk.
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum TaggingStatus {
+    Tagged,
+    AlreadyTagged,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TaggingResult {
+    pub scribble_id: i64,
+    pub tag_text:    String,
+    pub status:      TaggingStatus,
+    /// The new tagging, unless the scribble was already tagged
+    pub tagging:     Option<Tagging>,
+}
+
+/// A write request made with an idempotency key, along with the response to
+/// replay when it is retried.
+#[derive(Queryable, Clone, Debug)]
+pub struct IdempotencyKey {
+    pub key:         String,
+    pub created_at:  DateTime<Utc>,
+    /// What the key was used for, such as the method and path of a request
+    pub fingerprint: String,
+    /// The status of the response, unless the request is still in progress
+    pub status:      Option<i32>,
+    pub body:        Option<String>,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name="idempotency_keys"]
+pub struct NewIdempotencyKey<'a> {
+    pub key:         &'a str,
+    pub created_at:  SqlTimestamp,
+    pub fingerprint: &'a str,
+}
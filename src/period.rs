@@ -0,0 +1,248 @@
+//! Stretches of time to list scribbles from.
+//!
+//! Their bounds can be given as:
+//!
+//! - a point in time in RFC 3339, `2019-03-01T09:30:00+01:00`
+//! - a duration ago, `3d` or `12h`
+//! - `now`, `today` or `yesterday`, the latter two starting at midnight
+//! - a calendar date, `2019-03-01`, starting at midnight
+//!
+//! Midnight is that of a time zone given either as `local`, as `UTC` or as an
+//! offset like `+02:00`.
+
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use diesel::backend::Backend;
+use diesel::prelude::*;
+use diesel::serialize::ToSql;
+use diesel::sql_types::HasSqlType;
+
+use crate::models::Scribble;
+use crate::schema::scribbles;
+use crate::timestamp::{self, SqlTimestamp, Timestamptz};
+use crate::{duration, Error, Result};
+
+
+/// Which time of a scribble a period applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    #[default]
+    Created,
+    /// The time of the last update, or of creation for scribbles never
+    /// updated.
+    Updated,
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Field, String> {
+        match s {
+            "created" => Ok(Field::Created),
+            "updated" => Ok(Field::Updated),
+            _ => Err(format!("expected `created` or `updated`, not `{}`", s)),
+        }
+    }
+}
+
+/// The time zone calendar dates are read in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Zone {
+    /// The time zone of the machine, which follows `TZ`.
+    #[default]
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl FromStr for Zone {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Zone> {
+        let invalid = || Error::InvalidTimeZone(s.to_owned());
+
+        match s {
+            "local" => return Ok(Zone::Local),
+            "UTC" | "utc" | "Z" => return Ok(Zone::Fixed(FixedOffset::east(0))),
+            _ => {},
+        }
+        let sign = match s.get(..1) {
+            Some("+") => 1,
+            Some("-") => -1,
+            _ => return Err(invalid()),
+        };
+        let digits = s[1..].replacen(':', "", 1);
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let (hours, minutes) = match digits.len() {
+            2 => (&digits[..], "0"),
+            4 => digits.split_at(2),
+            _ => return Err(invalid()),
+        };
+        let hours: i32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(Zone::Fixed(FixedOffset::east(sign * (hours * 60 + minutes) * 60)))
+    }
+}
+
+impl Zone {
+    pub(crate) fn today(self) -> NaiveDate {
+        match self {
+            Zone::Local => Local::today().naive_local(),
+            Zone::Fixed(offset) => Utc::now().with_timezone(&offset).date().naive_local(),
+        }
+    }
+
+    /// Returns the first instant of `date` in this time zone.
+    pub(crate) fn start_of_day(self, date: NaiveDate) -> DateTime<Utc> {
+        let midnight = date.and_hms(0, 0, 0);
+        let start = match self {
+            Zone::Local => Local.from_local_datetime(&midnight).earliest().map(|start| start.with_timezone(&Utc)),
+            Zone::Fixed(offset) => offset.from_local_datetime(&midnight).earliest().map(|start| start.with_timezone(&Utc)),
+        };
+        start.unwrap_or_else(|| Utc.from_utc_datetime(&midnight))
+    }
+}
+
+/// Parses a bound of a period as described for this module, failing with
+/// `InvalidTime` otherwise.
+pub fn parse_time(s: &str, zone: Zone) -> Result<DateTime<Utc>> {
+    let invalid = || Error::InvalidTime(s.to_owned());
+
+    match s {
+        "now" => return Ok(timestamp::now()),
+        "today" => return Ok(zone.start_of_day(zone.today())),
+        "yesterday" => return zone.today().pred_opt().map(|date| zone.start_of_day(date)).ok_or_else(invalid),
+        _ => {},
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(zone.start_of_day(date));
+    }
+    let ago = duration::parse(s).map_err(|_| invalid())?;
+    timestamp::now().checked_sub_signed(ago).ok_or_else(invalid)
+}
+
+/// Scribbles created or updated at or after `since` and before `until`, which
+/// are both optional.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Period {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub field: Field,
+}
+
+impl Period {
+    /// Builds a period from bounds written as described for this module.
+    pub fn parse(since: Option<&str>, until: Option<&str>, field: Field, zone: Zone) -> Result<Period> {
+        Ok(Period {
+            since: since.map(|since| parse_time(since, zone)).transpose()?,
+            until: until.map(|until| parse_time(until, zone)).transpose()?,
+            field,
+        })
+    }
+
+    pub(crate) fn contains(&self, scribble: &Scribble) -> bool {
+        let time = match self.field {
+            Field::Created => scribble.created_at,
+            Field::Updated => scribble.updated_at.unwrap_or(scribble.created_at),
+        };
+        self.since.is_none_or(|since| time >= since) && self.until.is_none_or(|until| time < until)
+    }
+
+    /// Narrows down `query` to the scribbles within the period.
+    pub(crate) fn apply<'a, DB>(&self, mut query: scribbles::BoxedQuery<'a, DB>) -> scribbles::BoxedQuery<'a, DB>
+        where DB: Backend + HasSqlType<Timestamptz> + 'a,
+              SqlTimestamp: ToSql<Timestamptz, DB>,
+    {
+        use crate::schema::scribbles::dsl::*;
+
+        if let Some(since) = self.since.map(SqlTimestamp) {
+            query = match self.field {
+                Field::Created => query.filter(created_at.ge(since)),
+                Field::Updated => query.filter(updated_at.ge(since).or(updated_at.is_null().and(created_at.ge(since)))),
+            };
+        }
+        if let Some(until) = self.until.map(SqlTimestamp) {
+            query = match self.field {
+                Field::Created => query.filter(created_at.lt(until)),
+                Field::Updated => query.filter(updated_at.lt(until).or(updated_at.is_null().and(created_at.lt(until)))),
+            };
+        }
+        query
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn scribble(created_at: &str, updated_at: Option<&str>) -> Scribble {
+        Scribble {
+            id: 1,
+            created_at: utc(created_at),
+            updated_at: updated_at.map(utc),
+            text: String::new(),
+            deleted_at: None,
+            expires_at: None,
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn parses_time_zones() {
+        assert_eq!("local".parse::<Zone>().unwrap(), Zone::Local);
+        assert_eq!("UTC".parse::<Zone>().unwrap(), Zone::Fixed(FixedOffset::east(0)));
+        assert_eq!("+02:00".parse::<Zone>().unwrap(), Zone::Fixed(FixedOffset::east(2 * 3600)));
+        assert_eq!("-0530".parse::<Zone>().unwrap(), Zone::Fixed(FixedOffset::west(5 * 3600 + 30 * 60)));
+        assert_eq!("+09".parse::<Zone>().unwrap(), Zone::Fixed(FixedOffset::east(9 * 3600)));
+        for s in &["", "02:00", "+2", "+24:00", "+01:60", "+0a:00"] {
+            assert!(matches!(s.parse::<Zone>(), Err(Error::InvalidTimeZone(z)) if z == *s), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn parses_bounds() {
+        let plus_two: Zone = "+02:00".parse().unwrap();
+        assert_eq!(parse_time("2019-03-01T09:30:00+01:00", plus_two).unwrap(), utc("2019-03-01T08:30:00Z"));
+        assert_eq!(parse_time("2019-03-01", plus_two).unwrap(), utc("2019-02-28T22:00:00Z"));
+
+        let today = parse_time("today", plus_two).unwrap();
+        assert_eq!(parse_time("yesterday", plus_two).unwrap(), today - chrono::Duration::days(1));
+        let three_days_ago = parse_time("3d", plus_two).unwrap();
+        let ago = timestamp::now() - three_days_ago;
+        assert!(ago >= chrono::Duration::days(3) && ago < chrono::Duration::days(3) + chrono::Duration::minutes(1));
+
+        assert!(matches!(parse_time("soon", plus_two), Err(Error::InvalidTime(s)) if s == "soon"));
+        assert!(Period::parse(Some("2019-03-01"), Some("later"), Field::Created, plus_two).is_err());
+    }
+
+    #[test]
+    fn contains_scribbles_by_the_field_chosen() {
+        let period = Period {
+            since: Some(utc("2019-03-01T00:00:00Z")),
+            until: Some(utc("2019-04-01T00:00:00Z")),
+            field: Field::Created,
+        };
+        assert!(period.contains(&scribble("2019-03-01T00:00:00Z", None)));
+        assert!(!period.contains(&scribble("2019-04-01T00:00:00Z", None)));
+        assert!(period.contains(&scribble("2019-03-15T00:00:00Z", Some("2019-05-01T00:00:00Z"))));
+
+        let period = Period { field: Field::Updated, ..period };
+        assert!(!period.contains(&scribble("2019-03-15T00:00:00Z", Some("2019-05-01T00:00:00Z"))));
+        assert!(period.contains(&scribble("2019-02-01T00:00:00Z", Some("2019-03-15T00:00:00Z"))));
+        assert!(period.contains(&scribble("2019-03-15T00:00:00Z", None)));
+        assert!(Period::default().contains(&scribble("1970-01-01T00:00:00Z", None)));
+    }
+}
@@ -0,0 +1,349 @@
+//! A small query language for filtering scribbles.
+//!
+//! A query is a whitespace separated list of terms, all of which have to
+//! match:
+//!
+//! - `word` and `"exact phrase"` match the text of a scribble
+//! - `tag:work` matches scribbles tagged with `work`
+//! - `before:2019-03-01` and `after:yesterday` match the date the scribble
+//!   was created on in the time zone the query is parsed in; `before` is
+//!   exclusive and `after` inclusive
+//! - `id:123` matches a single scribble
+//!
+//! Any term can be negated with a leading `-`, and values containing spaces
+//! can be quoted as in `tag:"to do"`. Other words with a colon, such as
+//! `https://example.com` or `10:30`, are plain words.
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::{CharIndices, FromStr};
+
+use chrono::prelude::*;
+use diesel::dsl::{not, sql};
+use diesel::expression::BoxableExpression;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Text};
+
+use crate::period::Zone;
+use crate::schema::{scribbles, taggings, tags};
+use crate::timestamp::SqlTimestamp;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Word(String),
+    Phrase(String),
+    Tag(String),
+    /// Created before the start of a day
+    Before(DateTime<Utc>),
+    /// Created from the start of a day on
+    After(DateTime<Utc>),
+    Id(i64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clause {
+    pub negated: bool,
+    pub term:    Term,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Query {
+    pub clauses: Vec<Clause>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    UnterminatedQuote,
+    EmptyTerm,
+    MissingValue(String),
+    InvalidDate(String),
+    InvalidId(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind:     ParseErrorKind,
+    /// Byte offset of the offending term in the query
+    pub position: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ParseErrorKind::UnterminatedQuote => write!(f, "unterminated quote")?,
+            ParseErrorKind::EmptyTerm => write!(f, "`-` must be followed by a term")?,
+            ParseErrorKind::MissingValue(field) => write!(f, "missing value for `{}`", field)?,
+            ParseErrorKind::InvalidDate(value) => write!(f, "invalid date `{}`, expected YYYY-MM-DD, `today` or `yesterday`", value)?,
+            ParseErrorKind::InvalidId(value) => write!(f, "invalid id `{}`", value)?,
+        }
+        write!(f, " at position {}", self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parses a query reading dates in local time.
+impl FromStr for Query {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Query, ParseError> {
+        parse(s, Zone::Local)
+    }
+}
+
+const FIELDS: &[&str] = &["tag", "before", "after", "id"];
+
+/// Parses a query, reading dates in `zone`.
+pub fn parse(input: &str, zone: Zone) -> Result<Query, ParseError> {
+    let mut chars = input.char_indices().peekable();
+    let mut clauses = Vec::new();
+
+    loop {
+        while let Some(&(_, c)) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            }
+            else {
+                break;
+            }
+        }
+        let start = match chars.peek() {
+            Some(&(i, _)) => i,
+            None => break,
+        };
+
+        let negated = match chars.peek() {
+            Some(&(_, '-')) => {
+                chars.next();
+                true
+            },
+            _ => false,
+        };
+
+        let term = match chars.peek() {
+            None => {
+                return Err(ParseError { kind: ParseErrorKind::EmptyTerm, position: start });
+            },
+            Some(&(_, c)) if c.is_whitespace() => {
+                return Err(ParseError { kind: ParseErrorKind::EmptyTerm, position: start });
+            },
+            Some(&(_, '"')) => {
+                Term::Phrase(read_quoted(&mut chars, start)?)
+            },
+            Some(_) => {
+                let word = read_bare(&mut chars, true);
+                match word.strip_suffix(':') {
+                    Some(field) if FIELDS.contains(&field) => {
+                        let value = match chars.peek() {
+                            Some(&(_, '"')) => read_quoted(&mut chars, start)?,
+                            _ => read_bare(&mut chars, false),
+                        };
+                        field_term(field, value, start, zone)?
+                    },
+                    _ => Term::Word(word + &read_bare(&mut chars, false)),
+                }
+            },
+        };
+
+        clauses.push(Clause { negated, term });
+    }
+
+    Ok(Query { clauses })
+}
+
+/// Reads an unquoted word, stopping after the first `:` if `field` is set.
+fn read_bare(chars: &mut Peekable<CharIndices>, field: bool) -> String {
+    let mut word = String::new();
+    while let Some(&(_, c)) = chars.peek() {
+        if c.is_whitespace() || c == '"' {
+            break;
+        }
+        chars.next();
+        word.push(c);
+        if field && c == ':' {
+            break;
+        }
+    }
+    word
+}
+
+fn read_quoted(chars: &mut Peekable<CharIndices>, start: usize) -> Result<String, ParseError> {
+    // Skip the opening quote
+    chars.next();
+    let mut text = String::new();
+    for (_, c) in chars {
+        if c == '"' {
+            return Ok(text);
+        }
+        text.push(c);
+    }
+    Err(ParseError { kind: ParseErrorKind::UnterminatedQuote, position: start })
+}
+
+fn field_term(field: &str, value: String, position: usize, zone: Zone) -> Result<Term, ParseError> {
+    let error = |kind| Err(ParseError { kind, position });
+
+    if value.is_empty() {
+        return error(ParseErrorKind::MissingValue(field.to_owned()));
+    }
+    match field {
+        "tag" => Ok(Term::Tag(value)),
+        "before" => match parse_date(&value, zone) {
+            Some(date) => Ok(Term::Before(date)),
+            None => error(ParseErrorKind::InvalidDate(value)),
+        },
+        "after" => match parse_date(&value, zone) {
+            Some(date) => Ok(Term::After(date)),
+            None => error(ParseErrorKind::InvalidDate(value)),
+        },
+        "id" => match value.parse() {
+            Ok(id) => Ok(Term::Id(id)),
+            Err(_) => error(ParseErrorKind::InvalidId(value)),
+        },
+        _ => unreachable!(),
+    }
+}
+
+/// Returns the start of the day `value` stands for in `zone`.
+fn parse_date(value: &str, zone: Zone) -> Option<DateTime<Utc>> {
+    let today = zone.today();
+    let date = match value {
+        "today" => Some(today),
+        "yesterday" => today.pred_opt(),
+        _ => NaiveDate::parse_from_str(value, "%Y-%m-%d").ok(),
+    };
+    date.map(|date| zone.start_of_day(date))
+}
+
+type Predicate<'a> = Box<dyn BoxableExpression<scribbles::table, Pg, SqlType = Bool> + 'a>;
+
+impl Term {
+    fn predicate(&self) -> Predicate<'_> {
+        match self {
+            Term::Word(word) => {
+                Box::new(sql::<Bool>("scribbles.text_tsv @@ plainto_tsquery('simple', ")
+                         .bind::<Text, _>(word.as_str())
+                         .sql(")"))
+            },
+            Term::Phrase(phrase) => {
+                Box::new(sql::<Bool>("scribbles.text_tsv @@ phraseto_tsquery('simple', ")
+                         .bind::<Text, _>(phrase.as_str())
+                         .sql(")"))
+            },
+            Term::Tag(tag) => {
+                let tag_ids = tags::table
+                    .filter(tags::text.eq(tag.as_str()))
+                    .select(tags::id);
+                let scribble_ids = taggings::table
+                    .filter(taggings::tag_id.eq_any(tag_ids))
+                    .select(taggings::scribble_id);
+                Box::new(scribbles::id.eq_any(scribble_ids))
+            },
+            Term::Before(time) => {
+                Box::new(scribbles::created_at.lt(SqlTimestamp(*time)))
+            },
+            Term::After(time) => {
+                Box::new(scribbles::created_at.ge(SqlTimestamp(*time)))
+            },
+            Term::Id(id) => {
+                Box::new(scribbles::id.eq(*id))
+            },
+        }
+    }
+}
+
+impl Query {
+    /// Returns the query with the text of every `tag:` term replaced by what
+    /// `canonical` makes of it, see `TagFilter::map_texts`.
+    pub(crate) fn map_tags<F>(&self, canonical: F) -> crate::Result<Query>
+        where F: Fn(&str) -> crate::Result<String>,
+    {
+        let clauses = self.clauses.iter()
+            .map(|clause| Ok(Clause {
+                negated: clause.negated,
+                term: match &clause.term {
+                    Term::Tag(text) => Term::Tag(canonical(text)?),
+                    term => term.clone(),
+                },
+            }))
+            .collect::<crate::Result<_>>()?;
+        Ok(Query { clauses })
+    }
+
+    /// Narrows down `query` to the scribbles matching all the clauses.
+    pub(crate) fn apply<'a>(&'a self, mut query: scribbles::BoxedQuery<'a, Pg>) -> scribbles::BoxedQuery<'a, Pg> {
+        for clause in &self.clauses {
+            let predicate = clause.term.predicate();
+            query = if clause.negated {
+                query.filter(not(predicate))
+            }
+            else {
+                query.filter(predicate)
+            };
+        }
+        query
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(input: &str) -> Vec<(bool, Term)> {
+        parse(input, Zone::Local).unwrap().clauses.into_iter().map(|clause| (clause.negated, clause.term)).collect()
+    }
+
+    fn error(input: &str) -> ParseErrorKind {
+        parse(input, Zone::Local).unwrap_err().kind
+    }
+
+    #[test]
+    fn parses_words_phrases_and_fields() {
+        assert_eq!(terms(r#"milk "to do" -tag:done tag:"big deal" id:42"#), vec![
+            (false, Term::Word("milk".to_owned())),
+            (false, Term::Phrase("to do".to_owned())),
+            (true, Term::Tag("done".to_owned())),
+            (false, Term::Tag("big deal".to_owned())),
+            (false, Term::Id(42)),
+        ]);
+        assert_eq!(terms("after:2019-03-01 before:2019-04-01"), vec![
+            (false, Term::After(Zone::Local.start_of_day(NaiveDate::from_ymd(2019, 3, 1)))),
+            (false, Term::Before(Zone::Local.start_of_day(NaiveDate::from_ymd(2019, 4, 1)))),
+        ]);
+        assert!(terms("  ").is_empty());
+    }
+
+    #[test]
+    fn reads_dates_in_the_zone_given() {
+        let zone: Zone = "+02:00".parse().unwrap();
+        let query = parse("after:2019-03-01", zone).unwrap();
+        assert_eq!(query.clauses[0].term, Term::After(Utc.ymd(2019, 2, 28).and_hms(22, 0, 0)));
+    }
+
+    #[test]
+    fn words_with_colons_are_not_fields() {
+        assert_eq!(terms("https://example.com 10:30 -note:"), vec![
+            (false, Term::Word("https://example.com".to_owned())),
+            (false, Term::Word("10:30".to_owned())),
+            (true, Term::Word("note:".to_owned())),
+        ]);
+    }
+
+    #[test]
+    fn reports_errors_with_their_position() {
+        assert_eq!(parse("milk -", Zone::Local).unwrap_err(), ParseError { kind: ParseErrorKind::EmptyTerm, position: 5 });
+        assert_eq!(error(r#""to do"#), ParseErrorKind::UnterminatedQuote);
+        assert_eq!(error("tag:"), ParseErrorKind::MissingValue("tag".to_owned()));
+        assert_eq!(error("before:March"), ParseErrorKind::InvalidDate("March".to_owned()));
+        assert_eq!(error("id:one"), ParseErrorKind::InvalidId("one".to_owned()));
+    }
+
+    #[test]
+    fn maps_tag_texts() {
+        let query: Query = "tag:Work milk -tag:Done".parse().unwrap();
+        let mapped = query.map_tags(|text| Ok(text.to_lowercase())).unwrap();
+        assert_eq!(mapped, "tag:work milk -tag:done".parse().unwrap());
+    }
+}
@@ -1,31 +1,89 @@
 table! {
+    use diesel::sql_types::*;
+    use crate::timestamp::Timestamptz;
+
+    idempotency_keys (key) {
+        key -> Text,
+        created_at -> Timestamptz,
+        fingerprint -> Text,
+        status -> Nullable<Int4>,
+        body -> Nullable<Text>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::timestamp::Timestamptz;
+
+    scribble_revisions (id) {
+        id -> Int8,
+        created_at -> Timestamptz,
+        scribble_id -> Int8,
+        revision -> Int4,
+        text -> Text,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::timestamp::Timestamptz;
+
     scribbles (id) {
         id -> Int8,
-        created_at -> Int8,
-        updated_at -> Nullable<Int8>,
+        created_at -> Timestamptz,
+        updated_at -> Nullable<Timestamptz>,
         text -> Text,
+        deleted_at -> Nullable<Timestamptz>,
+        expires_at -> Nullable<Timestamptz>,
+        version -> Int4,
     }
 }
 
 table! {
+    use diesel::sql_types::*;
+    use crate::timestamp::Timestamptz;
+
+    tag_aliases (alias) {
+        alias -> Text,
+        created_at -> Timestamptz,
+        tag_id -> Int8,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::timestamp::Timestamptz;
+
     taggings (id) {
         id -> Int8,
-        created_at -> Int8,
+        created_at -> Timestamptz,
         scribble_id -> Int8,
         tag_id -> Int8,
     }
 }
 
 table! {
+    use diesel::sql_types::*;
+    use crate::timestamp::Timestamptz;
+
     tags (id) {
         id -> Int8,
-        created_at -> Int8,
+        created_at -> Timestamptz,
         text -> Text,
+        ttl -> Nullable<Int8>,
     }
 }
 
+joinable!(scribble_revisions -> scribbles (scribble_id));
+joinable!(tag_aliases -> tags (tag_id));
+joinable!(taggings -> scribbles (scribble_id));
+joinable!(taggings -> tags (tag_id));
+
 allow_tables_to_appear_in_same_query!(
+    idempotency_keys,
+    scribble_revisions,
     scribbles,
+    tag_aliases,
     taggings,
     tags,
 );
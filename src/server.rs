@@ -1,20 +1,31 @@
 pub mod db;
+mod sweeper;
+mod v1;
 
 use std::env;
+use std::sync::Arc;
 
 use actix::prelude::*;
-use actix_web::{http, server, App, HttpRequest, HttpResponse, AsyncResponder, FutureResponse, State, Json, Query, Result, fs::NamedFile, middleware::Logger, middleware::cors::Cors};
-use actix_web::middleware::{Middleware, Started};
-use argon2;
-use diesel::prelude::*;
-use diesel::r2d2::{ConnectionManager, Pool};
+use actix_web::{http, server, Body, App, FromRequest, HttpMessage, HttpRequest, HttpResponse, AsyncResponder, FutureResponse, Query, State, Result, fs::NamedFile, middleware::Logger, middleware::cors::Cors};
+use actix_web::dev::JsonConfig;
+use actix_web::error::JsonPayloadError;
+use actix_web::http::header::HeaderValue;
+use actix_web::middleware::{Middleware, Started, Response};
 use dotenv::dotenv;
+use chrono::prelude::*;
 use futures::Future;
-use futures::future::result;
+use futures::future::{result, Either};
 use jsonwebtoken as jwt;
-use serde_json::json;
+use log::error;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 
-use db::{CreateScribble, UpdateScribble, DeleteScribble, TagScribble, List};
+use db::{CreateScribble, UpdateScribble, DeleteScribble, TagScribble, UntagScribble, RenameTag, MergeTags, DeleteTag, Batch, ClaimIdempotencyKey, SaveIdempotentResponse, ReleaseIdempotencyKey};
+use crate::Error;
+use crate::batch::{self, Mode, Operation};
+use crate::models::{IdempotencyKey, TaggingResult};
+use crate::store::ScribbleStore;
 
 
 struct AppState {
@@ -29,7 +40,7 @@ impl<S> Middleware<S> for JwtAuthorization {
 
         let server_secret = env::var("SERVER_SECRET").expect("SERVER_SECRET must be set.");
 
-        if req.path() == "/login" {
+        if req.path() == "/login" || req.path() == "/api/v1/login" {
             Ok(Started::Done)
         }
         else if req.method() == http::Method::OPTIONS {
@@ -37,42 +48,360 @@ impl<S> Middleware<S> for JwtAuthorization {
             Ok(Started::Done)
         }
         else {
-            match req.headers().get(http::header::AUTHORIZATION) {
-                Some(identity) => {
-                    let token = identity.to_str().unwrap().split_whitespace().nth(1).unwrap();
+            let token = req.headers().get(http::header::AUTHORIZATION)
+                .and_then(|identity| identity.to_str().ok())
+                .and_then(|identity| identity.split_whitespace().nth(1));
+            match token {
+                Some(token) => {
                     let validation = jwt::Validation {
                         validate_exp: false,
                         ..jwt::Validation::default()
                     };
-                    match jwt::decode::<Claims>(&token, server_secret.as_ref(), &validation) {
+                    match jwt::decode::<Claims>(token, server_secret.as_ref(), &validation) {
                         Ok(_) => Ok(Started::Done),
-                        Err(_) => Ok(Started::Response(HttpResponse::Unauthorized().finish())),
+                        Err(_) => Ok(Started::Response(unauthorized(req.path(), "invalid token"))),
                     }
                 },
-                None => Ok(Started::Response(HttpResponse::Unauthorized().finish())),
+                None => Ok(Started::Response(unauthorized(req.path(), "missing token"))),
             }
         }
     }
 }
 
-pub fn start(host: &str, port: u16, pool: Pool<ConnectionManager<PgConnection>>) {
+/// Answers a request without a valid token, with an empty body on the
+/// legacy routes as they always did.
+fn unauthorized(path: &str, message: &str) -> HttpResponse {
+    if path.starts_with("/api/") || path == "/batch" {
+        error_body(http::StatusCode::UNAUTHORIZED, "Unauthorized", message)
+    }
+    else {
+        HttpResponse::Unauthorized().finish()
+    }
+}
+
+/// Marks the responses of a legacy route as deprecated in favour of the
+/// given `/api/v1` resource.
+struct Deprecated(&'static str);
+
+impl<S> Middleware<S> for Deprecated {
+    fn response(&self, _req: &HttpRequest<S>, mut resp: HttpResponse) -> Result<Response> {
+        let link = format!("<{}>; rel=\"successor-version\"", self.0);
+        resp.headers_mut().insert("Deprecation", HeaderValue::from_static("true"));
+        resp.headers_mut().insert(http::header::LINK, HeaderValue::from_str(&link).unwrap());
+        Ok(Response::Done(resp))
+    }
+}
+
+/// Writes the timestamps in responses as nanoseconds since the epoch, as they
+/// were before they became RFC 3339, for clients that still expect numbers.
+struct LegacyTimestamps;
+
+impl<S> Middleware<S> for LegacyTimestamps {
+    fn response(&self, _req: &HttpRequest<S>, mut resp: HttpResponse) -> Result<Response> {
+        let is_json = resp.headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("application/json"));
+        let json = match resp.body() {
+            Body::Binary(binary) if is_json => serde_json::from_slice::<Value>(binary.as_ref()).ok(),
+            _ => None,
+        };
+        if let Some(mut json) = json {
+            timestamps_to_nanos(&mut json);
+            resp.set_body(json.to_string());
+        }
+        Ok(Response::Done(resp))
+    }
+}
+
+/// Replaces the RFC 3339 values of the fields named like `created_at` by
+/// nanoseconds, throughout `json`.
+fn timestamps_to_nanos(json: &mut Value) {
+    match json {
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                let time = match value {
+                    Value::String(text) if name.ends_with("_at") => DateTime::parse_from_rfc3339(text).ok(),
+                    _ => None,
+                };
+                match time {
+                    Some(time) => *value = crate::timestamp::to_nanos(time.with_timezone(&Utc)).into(),
+                    None => timestamps_to_nanos(value),
+                }
+            }
+        },
+        Value::Array(items) => items.iter_mut().for_each(timestamps_to_nanos),
+        _ => {},
+    }
+}
+
+/// Replays the response to a write request retried with the same
+/// `Idempotency-Key` header instead of handling it again, for as long as
+/// `window` after the first request. The key is tied to the method, URI and
+/// a hash of the body of the request.
+///
+/// Responses with a server error are not kept, so that the request can be
+/// retried for real. Logins are left out, as replaying one would hand out a
+/// token without checking the password.
+struct Idempotency {
+    window: chrono::Duration,
+}
+
+/// The idempotency key claimed by the request being handled.
+struct ClaimedKey(String);
+
+impl Middleware<AppState> for Idempotency {
+    fn start(&self, req: &HttpRequest<AppState>) -> Result<Started> {
+        use http::Method;
+
+        let key = match req.headers().get("Idempotency-Key").and_then(|key| key.to_str().ok()) {
+            Some(key) => key.to_owned(),
+            None => return Ok(Started::Done),
+        };
+        match *req.method() {
+            Method::POST | Method::PUT | Method::PATCH | Method::DELETE => {},
+            _ => return Ok(Started::Done),
+        }
+        if req.path() == "/login" || req.path() == "/api/v1/login" {
+            return Ok(Started::Done);
+        }
+
+        let req = req.clone();
+        let expired_before = Utc::now() - self.window;
+        let claim = req.body()
+            .from_err()
+            .and_then(move |body| {
+                let fingerprint = format!("{} {} {:x}", req.method(), req.uri(), Sha256::digest(&body));
+                req.extensions_mut().insert(BufferedBody(body.to_vec()));
+                req.state()
+                    .db
+                    .send(ClaimIdempotencyKey { key: key.clone(), fingerprint, expired_before })
+                    .from_err()
+                    .map(move |res| match res {
+                        Ok(None) => {
+                            req.extensions_mut().insert(ClaimedKey(key));
+                            None
+                        },
+                        Ok(Some(claimed)) => Some(replayed_response(&claimed)),
+                        Err(e) => Some(error_response(&e)),
+                    })
+            });
+        Ok(Started::Future(Box::new(claim)))
+    }
+
+    fn response(&self, req: &HttpRequest<AppState>, resp: HttpResponse) -> Result<Response> {
+        let key = match req.extensions_mut().remove::<ClaimedKey>() {
+            Some(ClaimedKey(key)) => key,
+            None => return Ok(Response::Done(resp)),
+        };
+
+        let db = &req.state().db;
+        let finish = if resp.status().is_server_error() {
+            Either::A(db.send(ReleaseIdempotencyKey { key }))
+        }
+        else {
+            let body = match resp.body() {
+                Body::Binary(binary) => String::from_utf8_lossy(binary.as_ref()).into_owned(),
+                _ => String::new(),
+            };
+            Either::B(db.send(SaveIdempotentResponse {
+                key,
+                status: i32::from(resp.status().as_u16()),
+                body,
+            }))
+        };
+        let finish = finish.then(move |res| {
+            match res {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => error!("Failed to store the response for an idempotency key: {}", e),
+                Err(e) => error!("Failed to store the response for an idempotency key: {}", e),
+            }
+            Ok(resp)
+        });
+        Ok(Response::Future(Box::new(finish)))
+    }
+}
+
+/// The body of a request, read by `Idempotency` before the handler runs.
+struct BufferedBody(Vec<u8>);
+
+/// `actix_web::Json`, which takes the body from `BufferedBody` if it was read
+/// already, as the payload can only be read once.
+pub struct Json<T>(pub T);
+
+impl<T> Json<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> std::ops::Deref for Json<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned + 'static,
+    S: 'static,
+{
+    type Config = JsonConfig<S>;
+    type Result = Box<dyn Future<Item = Self, Error = actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest<S>, cfg: &Self::Config) -> Self::Result {
+        match req.extensions_mut().remove::<BufferedBody>() {
+            Some(BufferedBody(body)) => {
+                let json = match req.mime_type() {
+                    Ok(Some(mime)) => mime.subtype() == "json" || mime.suffix().is_some_and(|suffix| suffix == "json"),
+                    _ => false,
+                };
+                let parsed = if json {
+                    serde_json::from_slice(&body).map_err(JsonPayloadError::Deserialize)
+                }
+                else {
+                    Err(JsonPayloadError::ContentType)
+                };
+                Box::new(result(parsed.map(Json).map_err(actix_web::Error::from)))
+            },
+            None => Box::new(actix_web::Json::<T>::from_request(req, cfg).map(|json| Json(json.into_inner()))),
+        }
+    }
+}
+
+fn replayed_response(claimed: &IdempotencyKey) -> HttpResponse {
+    let status = claimed.status
+        .and_then(|status| http::StatusCode::from_u16(status as u16).ok())
+        .unwrap_or(http::StatusCode::OK);
+    HttpResponse::build(status)
+        .content_type("application/json")
+        .header("Idempotent-Replayed", "true")
+        .body(claimed.body.clone().unwrap_or_default())
+}
+
+/// Serves the API until the process is stopped, purging scribbles that have
+/// been in the trash for longer than `trash_retention`, if given, and
+/// replaying responses to requests with an idempotency key for
+/// `idempotency_window`. With `legacy_timestamps`, timestamps in responses are
+/// nanoseconds since the epoch rather than RFC 3339.
+pub fn start(host: &str, port: u16, store: Arc<dyn ScribbleStore>, trash_retention: Option<chrono::Duration>, idempotency_window: chrono::Duration, legacy_timestamps: bool) {
+    use http::Method;
+
     let sys = actix::System::new("diesel-example");
-    let addr = SyncArbiter::start(3, move || db::DbExecutor(pool.clone()));
+    let addr = SyncArbiter::start(3, move || db::DbExecutor(store.clone()));
+    sweeper::Sweeper {
+        db: addr.clone(),
+        trash_retention,
+        idempotency_window,
+    }.start();
     server::new(move || {
-        App::with_state(AppState { db: addr.clone() })
-            .middleware(Logger::default())
+        let app = App::with_state(AppState { db: addr.clone() })
+            .middleware(Logger::default());
+        let app = if legacy_timestamps {
+            app.middleware(LegacyTimestamps)
+        }
+        else {
+            app
+        };
+        app
             .middleware(JwtAuthorization)
+            .middleware(Idempotency { window: idempotency_window })
             .configure(|app| {
                 Cors::for_app(app)
                     .max_age(5)  // Cache the result of a preflight request at most 5 seconds
                     .send_wildcard()
-                    .resource("/", |r| r.method(http::Method::GET).f(handle_root))
-                    .resource("/add", |r| r.method(http::Method::POST).with(handle_add))
-                    .resource("/update", |r| r.method(http::Method::POST).with(handle_update))
-                    .resource("/delete", |r| r.method(http::Method::POST).with(handle_delete))
-                    .resource("/tag", |r| r.method(http::Method::POST).with(handle_tag))
-                    .resource("/list", |r| r.method(http::Method::GET).with(handle_list))
-                    .resource("/login", |r| r.method(http::Method::POST).with(handle_login))
+                    .resource("/", |r| r.method(Method::GET).f(handle_root))
+                    .resource("/api/v1/scribbles", |r| {
+                        r.method(Method::GET).with(v1::list_scribbles);
+                        r.method(Method::POST).with(v1::create_scribble);
+                    })
+                    .resource("/api/v1/scribbles/{id}", |r| {
+                        r.method(Method::GET).with(v1::get_scribble);
+                        r.method(Method::PATCH).with(v1::update_scribble);
+                        r.method(Method::DELETE).with(v1::delete_scribble);
+                    })
+                    .resource("/api/v1/scribbles/{id}/revisions", |r| r.method(Method::GET).with(v1::revisions))
+                    .resource("/api/v1/scribbles/{id}/revisions/{rev}/restore", |r| r.method(Method::POST).with(v1::restore_revision))
+                    .resource("/api/v1/scribbles/{id}/diff", |r| r.method(Method::GET).with(v1::diff_revisions))
+                    .resource("/api/v1/scribbles/{id}/tags", |r| {
+                        r.method(Method::GET).with(v1::tags_of);
+                        r.method(Method::POST).with(v1::tag_scribble);
+                    })
+                    .resource("/api/v1/scribbles/{id}/tags/{tag}", |r| r.method(Method::DELETE).with(v1::untag_scribble))
+                    .resource("/api/v1/taggings", |r| r.method(Method::POST).with(v1::tag_scribbles))
+                    .resource("/api/v1/tags", |r| {
+                        r.method(Method::GET).with(v1::list_tags);
+                        r.method(Method::POST).with(v1::create_tag);
+                    })
+                    .resource("/api/v1/tags/{tag}", |r| {
+                        r.method(Method::PATCH).with(v1::rename_tag);
+                        r.method(Method::DELETE).with(v1::delete_tag);
+                    })
+                    .resource("/api/v1/tags/{tag}/merge", |r| r.method(Method::POST).with(v1::merge_tags))
+                    .resource("/api/v1/tags/{tag}/ttl", |r| {
+                        r.method(Method::PUT).with(v1::set_tag_ttl);
+                        r.method(Method::DELETE).with(v1::clear_tag_ttl)
+                    })
+                    .resource("/api/v1/tag-aliases", |r| {
+                        r.method(Method::GET).with(v1::tag_aliases);
+                        r.method(Method::POST).with(v1::alias_tag);
+                    })
+                    .resource("/api/v1/tag-aliases/{alias}", |r| r.method(Method::DELETE).with(v1::unalias_tag))
+                    .resource("/api/v1/trash", |r| {
+                        r.method(Method::GET).with(v1::trash);
+                        r.method(Method::DELETE).with(v1::empty_trash);
+                    })
+                    .resource("/api/v1/trash/{id}/restore", |r| r.method(Method::POST).with(v1::undelete_scribble))
+                    .resource("/api/v1/search", |r| r.method(Method::GET).with(v1::search))
+                    .resource("/api/v1/login", |r| r.method(Method::POST).with(v1::login))
+                    .resource("/batch", |r| r.method(Method::POST).with(handle_batch))
+                    // Deprecated RPC-style routes, kept for existing clients
+                    .resource("/add", |r| {
+                        r.middleware(Deprecated("/api/v1/scribbles"));
+                        r.method(Method::POST).with(handle_add)
+                    })
+                    .resource("/update", |r| {
+                        r.middleware(Deprecated("/api/v1/scribbles"));
+                        r.method(Method::POST).with(handle_update)
+                    })
+                    .resource("/delete", |r| {
+                        r.middleware(Deprecated("/api/v1/scribbles"));
+                        r.method(Method::POST).with(handle_delete)
+                    })
+                    .resource("/tag", |r| {
+                        r.middleware(Deprecated("/api/v1/scribbles"));
+                        r.method(Method::POST).with(handle_tag)
+                    })
+                    .resource("/untag", |r| {
+                        r.middleware(Deprecated("/api/v1/scribbles"));
+                        r.method(Method::POST).with(handle_untag)
+                    })
+                    .resource("/tags/rename", |r| {
+                        r.middleware(Deprecated("/api/v1/tags"));
+                        r.method(Method::POST).with(handle_rename_tag)
+                    })
+                    .resource("/tags/merge", |r| {
+                        r.middleware(Deprecated("/api/v1/tags"));
+                        r.method(Method::POST).with(handle_merge_tags)
+                    })
+                    .resource("/tags/delete", |r| {
+                        r.middleware(Deprecated("/api/v1/tags"));
+                        r.method(Method::POST).with(handle_delete_tag)
+                    })
+                    .resource("/list", |r| {
+                        r.middleware(Deprecated("/api/v1/scribbles"));
+                        r.method(Method::GET).with(handle_list)
+                    })
+                    .resource("/search", |r| {
+                        r.middleware(Deprecated("/api/v1/search"));
+                        r.method(Method::GET).with(handle_search)
+                    })
+                    .resource("/login", |r| {
+                        r.middleware(Deprecated("/api/v1/login"));
+                        r.method(Method::POST).with(handle_login)
+                    })
                     .register()
             })
     }).bind((host, port))
@@ -81,25 +410,142 @@ pub fn start(host: &str, port: u16, pool: Pool<ConnectionManager<PgConnection>>)
     let _ = sys.run();
 }
 
+fn error_json(error_type: &str, message: &str) -> serde_json::Value {
+    json!({
+        "error": {
+            "type": error_type,
+            "message": message,
+        },
+    })
+}
+
+fn error_body(status: http::StatusCode, error_type: &str, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(error_json(error_type, message))
+}
+
+/// Maps `e` to a status code and the JSON error body shared by all routes.
+fn error_parts(e: &Error) -> (http::StatusCode, serde_json::Value) {
+    use http::StatusCode;
+
+    let (status, error_type) = match e {
+        Error::DatabaseError(diesel::result::Error::NotFound) => {
+            return (StatusCode::NOT_FOUND, error_json("NotFound", "not found"));
+        },
+        Error::ScribbleNotFound => (StatusCode::NOT_FOUND, "ScribbleNotFound"),
+        Error::RevisionNotFound => (StatusCode::NOT_FOUND, "RevisionNotFound"),
+        Error::TagNotFound => (StatusCode::NOT_FOUND, "TagNotFound"),
+        Error::NotTagged => (StatusCode::NOT_FOUND, "NotTagged"),
+        Error::TagExists => (StatusCode::CONFLICT, "TagExists"),
+        Error::TagInUse => (StatusCode::CONFLICT, "TagInUse"),
+        Error::TagBelowItself => (StatusCode::UNPROCESSABLE_ENTITY, "TagBelowItself"),
+        Error::InvalidTag(_) => (StatusCode::UNPROCESSABLE_ENTITY, "InvalidTag"),
+        Error::AliasExists => (StatusCode::CONFLICT, "AliasExists"),
+        Error::AliasNotFound => (StatusCode::NOT_FOUND, "AliasNotFound"),
+        Error::AlreadyTagged => (StatusCode::CONFLICT, "AlreadyTagged"),
+        Error::InvalidCursor => (StatusCode::UNPROCESSABLE_ENTITY, "InvalidCursor"),
+        Error::InvalidDuration(_) => (StatusCode::UNPROCESSABLE_ENTITY, "InvalidDuration"),
+        Error::InvalidTime(_) => (StatusCode::UNPROCESSABLE_ENTITY, "InvalidTime"),
+        Error::InvalidTimeZone(_) => (StatusCode::UNPROCESSABLE_ENTITY, "InvalidTimeZone"),
+        Error::InvalidSort(_) => (StatusCode::UNPROCESSABLE_ENTITY, "InvalidSort"),
+        Error::IdempotencyKeyReused => (StatusCode::UNPROCESSABLE_ENTITY, "IdempotencyKeyReused"),
+        Error::RequestInProgress => (StatusCode::CONFLICT, "RequestInProgress"),
+        Error::VersionConflict(current) => {
+            return (StatusCode::CONFLICT, json!({
+                "error": {
+                    "type": "VersionConflict",
+                    "message": e.to_string(),
+                },
+                "scribble": current,
+            }));
+        },
+        Error::InvalidQuery(e) => {
+            return (StatusCode::UNPROCESSABLE_ENTITY, json!({
+                "error": {
+                    "type": "InvalidQuery",
+                    "message": e.to_string(),
+                    "position": e.position,
+                },
+            }));
+        },
+        Error::DatabaseError(_) | Error::PoolError(_) => {
+            // Details of the database stay in the log
+            error!("{}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, error_json("InternalError", "internal server error"));
+        },
+    };
+    (status, error_json(error_type, &e.to_string()))
+}
+
+fn error_response(e: &Error) -> HttpResponse {
+    let (status, body) = error_parts(e);
+    HttpResponse::build(status).json(body)
+}
+
+/// Maps `e` to a response as the legacy routes made it: 200 with the type of
+/// the error alone for tag errors, 400 for invalid listing parameters, and an
+/// empty 500 for anything they did not tell apart.
+fn legacy_error_response(e: &Error) -> HttpResponse {
+    let (status, error_type) = match e {
+        Error::AlreadyTagged => (http::StatusCode::OK, "AlreadyTagged"),
+        Error::TagNotFound => (http::StatusCode::OK, "TagNotFound"),
+        Error::NotTagged => (http::StatusCode::OK, "NotTagged"),
+        Error::TagExists => (http::StatusCode::OK, "TagExists"),
+        Error::TagInUse => (http::StatusCode::OK, "TagInUse"),
+        Error::InvalidCursor => (http::StatusCode::BAD_REQUEST, "InvalidCursor"),
+        Error::InvalidTime(_) => (http::StatusCode::BAD_REQUEST, "InvalidTime"),
+        Error::InvalidTimeZone(_) => (http::StatusCode::BAD_REQUEST, "InvalidTimeZone"),
+        Error::InvalidSort(_) => (http::StatusCode::BAD_REQUEST, "InvalidSort"),
+        Error::InvalidQuery(_) => return HttpResponse::BadRequest().json(error_parts(e).1),
+        Error::DatabaseError(_) | Error::PoolError(_) => {
+            error!("{}", e);
+            return HttpResponse::InternalServerError().into();
+        },
+        _ => return HttpResponse::InternalServerError().into(),
+    };
+    HttpResponse::build(status).json(json!({
+        "error": {
+            "type": error_type,
+        },
+    }))
+}
+
 fn handle_root(_req: &HttpRequest<AppState>) -> Result<NamedFile> {
     Ok(NamedFile::open("static/index.html")?)
 }
 
+/// When a new scribble expires, given as a timestamp and/or as a TTL such as
+/// `7d`, the earlier of the two winning.
+fn expiry(expires_at: Option<DateTime<Utc>>, ttl: Option<&str>) -> crate::Result<Option<DateTime<Utc>>> {
+    let ttl_expiry = match ttl {
+        None => None,
+        Some(ttl) => Some(crate::duration::from_now(crate::duration::parse(ttl)?)),
+    };
+    Ok(expires_at.into_iter().chain(ttl_expiry).min())
+}
+
 #[derive(Debug, Deserialize)]
 struct AddRequest {
     text: String,
+    #[serde(default, deserialize_with = "crate::timestamp::deserialize_option")]
+    expires_at: Option<DateTime<Utc>>,
+    ttl: Option<String>,
 }
 
 fn handle_add((req, state): (Json<AddRequest>, State<AppState>)) -> FutureResponse<HttpResponse> {
+    let expires_at = match expiry(req.expires_at, req.ttl.as_deref()) {
+        Ok(expires_at) => expires_at,
+        Err(e) => return result(Ok(legacy_error_response(&e))).responder(),
+    };
     state
         .db
         .send(CreateScribble {
             text: req.text.to_owned(),
+            expires_at,
         })
         .from_err()
         .and_then(|res| match res {
             Ok(scribble) => Ok(HttpResponse::Ok().json(scribble)),
-            Err(_) => Ok(HttpResponse::InternalServerError().into()),
+            Err(e) => Ok(legacy_error_response(&e)),
         })
         .responder()
 }
@@ -108,6 +554,8 @@ fn handle_add((req, state): (Json<AddRequest>, State<AppState>)) -> FutureRespon
 struct UpdateRequest {
     scribble_id: i64,
     text: String,
+    /// The version the update was made to, if it must not overwrite others
+    version: Option<i32>,
 }
 
 fn handle_update((req, state): (Json<UpdateRequest>, State<AppState>)) -> FutureResponse<HttpResponse> {
@@ -116,11 +564,12 @@ fn handle_update((req, state): (Json<UpdateRequest>, State<AppState>)) -> Future
         .send(UpdateScribble {
             scribble_id: req.scribble_id,
             text: req.text.to_owned(),
+            expected_version: req.version,
         })
         .from_err()
         .and_then(|res| match res {
             Ok(scribble) => Ok(HttpResponse::Ok().json(scribble)),
-            Err(_) => Ok(HttpResponse::InternalServerError().into()),
+            Err(e) => Ok(legacy_error_response(&e)),
         })
         .responder()
 }
@@ -128,6 +577,7 @@ fn handle_update((req, state): (Json<UpdateRequest>, State<AppState>)) -> Future
 #[derive(Debug, Deserialize)]
 struct DeleteRequest {
     scribble_id: i64,
+    version: Option<i32>,
 }
 
 fn handle_delete((req, state): (Json<DeleteRequest>, State<AppState>)) -> FutureResponse<HttpResponse> {
@@ -135,96 +585,229 @@ fn handle_delete((req, state): (Json<DeleteRequest>, State<AppState>)) -> Future
         .db
         .send(DeleteScribble {
             scribble_id: req.scribble_id,
+            expected_version: req.version,
         })
         .from_err()
         .and_then(|res| match res {
             Ok(()) => Ok(HttpResponse::Ok().json(())),
-            Err(_) => Ok(HttpResponse::InternalServerError().into()),
+            Err(e) => Ok(legacy_error_response(&e)),
         })
         .responder()
 }
 
+/// A single value or a list of them, as in requests that tag either one
+/// scribble with one tag or several with several.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T: Clone> OneOrMany<T> {
+    fn is_one(&self) -> bool {
+        match self {
+            OneOrMany::One(_) => true,
+            OneOrMany::Many(_) => false,
+        }
+    }
+
+    fn to_vec(&self) -> Vec<T> {
+        match self {
+            OneOrMany::One(value) => vec![value.clone()],
+            OneOrMany::Many(values) => values.clone(),
+        }
+    }
+}
+
+/// Answers a request for a single pair with the tagging made, failing if it
+/// was already tagged, and any other with the result of every pair.
+fn tagging_response(status: http::StatusCode, single: bool, mut results: Vec<TaggingResult>, on_error: fn(&Error) -> HttpResponse) -> HttpResponse {
+    if !single {
+        return HttpResponse::build(status).json(results);
+    }
+    match results.pop().and_then(|result| result.tagging) {
+        Some(tagging) => HttpResponse::build(status).json(tagging),
+        None => on_error(&Error::AlreadyTagged),
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct TagRequest {
-    scribble_id: i64,
-    tag_text: String,
+    #[serde(alias = "scribble_ids")]
+    scribble_id: OneOrMany<i64>,
+    #[serde(alias = "tag_texts")]
+    tag_text: OneOrMany<String>,
 }
 
 fn handle_tag((req, state): (Json<TagRequest>, State<AppState>)) -> FutureResponse<HttpResponse> {
+    let single = req.scribble_id.is_one() && req.tag_text.is_one();
     state
         .db
         .send(TagScribble {
+            scribble_ids: req.scribble_id.to_vec(),
+            tag_texts: req.tag_text.to_vec(),
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(results) => Ok(tagging_response(http::StatusCode::OK, single, results, legacy_error_response)),
+            Err(e) => Ok(legacy_error_response(&e)),
+        })
+        .responder()
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    #[serde(default)]
+    mode: Mode,
+    operations: Vec<Operation>,
+}
+
+/// Answers with the result of every operation, in order. Should a batch be
+/// rolled back, the operation that failed sets the status code and those
+/// after it are reported as skipped.
+fn handle_batch((req, state): (Json<BatchRequest>, State<AppState>)) -> FutureResponse<HttpResponse> {
+    let BatchRequest { mode, operations } = req.into_inner();
+    let count = operations.len();
+    state
+        .db
+        .send(Batch {
+            operations,
+            mode,
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(results) => {
+                let committed = batch::committed(mode, &results);
+                let mut status = http::StatusCode::OK;
+                let mut reported: Vec<serde_json::Value> = results.iter()
+                    .map(|result| match result {
+                        Ok(outcome) if committed => json!({ "status": "ok", "result": outcome }),
+                        Ok(_) => json!({ "status": "rolled_back" }),
+                        Err(e) => {
+                            let (error_status, mut body) = error_parts(e);
+                            if !committed {
+                                status = error_status;
+                            }
+                            // Keeps whatever the error comes with, such as the current copy of a scribble
+                            body["status"] = json!("failed");
+                            body
+                        },
+                    })
+                    .collect();
+                reported.resize(count, json!({ "status": "skipped" }));
+                Ok(HttpResponse::build(status).json(json!({
+                    "committed": committed,
+                    "results": reported,
+                })))
+            },
+            Err(e) => Ok(error_response(&e)),
+        })
+        .responder()
+}
+
+#[derive(Debug, Deserialize)]
+struct UntagRequest {
+    scribble_id: i64,
+    tag_text: String,
+}
+
+fn handle_untag((req, state): (Json<UntagRequest>, State<AppState>)) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(UntagScribble {
             scribble_id: req.scribble_id,
             tag_text: req.tag_text.to_owned(),
         })
         .from_err()
         .and_then(|res| match res {
-            Ok(tagging) => Ok(HttpResponse::Ok().json(tagging)),
-            Err(crate::Error::AlreadyTagged) => Ok(HttpResponse::Ok().json(json!({
-                "error": {
-                    "type": "AlreadyTagged",
-                },
-            }))),
-            Err(_) => Ok(HttpResponse::InternalServerError().into()),
+            Ok(()) => Ok(HttpResponse::Ok().json(())),
+            Err(e) => Ok(legacy_error_response(&e)),
         })
         .responder()
 }
 
 #[derive(Debug, Deserialize)]
-struct ListRequest {
-    size: Option<usize>,
+struct RenameTagRequest {
+    from: String,
+    to: String,
 }
 
-fn handle_list((req, state): (Query<ListRequest>, State<AppState>)) -> FutureResponse<HttpResponse> {
+fn handle_rename_tag((req, state): (Json<RenameTagRequest>, State<AppState>)) -> FutureResponse<HttpResponse> {
     state
         .db
-        .send(List {
-            size: req.size,
+        .send(RenameTag {
+            from: req.from.to_owned(),
+            to: req.to.to_owned(),
         })
         .from_err()
         .and_then(|res| match res {
-            Ok(scribbles) => Ok(HttpResponse::Ok().json(scribbles)),
-            Err(_) => Ok(HttpResponse::InternalServerError().into()),
+            Ok(tag) => Ok(HttpResponse::Ok().json(tag)),
+            Err(e) => Ok(legacy_error_response(&e)),
         })
         .responder()
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: u64,
-    email: String,
-    username: String,
+#[derive(Debug, Deserialize)]
+struct MergeTagsRequest {
+    from: String,
+    into: String,
+}
+
+fn handle_merge_tags((req, state): (Json<MergeTagsRequest>, State<AppState>)) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(MergeTags {
+            from: req.from.to_owned(),
+            into: req.into.to_owned(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(tag) => Ok(HttpResponse::Ok().json(tag)),
+            Err(e) => Ok(legacy_error_response(&e)),
+        })
+        .responder()
 }
 
 #[derive(Debug, Deserialize)]
-struct LoginRequest {
-    email: String,
-    password: String,
+struct DeleteTagRequest {
+    tag_text: String,
+    #[serde(default)]
+    force: bool,
+}
+
+fn handle_delete_tag((req, state): (Json<DeleteTagRequest>, State<AppState>)) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(DeleteTag {
+            tag_text: req.tag_text.to_owned(),
+            force: req.force,
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(()) => Ok(HttpResponse::Ok().json(())),
+            Err(e) => Ok(legacy_error_response(&e)),
+        })
+        .responder()
 }
 
-fn handle_login((req, _state): (Json<LoginRequest>, State<AppState>)) -> FutureResponse<HttpResponse> {
-    use jwt::{encode, Header};
+fn handle_list(args: (Query<v1::ListRequest>, HttpRequest<AppState>, State<AppState>)) -> FutureResponse<HttpResponse> {
+    v1::list(args, legacy_error_response)
+}
 
-    dotenv().ok();
-    let username = env::var("USER_NAME").expect("USER_NAME must be set.");
-    let email = env::var("USER_EMAIL").expect("USER_EMAIL must be set.");
-    let encoded_password = env::var("USER_PASSWORD").expect("USER_PASSWORD must be set.");
-    let server_secret = env::var("SERVER_SECRET").expect("SERVER_SECRET must be set.");
+fn handle_search(args: (Query<v1::SearchRequest>, State<AppState>)) -> FutureResponse<HttpResponse> {
+    v1::search_scribbles(args, legacy_error_response)
+}
 
-    if req.email == email && argon2::verify_encoded(&encoded_password, req.password.as_ref()).unwrap() {
-        let my_claims = Claims {
-            sub: 0,
-            email: email,
-            username: username,
-        };
-        let token = encode(&Header::default(),
-                           &my_claims,
-                           server_secret.as_ref()).unwrap();
-        result(Ok(HttpResponse::Ok().json(token)))
-            .responder()
-    }
-    else {
-        result(Ok(HttpResponse::Ok().json(())))
-            .responder()
-    }
+/// Answers a failed login with `null`, as it always did.
+fn handle_login((req, _state): (Json<v1::LoginRequest>, State<AppState>)) -> FutureResponse<HttpResponse> {
+    result(Ok(HttpResponse::Ok().json(v1::token(&req))))
+        .responder()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: u64,
+    email: String,
+    username: String,
 }
@@ -1,21 +1,36 @@
+use std::sync::Arc;
+
 use ::actix::prelude::*;
-use diesel;
-use diesel::prelude::*;
-use diesel::r2d2::{ConnectionManager, Pool};
+use chrono::{DateTime, Utc};
 
-use crate::{models, Result};
+use crate::{models, Result, Seek, Page, SearchPage};
+use crate::batch::{Mode, Operation, Outcome};
+use crate::period::Period;
+use crate::query::Query;
+use crate::sort::Sort;
+use crate::tag_filter::TagFilter;
+use crate::store::ScribbleStore;
 
-use self::models::{Scribble, Tag, Tagging};
+use self::models::{IdempotencyKey, Scribble, ScribbleRevision, Tag, TagAlias, TaggingResult, TagUsage, TaggedScribble};
 
 
-pub struct DbExecutor(pub Pool<ConnectionManager<PgConnection>>);
+pub struct DbExecutor(pub Arc<dyn ScribbleStore>);
 
 impl Actor for DbExecutor {
     type Context = SyncContext<Self>;
 }
 
+pub struct GetScribble {
+    pub scribble_id: i64,
+}
+
+impl Message for GetScribble {
+    type Result = Result<Scribble>;
+}
+
 pub struct CreateScribble {
     pub text: String,
+    pub expires_at: Option<DateTime<Utc>>,
 }
 
 impl Message for CreateScribble {
@@ -25,6 +40,7 @@ impl Message for CreateScribble {
 pub struct UpdateScribble {
     pub scribble_id: i64,
     pub text: String,
+    pub expected_version: Option<i32>,
 }
 
 impl Message for UpdateScribble {
@@ -33,12 +49,116 @@ impl Message for UpdateScribble {
 
 pub struct DeleteScribble {
     pub scribble_id: i64,
+    pub expected_version: Option<i32>,
 }
 
 impl Message for DeleteScribble {
     type Result = Result<()>;
 }
 
+pub struct UndeleteScribble {
+    pub scribble_id: i64,
+}
+
+impl Message for UndeleteScribble {
+    type Result = Result<Scribble>;
+}
+
+pub struct Trash;
+
+impl Message for Trash {
+    type Result = Result<Vec<Scribble>>;
+}
+
+pub struct EmptyTrash {
+    pub deleted_before: Option<DateTime<Utc>>,
+}
+
+impl Message for EmptyTrash {
+    type Result = Result<usize>;
+}
+
+pub struct PurgeExpired {
+    pub now: DateTime<Utc>,
+}
+
+impl Message for PurgeExpired {
+    type Result = Result<usize>;
+}
+
+pub struct ClaimIdempotencyKey {
+    pub key: String,
+    pub fingerprint: String,
+    pub expired_before: DateTime<Utc>,
+}
+
+impl Message for ClaimIdempotencyKey {
+    type Result = Result<Option<IdempotencyKey>>;
+}
+
+pub struct SaveIdempotentResponse {
+    pub key: String,
+    pub status: i32,
+    pub body: String,
+}
+
+impl Message for SaveIdempotentResponse {
+    type Result = Result<()>;
+}
+
+pub struct ReleaseIdempotencyKey {
+    pub key: String,
+}
+
+impl Message for ReleaseIdempotencyKey {
+    type Result = Result<()>;
+}
+
+pub struct PurgeIdempotencyKeys {
+    pub created_before: DateTime<Utc>,
+}
+
+impl Message for PurgeIdempotencyKeys {
+    type Result = Result<usize>;
+}
+
+/// Applies a list of operations in a single transaction.
+pub struct Batch {
+    pub operations: Vec<Operation>,
+    pub mode: Mode,
+}
+
+impl Message for Batch {
+    type Result = Result<Vec<Result<Outcome>>>;
+}
+
+pub struct Revisions {
+    pub scribble_id: i64,
+}
+
+impl Message for Revisions {
+    type Result = Result<Vec<ScribbleRevision>>;
+}
+
+pub struct DiffRevisions {
+    pub scribble_id: i64,
+    pub from: Option<i32>,
+    pub to: Option<i32>,
+}
+
+impl Message for DiffRevisions {
+    type Result = Result<String>;
+}
+
+pub struct RestoreRevision {
+    pub scribble_id: i64,
+    pub revision: i32,
+}
+
+impl Message for RestoreRevision {
+    type Result = Result<Scribble>;
+}
+
 pub struct CreateTag {
     pub text: String,
 }
@@ -48,13 +168,82 @@ impl Message for CreateTag {
 }
 
 
+/// Tags every scribble with every tag.
 pub struct TagScribble {
+    pub scribble_ids: Vec<i64>,
+    pub tag_texts: Vec<String>,
+}
+
+impl Message for TagScribble {
+    type Result = Result<Vec<TaggingResult>>;
+}
+
+pub struct UntagScribble {
     pub scribble_id: i64,
     pub tag_text: String,
 }
 
-impl Message for TagScribble {
-    type Result = Result<Tagging>;
+impl Message for UntagScribble {
+    type Result = Result<()>;
+}
+
+pub struct RenameTag {
+    pub from: String,
+    pub to: String,
+}
+
+impl Message for RenameTag {
+    type Result = Result<Option<Tag>>;
+}
+
+pub struct MergeTags {
+    pub from: String,
+    pub into: String,
+}
+
+impl Message for MergeTags {
+    type Result = Result<Tag>;
+}
+
+pub struct DeleteTag {
+    pub tag_text: String,
+    pub force: bool,
+}
+
+impl Message for DeleteTag {
+    type Result = Result<()>;
+}
+
+pub struct SetTagTtl {
+    pub tag_text: String,
+    pub ttl: Option<i64>,
+}
+
+impl Message for SetTagTtl {
+    type Result = Result<Tag>;
+}
+
+pub struct TagAliases;
+
+impl Message for TagAliases {
+    type Result = Result<Vec<TagAlias>>;
+}
+
+pub struct AliasTag {
+    pub alias: String,
+    pub tag_text: String,
+}
+
+impl Message for AliasTag {
+    type Result = Result<TagAlias>;
+}
+
+pub struct UnaliasTag {
+    pub alias: String,
+}
+
+impl Message for UnaliasTag {
+    type Result = Result<()>;
 }
 
 pub struct Tags;
@@ -63,12 +252,46 @@ impl Message for Tags {
     type Result = Result<Vec<Tag>>;
 }
 
+pub struct TagUsages;
+
+impl Message for TagUsages {
+    type Result = Result<Vec<TagUsage>>;
+}
+
 pub struct List {
     pub size: Option<usize>,
+    pub cursor: Option<Seek>,
+    pub query: Option<Query>,
+    pub period: Period,
+    pub tags: TagFilter,
+    pub sort: Sort,
 }
 
 impl Message for List {
-    type Result = Result<Vec<Scribble>>;
+    type Result = Result<Page>;
+}
+
+pub struct ListWithTags {
+    pub size: Option<usize>,
+    pub cursor: Option<Seek>,
+    pub query: Option<Query>,
+    pub period: Period,
+    pub tags: TagFilter,
+    pub sort: Sort,
+}
+
+impl Message for ListWithTags {
+    type Result = Result<Page<TaggedScribble>>;
+}
+
+pub struct Search {
+    pub query: String,
+    pub size: Option<usize>,
+    pub offset: usize,
+}
+
+impl Message for Search {
+    type Result = Result<SearchPage>;
 }
 
 pub struct TagsOf {
@@ -79,12 +302,19 @@ impl Message for TagsOf {
     type Result = Result<Vec<Tag>>;
 }
 
+impl Handler<GetScribble> for DbExecutor {
+    type Result = Result<Scribble>;
+
+    fn handle(&mut self, msg: GetScribble, _: &mut Self::Context) -> Self::Result {
+        self.0.get_scribble(msg.scribble_id)
+    }
+}
+
 impl Handler<CreateScribble> for DbExecutor {
     type Result = Result<Scribble>;
 
     fn handle(&mut self, msg: CreateScribble, _: &mut Self::Context) -> Self::Result {
-        let conn: &PgConnection = &self.0.get().unwrap();
-        crate::create_scribble(conn, msg.text.as_str())
+        self.0.create_scribble(msg.text.as_str(), msg.expires_at)
     }
 }
 
@@ -92,8 +322,7 @@ impl Handler<UpdateScribble> for DbExecutor {
     type Result = Result<Scribble>;
 
     fn handle(&mut self, msg: UpdateScribble, _: &mut Self::Context) -> Self::Result {
-        let conn: &PgConnection = &self.0.get().unwrap();
-        crate::update_scribble(conn, msg.scribble_id, msg.text.as_str())
+        self.0.update_scribble(msg.scribble_id, msg.text.as_str(), msg.expected_version)
     }
 }
 
@@ -101,8 +330,103 @@ impl Handler<DeleteScribble> for DbExecutor {
     type Result = Result<()>;
 
     fn handle(&mut self, msg: DeleteScribble, _: &mut Self::Context) -> Self::Result {
-        let conn: &PgConnection = &self.0.get().unwrap();
-        crate::delete_scribble(conn, msg.scribble_id)
+        self.0.delete_scribble(msg.scribble_id, msg.expected_version)
+    }
+}
+
+impl Handler<UndeleteScribble> for DbExecutor {
+    type Result = Result<Scribble>;
+
+    fn handle(&mut self, msg: UndeleteScribble, _: &mut Self::Context) -> Self::Result {
+        self.0.undelete_scribble(msg.scribble_id)
+    }
+}
+
+impl Handler<Trash> for DbExecutor {
+    type Result = Result<Vec<Scribble>>;
+
+    fn handle(&mut self, _: Trash, _: &mut Self::Context) -> Self::Result {
+        self.0.trash()
+    }
+}
+
+impl Handler<EmptyTrash> for DbExecutor {
+    type Result = Result<usize>;
+
+    fn handle(&mut self, msg: EmptyTrash, _: &mut Self::Context) -> Self::Result {
+        self.0.empty_trash(msg.deleted_before)
+    }
+}
+
+impl Handler<PurgeExpired> for DbExecutor {
+    type Result = Result<usize>;
+
+    fn handle(&mut self, msg: PurgeExpired, _: &mut Self::Context) -> Self::Result {
+        self.0.purge_expired(msg.now)
+    }
+}
+
+impl Handler<ClaimIdempotencyKey> for DbExecutor {
+    type Result = Result<Option<IdempotencyKey>>;
+
+    fn handle(&mut self, msg: ClaimIdempotencyKey, _: &mut Self::Context) -> Self::Result {
+        self.0.claim_idempotency_key(&msg.key, &msg.fingerprint, msg.expired_before)
+    }
+}
+
+impl Handler<SaveIdempotentResponse> for DbExecutor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: SaveIdempotentResponse, _: &mut Self::Context) -> Self::Result {
+        self.0.save_idempotent_response(&msg.key, msg.status, &msg.body)
+    }
+}
+
+impl Handler<ReleaseIdempotencyKey> for DbExecutor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: ReleaseIdempotencyKey, _: &mut Self::Context) -> Self::Result {
+        self.0.release_idempotency_key(&msg.key)
+    }
+}
+
+impl Handler<PurgeIdempotencyKeys> for DbExecutor {
+    type Result = Result<usize>;
+
+    fn handle(&mut self, msg: PurgeIdempotencyKeys, _: &mut Self::Context) -> Self::Result {
+        self.0.purge_idempotency_keys(msg.created_before)
+    }
+}
+
+impl Handler<Batch> for DbExecutor {
+    type Result = Result<Vec<Result<Outcome>>>;
+
+    fn handle(&mut self, msg: Batch, _: &mut Self::Context) -> Self::Result {
+        self.0.batch(&msg.operations, msg.mode)
+    }
+}
+
+impl Handler<Revisions> for DbExecutor {
+    type Result = Result<Vec<ScribbleRevision>>;
+
+    fn handle(&mut self, msg: Revisions, _: &mut Self::Context) -> Self::Result {
+        self.0.revisions(msg.scribble_id)
+    }
+}
+
+impl Handler<DiffRevisions> for DbExecutor {
+    type Result = Result<String>;
+
+    fn handle(&mut self, msg: DiffRevisions, _: &mut Self::Context) -> Self::Result {
+        self.0.diff_revisions(msg.scribble_id, msg.from, msg.to)
+    }
+}
+
+impl Handler<RestoreRevision> for DbExecutor {
+    type Result = Result<Scribble>;
+
+    fn handle(&mut self, msg: RestoreRevision, _: &mut Self::Context) -> Self::Result {
+        self.0.restore_revision(msg.scribble_id, msg.revision)
     }
 }
 
@@ -110,17 +434,79 @@ impl Handler<CreateTag> for DbExecutor {
     type Result = Result<Tag>;
 
     fn handle(&mut self, msg: CreateTag, _: &mut Self::Context) -> Self::Result {
-        let conn: &PgConnection = &self.0.get().unwrap();
-        crate::create_tag(conn, msg.text.as_str())
+        self.0.create_tag(msg.text.as_str())
     }
 }
 
 impl Handler<TagScribble> for DbExecutor {
-    type Result = Result<Tagging>;
+    type Result = Result<Vec<TaggingResult>>;
 
     fn handle(&mut self, msg: TagScribble, _: &mut Self::Context) -> Self::Result {
-        let conn: &PgConnection = &self.0.get().unwrap();
-        crate::tag_scribble(conn, msg.scribble_id, msg.tag_text.as_str())
+        self.0.tag_scribbles(&msg.scribble_ids, &msg.tag_texts)
+    }
+}
+
+impl Handler<UntagScribble> for DbExecutor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: UntagScribble, _: &mut Self::Context) -> Self::Result {
+        self.0.untag_scribble(msg.scribble_id, msg.tag_text.as_str())
+    }
+}
+
+impl Handler<RenameTag> for DbExecutor {
+    type Result = Result<Option<Tag>>;
+
+    fn handle(&mut self, msg: RenameTag, _: &mut Self::Context) -> Self::Result {
+        self.0.rename_tag(msg.from.as_str(), msg.to.as_str())
+    }
+}
+
+impl Handler<MergeTags> for DbExecutor {
+    type Result = Result<Tag>;
+
+    fn handle(&mut self, msg: MergeTags, _: &mut Self::Context) -> Self::Result {
+        self.0.merge_tags(msg.from.as_str(), msg.into.as_str())
+    }
+}
+
+impl Handler<DeleteTag> for DbExecutor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: DeleteTag, _: &mut Self::Context) -> Self::Result {
+        self.0.delete_tag(msg.tag_text.as_str(), msg.force)
+    }
+}
+
+impl Handler<SetTagTtl> for DbExecutor {
+    type Result = Result<Tag>;
+
+    fn handle(&mut self, msg: SetTagTtl, _: &mut Self::Context) -> Self::Result {
+        self.0.set_tag_ttl(msg.tag_text.as_str(), msg.ttl)
+    }
+}
+
+impl Handler<TagAliases> for DbExecutor {
+    type Result = Result<Vec<TagAlias>>;
+
+    fn handle(&mut self, _: TagAliases, _: &mut Self::Context) -> Self::Result {
+        self.0.tag_aliases()
+    }
+}
+
+impl Handler<AliasTag> for DbExecutor {
+    type Result = Result<TagAlias>;
+
+    fn handle(&mut self, msg: AliasTag, _: &mut Self::Context) -> Self::Result {
+        self.0.alias_tag(msg.alias.as_str(), msg.tag_text.as_str())
+    }
+}
+
+impl Handler<UnaliasTag> for DbExecutor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: UnaliasTag, _: &mut Self::Context) -> Self::Result {
+        self.0.unalias_tag(msg.alias.as_str())
     }
 }
 
@@ -128,17 +514,39 @@ impl Handler<Tags> for DbExecutor {
     type Result = Result<Vec<Tag>>;
 
     fn handle(&mut self, msg: Tags, _: &mut Self::Context) -> Self::Result {
-        let conn: &PgConnection = &self.0.get().unwrap();
-        crate::tags(conn)
+        self.0.tags()
+    }
+}
+
+impl Handler<TagUsages> for DbExecutor {
+    type Result = Result<Vec<TagUsage>>;
+
+    fn handle(&mut self, _: TagUsages, _: &mut Self::Context) -> Self::Result {
+        self.0.tag_usage()
     }
 }
 
 impl Handler<List> for DbExecutor {
-    type Result = Result<Vec<Scribble>>;
+    type Result = Result<Page>;
 
     fn handle(&mut self, msg: List, _: &mut Self::Context) -> Self::Result {
-        let conn: &PgConnection = &self.0.get().unwrap();
-        crate::list(conn, msg.size)
+        self.0.list(msg.size, msg.cursor, msg.query.as_ref(), &msg.period, &msg.tags, msg.sort)
+    }
+}
+
+impl Handler<ListWithTags> for DbExecutor {
+    type Result = Result<Page<TaggedScribble>>;
+
+    fn handle(&mut self, msg: ListWithTags, _: &mut Self::Context) -> Self::Result {
+        self.0.list_with_tags(msg.size, msg.cursor, msg.query.as_ref(), &msg.period, &msg.tags, msg.sort)
+    }
+}
+
+impl Handler<Search> for DbExecutor {
+    type Result = Result<SearchPage>;
+
+    fn handle(&mut self, msg: Search, _: &mut Self::Context) -> Self::Result {
+        self.0.search(msg.query.as_str(), msg.size, msg.offset)
     }
 }
 
@@ -146,7 +554,6 @@ impl Handler<TagsOf> for DbExecutor {
     type Result = Result<Vec<Tag>>;
 
     fn handle(&mut self, msg: TagsOf, _: &mut Self::Context) -> Self::Result {
-        let conn: &PgConnection = &self.0.get().unwrap();
-        crate::tags_of(conn, msg.scribble_id)
+        self.0.tags_of(msg.scribble_id)
     }
 }
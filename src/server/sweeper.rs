@@ -0,0 +1,73 @@
+//! Housekeeping that runs periodically alongside the server.
+
+use std::time::Duration;
+
+use ::actix::prelude::*;
+use chrono::prelude::*;
+use futures::Future;
+use log::{error, info};
+
+use super::db::{DbExecutor, EmptyTrash, PurgeExpired, PurgeIdempotencyKeys};
+
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+pub struct Sweeper {
+    pub db: Addr<DbExecutor>,
+    /// How long scribbles stay in the trash, or forever if `None`
+    pub trash_retention: Option<chrono::Duration>,
+    /// How long responses are kept for requests with an idempotency key
+    pub idempotency_window: chrono::Duration,
+}
+
+impl Actor for Sweeper {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.sweep();
+        ctx.run_interval(SWEEP_INTERVAL, |sweeper, _| sweeper.sweep());
+    }
+}
+
+impl Sweeper {
+    fn sweep(&self) {
+        let purge = self.db
+            .send(PurgeExpired {
+                now: Utc::now(),
+            })
+            .map(|res| match res {
+                Ok(0) => {},
+                Ok(purged) => info!("Purged {} expired scribbles", purged),
+                Err(e) => error!("Failed to purge expired scribbles: {}", e),
+            })
+            .map_err(|e| error!("Failed to purge expired scribbles: {}", e));
+        Arbiter::spawn(purge);
+
+        let purge = self.db
+            .send(PurgeIdempotencyKeys {
+                created_before: Utc::now() - self.idempotency_window,
+            })
+            .map(|res| match res {
+                Ok(0) => {},
+                Ok(purged) => info!("Purged {} idempotency keys", purged),
+                Err(e) => error!("Failed to purge idempotency keys: {}", e),
+            })
+            .map_err(|e| error!("Failed to purge idempotency keys: {}", e));
+        Arbiter::spawn(purge);
+
+        if let Some(retention) = self.trash_retention {
+            let deleted_before = Utc::now() - retention;
+            let purge = self.db
+                .send(EmptyTrash {
+                    deleted_before: Some(deleted_before),
+                })
+                .map(|res| match res {
+                    Ok(0) => {},
+                    Ok(purged) => info!("Purged {} scribbles from the trash", purged),
+                    Err(e) => error!("Failed to purge the trash: {}", e),
+                })
+                .map_err(|e| error!("Failed to purge the trash: {}", e));
+            Arbiter::spawn(purge);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Orders scribbles can be listed in: `created`, `updated`, `length` or
+//! `tags`, ascending or, with a leading `-`, descending. The default is
+//! `-created`, newest first.
+
+use std::fmt;
+use std::str::FromStr;
+
+use diesel::backend::Backend;
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::serialize::ToSql;
+use diesel::sql_types::{BigInt, Bool, HasSqlType};
+use serde::{Deserialize, Deserializer};
+
+use crate::schema::scribbles;
+use crate::timestamp::{SqlTimestamp, Timestamptz};
+use crate::{Error, Result, Seek};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Created,
+    /// The time of the last update, or of creation for scribbles never
+    /// updated
+    Updated,
+    /// The number of characters of the text
+    Length,
+    /// The number of tags
+    Tags,
+}
+
+impl Key {
+    const ALL: [Key; 4] = [Key::Created, Key::Updated, Key::Length, Key::Tags];
+
+    fn name(self) -> &'static str {
+        match self {
+            Key::Created => "created",
+            Key::Updated => "updated",
+            Key::Length => "length",
+            Key::Tags => "tags",
+        }
+    }
+
+    /// Whether the key is a time, kept in cursors as microseconds since the
+    /// epoch.
+    pub(crate) fn is_time(self) -> bool {
+        match self {
+            Key::Created | Key::Updated => true,
+            Key::Length | Key::Tags => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sort {
+    pub key:        Key,
+    pub descending: bool,
+}
+
+impl Default for Sort {
+    fn default() -> Sort {
+        Sort { key: Key::Created, descending: true }
+    }
+}
+
+impl fmt::Display for Sort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", if self.descending { "-" } else { "" }, self.key.name())
+    }
+}
+
+impl FromStr for Sort {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Sort> {
+        let (descending, name) = match s.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, s),
+        };
+        match Key::ALL.iter().find(|key| key.name() == name) {
+            Some(&key) => Ok(Sort { key, descending }),
+            None => Err(Error::InvalidSort(s.to_owned())),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Sort {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Sort, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Sort {
+    /// A byte standing for the sort in cursors.
+    pub(crate) fn code(self) -> u8 {
+        let key = Key::ALL.iter().position(|&key| key == self.key).unwrap() as u8;
+        key << 1 | self.descending as u8
+    }
+
+    pub(crate) fn from_code(code: u8) -> Option<Sort> {
+        Key::ALL.get(usize::from(code >> 1)).map(|&key| Sort { key, descending: code & 1 == 1 })
+    }
+}
+
+/// Counts the taggings of a scribble, in SQL that both backends understand.
+pub(crate) const TAG_COUNT_SQL: &str = "(SELECT COUNT(*) FROM taggings WHERE taggings.scribble_id = scribbles.id)";
+
+/// A sort key as written in SQL for one backend.
+pub struct KeySql {
+    /// The expression scribbles are ordered by
+    pub order: &'static str,
+    /// The value of the same as a `BIGINT`, as kept in cursors
+    pub value: &'static str,
+}
+
+impl Sort {
+    /// Orders `query` by the key written as `key_sql`, narrowing it down to
+    /// the scribbles past the cursor of `seek`, if any. Rows come in seek
+    /// order, that is backwards for `Seek::After`.
+    ///
+    /// Fails with `InvalidCursor` if the cursor was made for another order.
+    pub(crate) fn apply<'a, DB>(self, key_sql: &KeySql, seek: Option<Seek>, mut query: scribbles::BoxedQuery<'a, DB>)
+        -> Result<scribbles::BoxedQuery<'a, DB>>
+        where DB: Backend + HasSqlType<Timestamptz> + HasSqlType<BigInt> + 'a,
+              SqlTimestamp: ToSql<Timestamptz, DB>,
+              i64: ToSql<BigInt, DB>,
+    {
+        let backwards = matches!(seek, Some(Seek::After(_)));
+        let (operator, direction) = if self.descending != backwards { ("<", "DESC") } else { (">", "ASC") };
+
+        if let Some(cursor) = seek.map(Seek::cursor) {
+            if cursor.sort() != self {
+                return Err(Error::InvalidCursor);
+            }
+            let comparison = sql::<Bool>(&format!("({}, scribbles.id) {} (", key_sql.order, operator));
+            query = match cursor.time() {
+                Some(time) => query.filter(comparison.bind::<Timestamptz, _>(SqlTimestamp(time)).sql(", ").bind::<BigInt, _>(cursor.id).sql(")")),
+                None => query.filter(comparison.bind::<BigInt, _>(cursor.value).sql(", ").bind::<BigInt, _>(cursor.id).sql(")")),
+            };
+        }
+        Ok(query.order(sql::<Bool>(&format!("{} {}, scribbles.id {}", key_sql.order, direction, direction))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_displays_sorts() {
+        assert_eq!("-created".parse::<Sort>().unwrap(), Sort::default());
+        assert_eq!("length".parse::<Sort>().unwrap(), Sort { key: Key::Length, descending: false });
+        assert!(matches!("-size".parse::<Sort>(), Err(Error::InvalidSort(s)) if s == "-size"));
+        for &key in &Key::ALL {
+            for &descending in &[false, true] {
+                let sort = Sort { key, descending };
+                assert_eq!(sort.to_string().parse::<Sort>().unwrap(), sort);
+            }
+        }
+    }
+
+    #[test]
+    fn codes_round_trip() {
+        for &key in &Key::ALL {
+            for &descending in &[false, true] {
+                let sort = Sort { key, descending };
+                assert_eq!(Sort::from_code(sort.code()), Some(sort));
+            }
+        }
+        assert_eq!(Sort::from_code(Key::ALL.len() as u8 * 2), None);
+    }
+}
@@ -0,0 +1,229 @@
+//! Storage backends for scribbles.
+//!
+//! The backend is chosen by the scheme of the database URL: `postgres://`
+//! (or `postgresql://`) for PostgreSQL and `sqlite://<path>` for a SQLite
+//! database file, which is created and migrated on first use.
+//! [`MemoryStore`] keeps everything in memory instead and has no URL.
+
+pub mod memory;
+pub mod postgres;
+pub mod sqlite;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+use crate::batch::{Mode, Operation, Outcome};
+use crate::doctor::Finding;
+use crate::models::{IdempotencyKey, Scribble, ScribbleRevision, Tag, TagAlias, Tagging, TaggingResult, TagUsage, TaggedScribble};
+use crate::period::Period;
+use crate::query::Query;
+use crate::sort::Sort;
+use crate::tag_filter::TagFilter;
+use crate::tag_rules::{FoldPlan, TagRules};
+use crate::{diff, Error, Result, Seek, Page, SearchPage};
+
+pub use self::memory::MemoryStore;
+pub use self::postgres::PgStore;
+pub use self::sqlite::SqliteStore;
+
+
+/// Tag texts given to methods, as well as those of list filters, are
+/// normalized by the `TagRules` of the store and resolved to the tag they are
+/// an alias of if any. `fold_tags` deals with tags from before the rules.
+pub trait ScribbleStore: Send + Sync {
+    /// Returns a scribble, failing with `ScribbleNotFound` if it is in the
+    /// trash or has expired.
+    fn get_scribble(&self, scribble_id: i64) -> Result<Scribble>;
+    /// Creates a scribble, which expires at `expires_at` if given.
+    fn create_scribble(&self, text: &str, expires_at: Option<DateTime<Utc>>) -> Result<Scribble>;
+    /// Replaces the text of a scribble, failing with `VersionConflict` unless
+    /// it is at `expected_version` if given.
+    fn update_scribble(&self, scribble_id: i64, text: &str, expected_version: Option<i32>) -> Result<Scribble>;
+    /// Moves a scribble to the trash, failing with `VersionConflict` unless it
+    /// is at `expected_version` if given.
+    fn delete_scribble(&self, scribble_id: i64, expected_version: Option<i32>) -> Result<()>;
+    fn undelete_scribble(&self, scribble_id: i64) -> Result<Scribble>;
+    fn trash(&self) -> Result<Vec<Scribble>>;
+    /// Purges the scribbles trashed before `deleted_before`, or all of them,
+    /// and returns how many there were.
+    fn empty_trash(&self, deleted_before: Option<DateTime<Utc>>) -> Result<usize>;
+    /// Purges the scribbles that expired by `now` and returns how many there
+    /// were.
+    fn purge_expired(&self, now: DateTime<Utc>) -> Result<usize>;
+    /// Claims `key` for the request described by `fingerprint`, forgetting
+    /// it first if it was created before `expired_before`. Returns the key
+    /// with the response to replay instead if the request was made already.
+    fn claim_idempotency_key(&self, key: &str, fingerprint: &str, expired_before: DateTime<Utc>) -> Result<Option<IdempotencyKey>>;
+    fn save_idempotent_response(&self, key: &str, status: i32, body: &str) -> Result<()>;
+    /// Gives up a claimed key, so that the request can be retried.
+    fn release_idempotency_key(&self, key: &str) -> Result<()>;
+    /// Forgets the keys created before `created_before` and returns how many
+    /// there were.
+    fn purge_idempotency_keys(&self, created_before: DateTime<Utc>) -> Result<usize>;
+    fn revisions(&self, scribble_id: i64) -> Result<Vec<ScribbleRevision>>;
+    fn restore_revision(&self, scribble_id: i64, revision: i32) -> Result<Scribble>;
+    /// Creates a tag, failing with `TagExists` if its text is taken by a tag
+    /// or an alias.
+    fn create_tag(&self, text: &str) -> Result<Tag>;
+    /// Tags a scribble with all of `tag_texts` or, if any of them fails, with
+    /// none.
+    fn tag_scribble(&self, scribble_id: i64, tag_texts: &[String]) -> Result<Vec<Tagging>>;
+    /// Tags each of `scribble_ids` with each of `tag_texts` in one go,
+    /// reporting the pairs already tagged rather than failing on them.
+    fn tag_scribbles(&self, scribble_ids: &[i64], tag_texts: &[String]) -> Result<Vec<TaggingResult>>;
+    fn untag_scribble(&self, scribble_id: i64, tag_text: &str) -> Result<()>;
+    /// Renames a tag along with the tags below it in the hierarchy, failing
+    /// as a whole if any of them clashes with an existing tag. Returns `None`
+    /// if there are only tags below `from` but no tag `from` itself.
+    fn rename_tag(&self, from: &str, to: &str) -> Result<Option<Tag>>;
+    /// Moves the scribbles and aliases of a tag over to another one and
+    /// deletes it, or renames it if `into` does not exist.
+    fn merge_tags(&self, from: &str, into: &str) -> Result<Tag>;
+    fn delete_tag(&self, tag_text: &str, force: bool) -> Result<()>;
+    /// Sets the TTL in seconds given to scribbles when they are tagged with
+    /// `tag_text`, or clears it with `None`.
+    fn set_tag_ttl(&self, tag_text: &str, ttl: Option<i64>) -> Result<Tag>;
+    /// Merges tags that are the same once normalized by `rules` as planned
+    /// by `tag_rules::plan_folds`, all of them or none, and returns the plan.
+    fn fold_tags(&self, rules: &TagRules) -> Result<FoldPlan>;
+    fn tag_aliases(&self) -> Result<Vec<TagAlias>>;
+    /// Makes `alias` stand for a tag, failing with `TagExists` if it is the
+    /// text of a tag and with `AliasExists` if it stands for one already.
+    fn alias_tag(&self, alias: &str, tag_text: &str) -> Result<TagAlias>;
+    fn unalias_tag(&self, alias: &str) -> Result<()>;
+    /// Applies `operations` in order as a single transaction, returning the
+    /// result of each operation attempted.
+    fn batch(&self, operations: &[Operation], mode: Mode) -> Result<Vec<Result<Outcome>>>;
+    /// Looks for integrity problems, fixing them all at once if `repair` is
+    /// given.
+    fn check_integrity(&self, repair: bool) -> Result<Vec<Finding>>;
+    fn tags(&self) -> Result<Vec<Tag>>;
+    fn tag_usage(&self) -> Result<Vec<TagUsage>>;
+    /// Lists the scribbles matching `filter` and `tags` within `period` in the
+    /// order of `sort`, which has to be that of the cursor if seeking.
+    fn list(&self, size: Option<usize>, seek: Option<Seek>, filter: Option<&Query>, period: &Period, tags: &TagFilter, sort: Sort) -> Result<Page>;
+    fn tags_of(&self, scribble_id: i64) -> Result<Vec<Tag>>;
+    fn tags_of_many(&self, scribble_ids: &[i64]) -> Result<HashMap<i64, Vec<Tag>>>;
+    fn search(&self, query: &str, size: Option<usize>, offset: usize) -> Result<SearchPage>;
+
+    /// Lists scribbles along with their tags, which are fetched for the whole
+    /// page at once.
+    fn list_with_tags(&self, size: Option<usize>, seek: Option<Seek>, filter: Option<&Query>, period: &Period, tags: &TagFilter, sort: Sort) -> Result<Page<TaggedScribble>> {
+        let page = self.list(size, seek, filter, period, tags, sort)?;
+        let scribble_ids: Vec<i64> = page.scribbles.iter().map(|scribble| scribble.id).collect();
+        let mut tags_of = self.tags_of_many(&scribble_ids)?;
+        Ok(page.map(|scribble| TaggedScribble {
+            tags: tags_of.remove(&scribble.id).unwrap_or_default(),
+            scribble,
+        }))
+    }
+
+    /// Returns the unified diff between two revisions of a scribble, which
+    /// default to the latest revision and the one before it.
+    fn diff_revisions(&self, scribble_id: i64, from: Option<i32>, to: Option<i32>) -> Result<String> {
+        let revisions = self.revisions(scribble_id)?;
+        let to = to.unwrap_or_else(|| revisions.last().map_or(0, |latest| latest.revision));
+        let from = from.unwrap_or(to - 1);
+        let find = |revision| revisions.iter()
+            .find(|found| found.revision == revision)
+            .ok_or(Error::RevisionNotFound);
+        let (old, new) = (find(from)?, find(to)?);
+        Ok(diff::unified(&old.text, &new.text, &format!("revision {}", from), &format!("revision {}", to)))
+    }
+}
+
+pub fn open(database_url: &str, rules: TagRules) -> Arc<dyn ScribbleStore> {
+    if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+        Arc::new(PgStore::new(database_url).with_tag_rules(rules))
+    }
+    else if let Some(path) = database_url.strip_prefix("sqlite://") {
+        Arc::new(SqliteStore::new(path).with_tag_rules(rules))
+    }
+    else {
+        panic!("Unsupported database URL: {}", database_url);
+    }
+}
+
+/// Terms of a search in the web search syntax of PostgreSQL (`"exact
+/// phrase"`, `or`, `-excluded`), for backends that have to interpret it
+/// themselves.
+///
+/// Every group has to match, where a group matches if any of its terms
+/// does, and none of the excluded terms may match.
+pub(crate) struct SearchTerms {
+    pub groups:   Vec<Vec<String>>,
+    pub excluded: Vec<String>,
+}
+
+impl SearchTerms {
+    pub fn parse(query: &str) -> SearchTerms {
+        let mut groups: Vec<Vec<String>> = Vec::new();
+        let mut excluded = Vec::new();
+        let mut or = false;
+
+        let mut rest = query.trim_start();
+        while !rest.is_empty() {
+            let negated = rest.starts_with('-');
+            if negated {
+                rest = &rest[1..];
+            }
+            let (term, quoted) = if let Some(quoted) = rest.strip_prefix('"') {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                rest = quoted[end..].strip_prefix('"').unwrap_or(&quoted[end..]);
+                (&quoted[..end], true)
+            }
+            else {
+                let end = rest.find(|c: char| c.is_whitespace() || c == '"').unwrap_or(rest.len());
+                let term = &rest[..end];
+                rest = &rest[end..];
+                (term, false)
+            };
+            rest = rest.trim_start();
+
+            if term.trim().is_empty() {
+                continue;
+            }
+            if !quoted && !negated && term.eq_ignore_ascii_case("or") {
+                or = !groups.is_empty();
+                continue;
+            }
+            if negated {
+                excluded.push(term.to_owned());
+            }
+            else if or {
+                groups.last_mut().unwrap().push(term.to_owned());
+            }
+            else {
+                groups.push(vec![term.to_owned()]);
+            }
+            or = false;
+        }
+
+        SearchTerms { groups, excluded }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(terms: &[&str]) -> Vec<String> {
+        terms.iter().map(|&term| term.to_owned()).collect()
+    }
+
+    #[test]
+    fn parses_search_terms() {
+        let terms = SearchTerms::parse(r#"milk "oat milk" or soy -"cow" -tea"#);
+        assert_eq!(terms.groups, vec![strings(&["milk"]), strings(&["oat milk", "soy"])]);
+        assert_eq!(terms.excluded, strings(&["cow", "tea"]));
+    }
+
+    #[test]
+    fn leading_or_and_empty_terms_are_ignored() {
+        let terms = SearchTerms::parse(r#"  or milk "" - "OR" or "unterminated"#);
+        assert_eq!(terms.groups, vec![strings(&["milk"]), strings(&["OR", "unterminated"])]);
+        assert!(terms.excluded.is_empty());
+    }
+}
@@ -0,0 +1,1140 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+use crate::batch::{Mode, Operation, Outcome};
+use crate::doctor::{self, Finding};
+use crate::idempotency;
+use crate::models::{IdempotencyKey, Scribble, ScribbleRevision, Tag, TagAlias, Tagging, TaggingResult, TaggingStatus, TagUsage, SearchHit};
+use crate::period::Period;
+use crate::query::{Query, Term};
+use crate::sort::{self, Sort};
+use crate::tag_filter::TagFilter;
+use crate::tag_rules::{self, FoldPlan, TagRules};
+use crate::tag_tree;
+use crate::timestamp;
+use crate::{Error, Result, Seek, Page, SearchPage};
+
+use super::{ScribbleStore, SearchTerms};
+
+
+/// A store keeping everything in memory, for tests and throwaway servers.
+///
+/// It follows the semantics of the SQL backends, including the uniqueness of
+/// tag texts and of taggings, and taggings and revisions going along with
+/// their scribble or tag.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+    rules: TagRules,
+}
+
+#[derive(Clone)]
+struct Alias {
+    created_at: DateTime<Utc>,
+    tag_id:     i64,
+}
+
+#[derive(Default, Clone)]
+struct State {
+    scribbles:        BTreeMap<i64, Scribble>,
+    tags:             BTreeMap<i64, Tag>,
+    aliases:          BTreeMap<String, Alias>,
+    taggings:         BTreeMap<i64, Tagging>,
+    revisions:        BTreeMap<i64, ScribbleRevision>,
+    idempotency_keys: HashMap<String, IdempotencyKey>,
+    last_scribble_id: i64,
+    last_tag_id:      i64,
+    last_tagging_id:  i64,
+    last_revision_id: i64,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    /// Normalizes tags by `rules` rather than by the default ones.
+    pub fn with_tag_rules(self, rules: TagRules) -> MemoryStore {
+        MemoryStore { rules, ..self }
+    }
+}
+
+impl State {
+    fn create_tag(&mut self, text: &str) -> Result<Tag> {
+        if self.tags.values().any(|tag| tag.text == text) || self.aliases.contains_key(text) {
+            return Err(Error::TagExists);
+        }
+
+        self.last_tag_id += 1;
+        let tag = Tag {
+            id: self.last_tag_id,
+            created_at: timestamp::now(),
+            text: text.to_owned(),
+            ttl: None,
+        };
+        self.tags.insert(tag.id, tag.clone());
+        Ok(tag)
+    }
+
+    fn record_revision(&mut self, scribble_id: i64, created_at: DateTime<Utc>, text: &str) {
+        let revision = self.revisions.values()
+            .filter(|revision| revision.scribble_id == scribble_id)
+            .map(|revision| revision.revision)
+            .max()
+            .unwrap_or(0) + 1;
+
+        self.last_revision_id += 1;
+        let id = self.last_revision_id;
+        self.revisions.insert(id, ScribbleRevision {
+            id,
+            created_at,
+            scribble_id,
+            revision,
+            text: text.to_owned(),
+        });
+    }
+
+    fn create_scribble(&mut self, text: &str, expires_at: Option<DateTime<Utc>>) -> Scribble {
+        self.last_scribble_id += 1;
+        let scribble = Scribble {
+            id: self.last_scribble_id,
+            created_at: timestamp::now(),
+            updated_at: None,
+            text: text.to_owned(),
+            deleted_at: None,
+            expires_at,
+            version: 1,
+        };
+        self.scribbles.insert(scribble.id, scribble.clone());
+        self.record_revision(scribble.id, scribble.created_at, text);
+        scribble
+    }
+
+    /// Finds a scribble outside of the trash that has not expired to change,
+    /// provided that it is at `expected_version` if given.
+    fn scribble_to_change(&mut self, scribble_id: i64, expected_version: Option<i32>) -> Result<&mut Scribble> {
+        let now = timestamp::now();
+        match self.scribbles.get_mut(&scribble_id) {
+            Some(scribble) if is_live(scribble, now) => {
+                if expected_version.is_some_and(|expected| expected != scribble.version) {
+                    return Err(Error::VersionConflict(Box::new(scribble.clone())));
+                }
+                Ok(scribble)
+            },
+            _ => Err(Error::ScribbleNotFound),
+        }
+    }
+
+    fn update_scribble(&mut self, scribble_id: i64, text: &str, expected_version: Option<i32>) -> Result<Scribble> {
+        let now = timestamp::now();
+        let scribble = self.scribble_to_change(scribble_id, expected_version)?;
+        scribble.updated_at = Some(now);
+        scribble.text = text.to_owned();
+        scribble.version += 1;
+        let scribble = scribble.clone();
+        self.record_revision(scribble_id, now, text);
+        Ok(scribble)
+    }
+
+    fn delete_scribble(&mut self, scribble_id: i64, expected_version: Option<i32>) -> Result<()> {
+        let scribble = self.scribble_to_change(scribble_id, expected_version)?;
+        scribble.deleted_at = Some(timestamp::now());
+        scribble.version += 1;
+        Ok(())
+    }
+
+    /// Tags a scribble with all of `tag_texts`, failing without changing
+    /// anything if any of them cannot be.
+    fn tag_scribble_with_all(&mut self, scribble_id: i64, tag_texts: &[String]) -> Result<Vec<Tagging>> {
+        // Check everything up front, so that nothing is changed on failure
+        if !self.is_live(scribble_id) {
+            return Err(Error::ScribbleNotFound);
+        }
+        let tag_texts: Vec<String> = tag_texts.iter().map(|tag_text| self.resolve_alias(tag_text)).collect();
+        let mut seen = HashSet::new();
+        for tag_text in &tag_texts {
+            let tagged = self.find_tag(tag_text).is_ok_and(|tag| {
+                self.taggings.values().any(|tagging| tagging.scribble_id == scribble_id && tagging.tag_id == tag.id)
+            });
+            if tagged || !seen.insert(tag_text) {
+                return Err(Error::AlreadyTagged);
+            }
+        }
+
+        Ok(tag_texts.iter().map(|tag_text| self.tag_scribble(scribble_id, tag_text)).collect())
+    }
+
+    /// Tags a scribble known to exist and not to be tagged with `tag_text`.
+    fn tag_scribble(&mut self, scribble_id: i64, tag_text: &str) -> Tagging {
+        let tag = match self.create_tag(tag_text) {
+            Ok(tag) => tag,
+            Err(_) => self.tags.values().find(|tag| tag.text == tag_text).unwrap().clone(),
+        };
+
+        self.last_tagging_id += 1;
+        let tagging = Tagging {
+            id: self.last_tagging_id,
+            created_at: timestamp::now(),
+            scribble_id,
+            tag_id: tag.id,
+        };
+        self.taggings.insert(tagging.id, tagging.clone());
+
+        if let (Some(ttl), Some(scribble)) = (tag.ttl, self.scribbles.get_mut(&scribble_id)) {
+            let expiry = timestamp::saturating_add(tagging.created_at, Duration::milliseconds(ttl.saturating_mul(1000)));
+            if scribble.expires_at.is_none_or(|expires_at| expires_at > expiry) {
+                scribble.expires_at = Some(expiry);
+            }
+        }
+        tagging
+    }
+
+    fn untag_scribble(&mut self, scribble_id: i64, tag_text: &str) -> Result<()> {
+        let tag = self.find_tag(&self.resolve_alias(tag_text))?;
+        let before = self.taggings.len();
+        self.taggings.retain(|_, tagging| !(tagging.scribble_id == scribble_id && tagging.tag_id == tag.id));
+        if self.taggings.len() == before {
+            return Err(Error::NotTagged);
+        }
+        Ok(())
+    }
+
+    /// Applies a single operation of a batch, which either changes nothing
+    /// or succeeds.
+    fn apply(&mut self, rules: &TagRules, operation: &Operation) -> Result<Outcome> {
+        match operation {
+            Operation::Create { text, expires_at } => {
+                Ok(Outcome::Create { scribble: self.create_scribble(text, *expires_at) })
+            },
+            Operation::Update { scribble_id, text, version } => {
+                self.update_scribble(*scribble_id, text, *version).map(|scribble| Outcome::Update { scribble })
+            },
+            Operation::Delete { scribble_id, version } => {
+                self.delete_scribble(*scribble_id, *version).map(|()| Outcome::Delete)
+            },
+            Operation::Tag { scribble_id, tag_texts } => {
+                rules.normalize_all(tag_texts)
+                    .and_then(|tag_texts| self.tag_scribble_with_all(*scribble_id, &tag_texts))
+                    .map(|taggings| Outcome::Tag { taggings })
+            },
+            Operation::Untag { scribble_id, tag_text } => {
+                rules.normalize(tag_text)
+                    .and_then(|tag_text| self.untag_scribble(*scribble_id, &tag_text))
+                    .map(|()| Outcome::Untag)
+            },
+        }
+    }
+
+    fn find_tag(&self, text: &str) -> Result<Tag> {
+        match self.tags.values().find(|tag| tag.text == text) {
+            None => Err(Error::TagNotFound),
+            Some(tag) => Ok(tag.clone()),
+        }
+    }
+
+    /// Returns the text of the tag `text` stands for if it is an alias, or
+    /// `text` itself otherwise.
+    fn resolve_alias(&self, text: &str) -> String {
+        self.aliases.get(text)
+            .map_or_else(|| text.to_owned(), |alias| self.tags[&alias.tag_id].text.clone())
+    }
+
+    /// Removes a tag along with its taggings and aliases, as the foreign keys
+    /// of the SQL backends would.
+    fn remove_tag(&mut self, tag_id: i64) {
+        self.taggings.retain(|_, tagging| tagging.tag_id != tag_id);
+        self.aliases.retain(|_, alias| alias.tag_id != tag_id);
+        self.tags.remove(&tag_id);
+    }
+
+    fn rename_tag(&mut self, from: &str, to: &str) -> Result<Option<Tag>> {
+        let from = &self.resolve_alias(from);
+        if tag_tree::is_below(to, from) {
+            return Err(Error::TagBelowItself);
+        }
+        let below: Vec<String> = self.tags.values()
+            .filter(|tag| tag_tree::is_below(&tag.text, from))
+            .map(|tag| tag.text.clone())
+            .collect();
+
+        // Leave everything as it was if any of them fails, as a transaction
+        // would
+        let before = self.clone();
+        // A parent may only be there as the start of the tags below it
+        let renamed = match self.rename_single_tag(from, to) {
+            Err(Error::TagNotFound) if !below.is_empty() => Ok(None),
+            result => result.map(Some),
+        };
+        let result = renamed.and_then(|renamed| {
+            for text in &below {
+                self.rename_single_tag(text, &tag_tree::moved(text, from, to))?;
+            }
+            Ok(renamed)
+        });
+        if result.is_err() {
+            *self = before;
+        }
+        result
+    }
+
+    fn rename_single_tag(&mut self, from: &str, to: &str) -> Result<Tag> {
+        let id = self.find_tag(from)?.id;
+        if self.tags.values().any(|tag| tag.text == to && tag.id != id) || self.aliases.contains_key(to) {
+            return Err(Error::TagExists);
+        }
+
+        let tag = self.tags.get_mut(&id).unwrap();
+        tag.text = to.to_owned();
+        Ok(tag.clone())
+    }
+
+    fn merge_tags(&mut self, from: &str, into: &str) -> Result<Tag> {
+        let source = self.find_tag(&self.resolve_alias(from))?;
+        let into = self.resolve_alias(into);
+        let target = match self.find_tag(&into) {
+            Err(Error::TagNotFound) => return self.rename_single_tag(&source.text, &into),
+            result => result?,
+        };
+        if source.id == target.id {
+            return Ok(target);
+        }
+
+        let already_tagged: HashSet<i64> = self.taggings.values()
+            .filter(|tagging| tagging.tag_id == target.id)
+            .map(|tagging| tagging.scribble_id)
+            .collect();
+        for tagging in self.taggings.values_mut() {
+            if tagging.tag_id == source.id && !already_tagged.contains(&tagging.scribble_id) {
+                tagging.tag_id = target.id;
+            }
+        }
+        for alias in self.aliases.values_mut() {
+            if alias.tag_id == source.id {
+                alias.tag_id = target.id;
+            }
+        }
+        self.remove_tag(source.id);
+
+        Ok(target)
+    }
+
+    fn tag_aliases(&self) -> Vec<TagAlias> {
+        self.aliases.iter()
+            .map(|(alias, found)| TagAlias {
+                alias: alias.clone(),
+                created_at: found.created_at,
+                tag: self.tags[&found.tag_id].clone(),
+            })
+            .collect()
+    }
+
+    fn plan_folds(&self, rules: &TagRules) -> FoldPlan {
+        let tags: Vec<Tag> = self.tags.values().cloned().collect();
+        tag_rules::plan_folds(rules, &tags, &self.tag_aliases())
+    }
+
+    fn apply_folds(&mut self, plan: &FoldPlan) -> Result<()> {
+        // Leave everything as it was if any of them fails, as a transaction
+        // would
+        let before = self.clone();
+        let result = plan.folds.iter().try_for_each(|fold| self.merge_tags(&fold.from, &fold.into).map(|_| ()));
+        if result.is_err() {
+            *self = before;
+        }
+        result
+    }
+
+    fn is_live(&self, scribble_id: i64) -> bool {
+        let now = timestamp::now();
+        self.scribbles.get(&scribble_id).is_some_and(|scribble| is_live(scribble, now))
+    }
+
+    /// Removes scribbles for good along with their taggings and revisions.
+    fn purge(&mut self, purged: &HashSet<i64>) {
+        self.scribbles.retain(|id, _| !purged.contains(id));
+        self.taggings.retain(|_, tagging| !purged.contains(&tagging.scribble_id));
+        self.revisions.retain(|_, revision| !purged.contains(&revision.scribble_id));
+    }
+
+    fn tags_of(&self, scribble_id: i64) -> Vec<Tag> {
+        self.taggings.values()
+            .filter(|tagging| tagging.scribble_id == scribble_id)
+            .filter_map(|tagging| self.tags.get(&tagging.tag_id))
+            .cloned()
+            .collect()
+    }
+
+    /// The value of `key` for a scribble, as the SQL backends have it.
+    fn sort_value(&self, scribble: &Scribble, key: sort::Key) -> i64 {
+        match key {
+            sort::Key::Created => timestamp::to_micros(scribble.created_at),
+            sort::Key::Updated => timestamp::to_micros(scribble.updated_at.unwrap_or(scribble.created_at)),
+            sort::Key::Length => scribble.text.chars().count() as i64,
+            sort::Key::Tags => self.taggings.values().filter(|tagging| tagging.scribble_id == scribble.id).count() as i64,
+        }
+    }
+
+    fn matches(&self, scribble: &Scribble, term: &Term) -> bool {
+        match term {
+            Term::Word(text) => {
+                let haystack = words(&scribble.text);
+                words(text).iter().all(|(_, word)| haystack.iter().any(|(_, found)| found == word))
+            },
+            Term::Phrase(text) => {
+                contains_phrase(&words(&scribble.text), &words(text))
+            },
+            Term::Tag(text) => {
+                self.tags_of(scribble.id).iter().any(|tag| &tag.text == text)
+            },
+            Term::Before(time) => {
+                scribble.created_at < *time
+            },
+            Term::After(time) => {
+                scribble.created_at >= *time
+            },
+            Term::Id(id) => {
+                scribble.id == *id
+            },
+        }
+    }
+}
+
+/// Whether a scribble is neither in the trash nor expired by `now`.
+fn is_live(scribble: &Scribble, now: DateTime<Utc>) -> bool {
+    scribble.deleted_at.is_none() && scribble.expires_at.is_none_or(|expires_at| expires_at > now)
+}
+
+/// Splits `text` into lowercased words along with their byte offsets, which
+/// is roughly what the `simple` text search configuration of PostgreSQL does.
+fn words(text: &str) -> Vec<(usize, String)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices().chain(Some((text.len(), ' '))) {
+        match (start, c.is_alphanumeric()) {
+            (None, true) => {
+                start = Some(i);
+            },
+            (Some(s), false) => {
+                words.push((s, text[s..i].to_lowercase()));
+                start = None;
+            },
+            _ => {},
+        }
+    }
+    words
+}
+
+fn contains_phrase(haystack: &[(usize, String)], needle: &[(usize, String)]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|window| {
+        window.iter().zip(needle).all(|((_, a), (_, b))| a == b)
+    })
+}
+
+/// Wraps the words of `text` found in `marked` with `<mark>` and `</mark>`.
+fn highlight(text: &str, marked: &HashSet<String>) -> (String, usize) {
+    let mut snippet = String::new();
+    let mut count = 0;
+    let mut last = 0;
+    for (start, word) in words(text) {
+        if marked.contains(&word) {
+            let end = start + text[start..].find(|c: char| !c.is_alphanumeric()).unwrap_or(text.len() - start);
+            snippet.push_str(&text[last..start]);
+            snippet.push_str("<mark>");
+            snippet.push_str(&text[start..end]);
+            snippet.push_str("</mark>");
+            last = end;
+            count += 1;
+        }
+    }
+    snippet.push_str(&text[last..]);
+    (snippet, count)
+}
+
+impl ScribbleStore for MemoryStore {
+    fn get_scribble(&self, scribble_id: i64) -> Result<Scribble> {
+        let state = self.state.lock().unwrap();
+
+        if !state.is_live(scribble_id) {
+            return Err(Error::ScribbleNotFound);
+        }
+        Ok(state.scribbles[&scribble_id].clone())
+    }
+
+    fn create_scribble(&self, text: &str, expires_at: Option<DateTime<Utc>>) -> Result<Scribble> {
+        let mut state = self.state.lock().unwrap();
+
+        Ok(state.create_scribble(text, expires_at))
+    }
+
+    fn update_scribble(&self, scribble_id: i64, text: &str, expected_version: Option<i32>) -> Result<Scribble> {
+        let mut state = self.state.lock().unwrap();
+
+        state.update_scribble(scribble_id, text, expected_version)
+    }
+
+    fn delete_scribble(&self, scribble_id: i64, expected_version: Option<i32>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        state.delete_scribble(scribble_id, expected_version)
+    }
+
+    fn undelete_scribble(&self, scribble_id: i64) -> Result<Scribble> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.scribbles.get_mut(&scribble_id) {
+            Some(scribble) if scribble.deleted_at.is_some() => {
+                scribble.deleted_at = None;
+                scribble.version += 1;
+                Ok(scribble.clone())
+            },
+            _ => Err(Error::ScribbleNotFound),
+        }
+    }
+
+    fn trash(&self) -> Result<Vec<Scribble>> {
+        let state = self.state.lock().unwrap();
+
+        let mut trashed: Vec<Scribble> = state.scribbles.values()
+            .filter(|scribble| scribble.deleted_at.is_some())
+            .cloned()
+            .collect();
+        trashed.sort_by_key(|scribble| (scribble.deleted_at, scribble.id));
+        trashed.reverse();
+        Ok(trashed)
+    }
+
+    fn empty_trash(&self, deleted_before: Option<DateTime<Utc>>) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+
+        let purged: HashSet<i64> = state.scribbles.values()
+            .filter(|scribble| match (scribble.deleted_at, deleted_before) {
+                (None, _) => false,
+                (Some(_), None) => true,
+                (Some(deleted_at), Some(before)) => deleted_at < before,
+            })
+            .map(|scribble| scribble.id)
+            .collect();
+        state.purge(&purged);
+        Ok(purged.len())
+    }
+
+    fn purge_expired(&self, now: DateTime<Utc>) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+
+        let purged: HashSet<i64> = state.scribbles.values()
+            .filter(|scribble| scribble.expires_at.is_some_and(|expires_at| expires_at <= now))
+            .map(|scribble| scribble.id)
+            .collect();
+        state.purge(&purged);
+        Ok(purged.len())
+    }
+
+    fn claim_idempotency_key(&self, key: &str, fingerprint: &str, expired_before: DateTime<Utc>) -> Result<Option<IdempotencyKey>> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.idempotency_keys.get(key) {
+            Some(claimed) if claimed.created_at >= expired_before => {
+                idempotency::replay(claimed.clone(), fingerprint).map(Some)
+            },
+            _ => {
+                state.idempotency_keys.insert(key.to_owned(), IdempotencyKey {
+                    key: key.to_owned(),
+                    created_at: timestamp::now(),
+                    fingerprint: fingerprint.to_owned(),
+                    status: None,
+                    body: None,
+                });
+                Ok(None)
+            },
+        }
+    }
+
+    fn save_idempotent_response(&self, key: &str, status: i32, body: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(claimed) = state.idempotency_keys.get_mut(key) {
+            claimed.status = Some(status);
+            claimed.body = Some(body.to_owned());
+        }
+        Ok(())
+    }
+
+    fn release_idempotency_key(&self, key: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        state.idempotency_keys.remove(key);
+        Ok(())
+    }
+
+    fn purge_idempotency_keys(&self, created_before: DateTime<Utc>) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+
+        let before = state.idempotency_keys.len();
+        state.idempotency_keys.retain(|_, claimed| claimed.created_at >= created_before);
+        Ok(before - state.idempotency_keys.len())
+    }
+
+    fn revisions(&self, scribble_id: i64) -> Result<Vec<ScribbleRevision>> {
+        let state = self.state.lock().unwrap();
+
+        let revisions: Vec<ScribbleRevision> = state.revisions.values()
+            .filter(|revision| revision.scribble_id == scribble_id)
+            .cloned()
+            .collect();
+        if revisions.is_empty() {
+            return Err(Error::ScribbleNotFound);
+        }
+        Ok(revisions)
+    }
+
+    fn restore_revision(&self, scribble_id: i64, revision: i32) -> Result<Scribble> {
+        let mut state = self.state.lock().unwrap();
+
+        let text = match state.revisions.values().find(|found| found.scribble_id == scribble_id && found.revision == revision) {
+            None => return Err(Error::RevisionNotFound),
+            Some(found) => found.text.clone(),
+        };
+        state.update_scribble(scribble_id, &text, None)
+    }
+
+    fn create_tag(&self, text: &str) -> Result<Tag> {
+        let text = self.rules.normalize(text)?;
+        let mut state = self.state.lock().unwrap();
+
+        state.create_tag(&text)
+    }
+
+    fn tag_scribble(&self, scribble_id: i64, tag_texts: &[String]) -> Result<Vec<Tagging>> {
+        let tag_texts = self.rules.normalize_all(tag_texts)?;
+        let mut state = self.state.lock().unwrap();
+
+        state.tag_scribble_with_all(scribble_id, &tag_texts)
+    }
+
+    fn tag_scribbles(&self, scribble_ids: &[i64], tag_texts: &[String]) -> Result<Vec<TaggingResult>> {
+        let tag_texts = self.rules.normalize_all(tag_texts)?;
+        let mut state = self.state.lock().unwrap();
+
+        if !scribble_ids.iter().all(|&scribble_id| state.is_live(scribble_id)) {
+            return Err(Error::ScribbleNotFound);
+        }
+        let mut tags = Vec::new();
+        for tag_text in &tag_texts {
+            let tag_text = state.resolve_alias(tag_text);
+            tags.push(match state.create_tag(&tag_text) {
+                Err(Error::TagExists) => state.find_tag(&tag_text)?,
+                result => result?,
+            });
+        }
+
+        let mut results = Vec::new();
+        for &scribble_id in scribble_ids {
+            for tag in &tags {
+                let tagged = state.taggings.values().any(|tagging| tagging.scribble_id == scribble_id && tagging.tag_id == tag.id);
+                let (status, tagging) = if tagged {
+                    (TaggingStatus::AlreadyTagged, None)
+                }
+                else {
+                    (TaggingStatus::Tagged, Some(state.tag_scribble(scribble_id, &tag.text)))
+                };
+                results.push(TaggingResult {
+                    scribble_id,
+                    tag_text: tag.text.clone(),
+                    status,
+                    tagging,
+                });
+            }
+        }
+        Ok(results)
+    }
+
+    fn untag_scribble(&self, scribble_id: i64, tag_text: &str) -> Result<()> {
+        let tag_text = self.rules.normalize(tag_text)?;
+        let mut state = self.state.lock().unwrap();
+
+        state.untag_scribble(scribble_id, &tag_text)
+    }
+
+    fn rename_tag(&self, from: &str, to: &str) -> Result<Option<Tag>> {
+        let (from, to) = (self.rules.normalize(from)?, self.rules.normalize(to)?);
+        let mut state = self.state.lock().unwrap();
+
+        state.rename_tag(&from, &to)
+    }
+
+    fn merge_tags(&self, from: &str, into: &str) -> Result<Tag> {
+        let (from, into) = (self.rules.normalize(from)?, self.rules.normalize(into)?);
+        let mut state = self.state.lock().unwrap();
+
+        state.merge_tags(&from, &into)
+    }
+
+    fn delete_tag(&self, tag_text: &str, force: bool) -> Result<()> {
+        let tag_text = self.rules.normalize(tag_text)?;
+        let mut state = self.state.lock().unwrap();
+
+        let tag = state.find_tag(&state.resolve_alias(&tag_text))?;
+        if !force && state.taggings.values().any(|tagging| tagging.tag_id == tag.id) {
+            return Err(Error::TagInUse);
+        }
+        state.remove_tag(tag.id);
+
+        Ok(())
+    }
+
+    fn set_tag_ttl(&self, tag_text: &str, ttl: Option<i64>) -> Result<Tag> {
+        let tag_text = self.rules.normalize(tag_text)?;
+        let mut state = self.state.lock().unwrap();
+
+        let tag_text = state.resolve_alias(&tag_text);
+        let id = match state.create_tag(&tag_text) {
+            Ok(tag) => tag.id,
+            Err(Error::TagExists) => state.find_tag(&tag_text)?.id,
+            Err(e) => return Err(e),
+        };
+        let tag = state.tags.get_mut(&id).unwrap();
+        tag.ttl = ttl;
+        Ok(tag.clone())
+    }
+
+    fn fold_tags(&self, rules: &TagRules) -> Result<FoldPlan> {
+        let mut state = self.state.lock().unwrap();
+
+        let plan = state.plan_folds(rules);
+        state.apply_folds(&plan)?;
+        Ok(plan)
+    }
+
+    fn tag_aliases(&self) -> Result<Vec<TagAlias>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.tag_aliases())
+    }
+
+    fn alias_tag(&self, alias: &str, tag_text: &str) -> Result<TagAlias> {
+        let (alias, tag_text) = (self.rules.normalize(alias)?, self.rules.normalize(tag_text)?);
+        let mut state = self.state.lock().unwrap();
+
+        let tag = state.find_tag(&state.resolve_alias(&tag_text))?;
+        if state.find_tag(&alias).is_ok() {
+            return Err(Error::TagExists);
+        }
+        if state.aliases.contains_key(&alias) {
+            return Err(Error::AliasExists);
+        }
+        let created_at = timestamp::now();
+        state.aliases.insert(alias.clone(), Alias { created_at, tag_id: tag.id });
+        Ok(TagAlias { alias, created_at, tag })
+    }
+
+    fn unalias_tag(&self, alias: &str) -> Result<()> {
+        let alias = self.rules.normalize(alias)?;
+        let mut state = self.state.lock().unwrap();
+
+        match state.aliases.remove(&alias) {
+            None => Err(Error::AliasNotFound),
+            Some(_) => Ok(()),
+        }
+    }
+
+    fn batch(&self, operations: &[Operation], mode: Mode) -> Result<Vec<Result<Outcome>>> {
+        let mut state = self.state.lock().unwrap();
+
+        let before = state.clone();
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            let result = state.apply(&self.rules, operation);
+            let failed = result.is_err();
+            results.push(result);
+            if failed && mode == Mode::AllOrNothing {
+                *state = before;
+                break;
+            }
+        }
+        Ok(results)
+    }
+
+    fn check_integrity(&self, repair: bool) -> Result<Vec<Finding>> {
+        let mut state = self.state.lock().unwrap();
+
+        let orphans: Vec<Tagging> = state.taggings.values()
+            .filter(|tagging| !state.scribbles.contains_key(&tagging.scribble_id) || !state.tags.contains_key(&tagging.tag_id))
+            .cloned()
+            .collect();
+        // Tags with a TTL are policies for tagging later on
+        let unused: Vec<Tag> = state.tags.values()
+            .filter(|tag| tag.ttl.is_none() && !state.taggings.values().any(|tagging| tagging.tag_id == tag.id))
+            .cloned()
+            .collect();
+        let backdated: Vec<Scribble> = state.scribbles.values()
+            .filter(|scribble| scribble.updated_at.is_some_and(|updated_at| updated_at < scribble.created_at))
+            .cloned()
+            .collect();
+        let plan = state.plan_folds(&self.rules);
+        let similar = doctor::similar_tags(&plan, state.tags.values().cloned().collect());
+        let empty: Vec<i64> = state.scribbles.values()
+            .filter(|scribble| scribble.deleted_at.is_none() && doctor::is_empty(&scribble.text))
+            .map(|scribble| scribble.id)
+            .collect();
+
+        if repair {
+            // First, as it is the only repair that can fail
+            state.apply_folds(&plan)?;
+            for tagging in &orphans {
+                state.taggings.remove(&tagging.id);
+            }
+            // Merging may have put an unused tag to use
+            for tag in &unused {
+                if !state.taggings.values().any(|tagging| tagging.tag_id == tag.id) {
+                    state.remove_tag(tag.id);
+                }
+            }
+            for scribble in &backdated {
+                let scribble = state.scribbles.get_mut(&scribble.id).unwrap();
+                scribble.updated_at = Some(scribble.created_at);
+            }
+            let now = timestamp::now();
+            for scribble_id in &empty {
+                state.scribbles.get_mut(scribble_id).unwrap().deleted_at = Some(now);
+            }
+        }
+
+        let mut findings = Vec::new();
+        findings.extend(orphans.into_iter().map(|tagging| Finding::OrphanTagging {
+            tagging_id: tagging.id,
+            scribble_id: tagging.scribble_id,
+            tag_id: tagging.tag_id,
+        }));
+        findings.extend(unused.into_iter().map(|tag| Finding::UnusedTag {
+            tag_id: tag.id,
+            text: tag.text,
+        }));
+        findings.extend(backdated.into_iter().map(|scribble| Finding::UpdatedBeforeCreated {
+            scribble_id: scribble.id,
+            created_at: scribble.created_at,
+            updated_at: scribble.updated_at.unwrap_or(scribble.created_at),
+        }));
+        findings.extend(similar.into_iter().map(|tags| Finding::SimilarTags { tags }));
+        findings.extend(empty.into_iter().map(|scribble_id| Finding::EmptyScribble { scribble_id }));
+        Ok(findings)
+    }
+
+    fn tags(&self) -> Result<Vec<Tag>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.tags.values().cloned().collect())
+    }
+
+    fn tag_usage(&self) -> Result<Vec<TagUsage>> {
+        let state = self.state.lock().unwrap();
+
+        let mut usage: Vec<TagUsage> = state.tags.values()
+            .map(|tag| {
+                let taggings: Vec<&Tagging> = state.taggings.values()
+                    .filter(|tagging| tagging.tag_id == tag.id && state.is_live(tagging.scribble_id))
+                    .collect();
+                TagUsage {
+                    tag: tag.clone(),
+                    count: taggings.len() as i64,
+                    last_used_at: taggings.iter().map(|tagging| tagging.created_at).max(),
+                }
+            })
+            .collect();
+        usage.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.text.cmp(&b.tag.text)));
+        Ok(usage)
+    }
+
+    fn list(&self, size: Option<usize>, seek: Option<Seek>, filter: Option<&Query>, period: &Period, tags: &TagFilter, sort: Sort) -> Result<Page> {
+        let state = self.state.lock().unwrap();
+
+        let canonical = |text: &str| Ok(state.resolve_alias(&self.rules.normalize(text)?));
+        let filter = filter.map(|filter| filter.map_tags(canonical)).transpose()?;
+        let tags = tags.map_texts(canonical)?;
+        if seek.is_some_and(|seek| seek.cursor().sort() != sort) {
+            return Err(Error::InvalidCursor);
+        }
+        let now = timestamp::now();
+        let mut rows: Vec<(Scribble, i64)> = state.scribbles.values()
+            .filter(|scribble| is_live(scribble, now))
+            .filter(|scribble| {
+                filter.as_ref().is_none_or(|filter| {
+                    filter.clauses.iter().all(|clause| state.matches(scribble, &clause.term) != clause.negated)
+                })
+            })
+            .filter(|scribble| period.contains(scribble))
+            .filter(|scribble| tags.matches(&state.tags_of(scribble.id)))
+            .map(|scribble| (scribble.clone(), state.sort_value(scribble, sort.key)))
+            .filter(|(scribble, value)| {
+                // Ties are broken by id in the direction of the key
+                let key = (*value, scribble.id);
+                match seek {
+                    None => true,
+                    Some(Seek::Before(cursor)) => (key < (cursor.value, cursor.id)) == sort.descending,
+                    Some(Seek::After(cursor)) => (key > (cursor.value, cursor.id)) == sort.descending,
+                }
+            })
+            .collect();
+
+        // Rows are expected in seek order, that is backwards for `After`
+        rows.sort_by_key(|(scribble, value)| (*value, scribble.id));
+        let backwards = matches!(seek, Some(Seek::After(_)));
+        if sort.descending != backwards {
+            rows.reverse();
+        }
+        if let Some(size) = size {
+            rows.truncate(size + 1);
+        }
+
+        Ok(Page::from_rows(rows, sort, size, seek))
+    }
+
+    fn tags_of(&self, scribble_id: i64) -> Result<Vec<Tag>> {
+        let state = self.state.lock().unwrap();
+
+        if !state.is_live(scribble_id) {
+            return Ok(Vec::new());
+        }
+        Ok(state.tags_of(scribble_id))
+    }
+
+    fn tags_of_many(&self, scribble_ids: &[i64]) -> Result<HashMap<i64, Vec<Tag>>> {
+        let state = self.state.lock().unwrap();
+
+        let mut tags_of = HashMap::new();
+        for &scribble_id in scribble_ids {
+            let mut tags = state.tags_of(scribble_id);
+            if !tags.is_empty() {
+                tags.sort_by(|a, b| a.text.cmp(&b.text));
+                tags_of.insert(scribble_id, tags);
+            }
+        }
+        Ok(tags_of)
+    }
+
+    fn search(&self, query: &str, size: Option<usize>, offset: usize) -> Result<SearchPage> {
+        let state = self.state.lock().unwrap();
+
+        let terms = SearchTerms::parse(query);
+        let marked: HashSet<String> = terms.groups.iter()
+            .flatten()
+            .flat_map(|term| words(term))
+            .map(|(_, word)| word)
+            .collect();
+        let matches = |haystack: &[(usize, String)], term: &String| contains_phrase(haystack, &words(term));
+
+        let now = timestamp::now();
+        let mut rows: Vec<SearchHit> = state.scribbles.values()
+            .filter(|scribble| is_live(scribble, now))
+            .filter(|scribble| {
+                let haystack = words(&scribble.text);
+                !terms.groups.is_empty()
+                    && terms.groups.iter().all(|group| group.iter().any(|term| matches(&haystack, term)))
+                    && !terms.excluded.iter().any(|term| matches(&haystack, term))
+            })
+            .map(|scribble| {
+                let (snippet, count) = highlight(&scribble.text, &marked);
+                SearchHit {
+                    scribble: scribble.clone(),
+                    rank: count as f32,
+                    snippet,
+                }
+            })
+            .collect();
+
+        rows.sort_by(|a, b| {
+            b.rank.partial_cmp(&a.rank).unwrap()
+                .then((b.scribble.created_at, b.scribble.id).cmp(&(a.scribble.created_at, a.scribble.id)))
+        });
+        let mut rows: Vec<SearchHit> = rows.into_iter().skip(offset).collect();
+        if let Some(size) = size {
+            rows.truncate(size + 1);
+        }
+
+        Ok(SearchPage::from_rows(rows, size, offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tag_rules::Fold;
+
+    fn listed(store: &MemoryStore, filter: Option<&str>, tags: TagFilter) -> Result<Vec<i64>> {
+        let filter = filter.map(|filter| filter.parse::<Query>().unwrap());
+        let page = store.list(None, None, filter.as_ref(), &Period::default(), &tags, Sort::default())?;
+        Ok(page.scribbles.iter().map(|scribble| scribble.id).collect())
+    }
+
+    #[test]
+    fn creating_a_tag_twice_fails() {
+        let store = MemoryStore::new();
+        store.create_tag("work").unwrap();
+
+        match store.create_tag("work") {
+            Err(Error::TagExists) => {},
+            result => panic!("expected TagExists, got {:?}", result),
+        }
+        assert_eq!(store.tags().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn tagging_twice_fails_without_tagging_anything() {
+        let store = MemoryStore::new();
+        let scribble = store.create_scribble("scribble", None).unwrap();
+        store.tag_scribble(scribble.id, &["work".to_owned()]).unwrap();
+
+        match store.tag_scribble(scribble.id, &["home".to_owned(), "work".to_owned()]) {
+            Err(Error::AlreadyTagged) => {},
+            result => panic!("expected AlreadyTagged, got {:?}", result),
+        }
+        let texts: Vec<String> = store.tags_of(scribble.id).unwrap().into_iter().map(|tag| tag.text).collect();
+        assert_eq!(texts, vec!["work"]);
+
+        let results = store.tag_scribbles(&[scribble.id], &["home".to_owned(), "work".to_owned()]).unwrap();
+        let statuses: Vec<TaggingStatus> = results.iter().map(|result| result.status).collect();
+        assert_eq!(statuses, vec![TaggingStatus::Tagged, TaggingStatus::AlreadyTagged]);
+    }
+
+    #[test]
+    fn stale_versions_are_refused() {
+        let store = MemoryStore::new();
+        let scribble = store.create_scribble("first", None).unwrap();
+        let updated = store.update_scribble(scribble.id, "second", Some(scribble.version)).unwrap();
+        assert_eq!(updated.version, scribble.version + 1);
+
+        match store.update_scribble(scribble.id, "third", Some(scribble.version)) {
+            Err(Error::VersionConflict(current)) => assert_eq!(current.version, updated.version),
+            result => panic!("expected VersionConflict, got {:?}", result),
+        }
+        match store.delete_scribble(scribble.id, Some(scribble.version)) {
+            Err(Error::VersionConflict(current)) => assert_eq!(current.version, updated.version),
+            result => panic!("expected VersionConflict, got {:?}", result),
+        }
+        assert_eq!(store.get_scribble(scribble.id).unwrap().text, "second");
+        store.delete_scribble(scribble.id, Some(updated.version)).unwrap();
+    }
+
+    #[test]
+    fn list_filters_are_normalized_and_resolve_aliases() {
+        let store = MemoryStore::new();
+        let tagged = store.create_scribble("tagged", None).unwrap();
+        store.create_scribble("untagged", None).unwrap();
+        store.tag_scribble(tagged.id, &["Work".to_owned(), "JavaScript".to_owned()]).unwrap();
+        store.alias_tag("js", "javascript").unwrap();
+
+        let all = |text: &str| TagFilter { all: vec![text.to_owned()], ..TagFilter::default() };
+        assert_eq!(listed(&store, None, all("work")).unwrap(), vec![tagged.id]);
+        assert_eq!(listed(&store, None, all("Work")).unwrap(), vec![tagged.id]);
+        assert_eq!(listed(&store, None, TagFilter { any: vec!["JS".to_owned()], ..TagFilter::default() }).unwrap(), vec![tagged.id]);
+        assert_eq!(listed(&store, Some("tag:Work tag:js"), TagFilter::default()).unwrap(), vec![tagged.id]);
+        match listed(&store, None, all("a//b")) {
+            Err(Error::InvalidTag(text)) => assert_eq!(text, "a//b"),
+            result => panic!("expected InvalidTag, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn repair_folds_tags_as_fold_does() {
+        let store = MemoryStore::new().with_tag_rules("none".parse().unwrap());
+        let scribble = store.create_scribble("tagged", None).unwrap();
+        store.tag_scribble(scribble.id, &["Work".to_owned(), "work ".to_owned(), "Home".to_owned()]).unwrap();
+        let store = store.with_tag_rules(TagRules::default());
+
+        let findings = store.check_integrity(true).unwrap();
+        assert_eq!(findings.iter().filter(|finding| matches!(finding, Finding::SimilarTags { .. })).count(), 2);
+        let mut texts: Vec<String> = store.tags().unwrap().into_iter().map(|tag| tag.text).collect();
+        texts.sort();
+        assert_eq!(texts, vec!["home", "work"]);
+        assert!(store.fold_tags(&TagRules::default()).unwrap().folds.is_empty());
+        assert!(store.check_integrity(false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_fold_leaves_tags_as_they_were() {
+        let store = MemoryStore::new().with_tag_rules("none".parse().unwrap());
+        let scribble = store.create_scribble("tagged", None).unwrap();
+        store.tag_scribble(scribble.id, &["Work".to_owned()]).unwrap();
+
+        let plan = FoldPlan {
+            folds: vec![
+                Fold { from: "Work".to_owned(), into: "work".to_owned() },
+                Fold { from: "Missing".to_owned(), into: "missing".to_owned() },
+            ],
+            invalid: vec![],
+        };
+        let mut state = store.state.lock().unwrap();
+        assert!(state.apply_folds(&plan).is_err());
+        assert!(state.find_tag("Work").is_ok());
+    }
+
+    #[test]
+    fn renaming_an_implicit_parent_moves_the_tags_below_it() {
+        let store = MemoryStore::new();
+        let scribble = store.create_scribble("tagged", None).unwrap();
+        store.tag_scribble(scribble.id, &["work/a".to_owned(), "work/b/c".to_owned()]).unwrap();
+
+        assert!(store.rename_tag("work", "job").unwrap().is_none());
+        let mut texts: Vec<String> = store.tags().unwrap().into_iter().map(|tag| tag.text).collect();
+        texts.sort();
+        assert_eq!(texts, vec!["job/a", "job/b/c"]);
+        assert_eq!(store.rename_tag("job/a", "job/x").unwrap().unwrap().text, "job/x");
+        match store.rename_tag("work", "job") {
+            Err(Error::TagNotFound) => {},
+            result => panic!("expected TagNotFound, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn trashed_and_expired_scribbles_are_not_found() {
+        let store = MemoryStore::new();
+        let trashed = store.create_scribble("trashed", None).unwrap();
+        store.delete_scribble(trashed.id, None).unwrap();
+        let expired = store.create_scribble("expired", Some(timestamp::now() - Duration::seconds(1))).unwrap();
+        let live = store.create_scribble("live", None).unwrap();
+
+        for scribble_id in &[trashed.id, expired.id] {
+            match store.get_scribble(*scribble_id) {
+                Err(Error::ScribbleNotFound) => {},
+                result => panic!("expected ScribbleNotFound, got {:?}", result),
+            }
+        }
+        assert_eq!(store.get_scribble(live.id).unwrap().text, "live");
+    }
+
+    #[test]
+    fn trashed_scribbles_cannot_be_tagged() {
+        let store = MemoryStore::new();
+        let trashed = store.create_scribble("trashed", None).unwrap();
+        store.delete_scribble(trashed.id, None).unwrap();
+        let live = store.create_scribble("live", None).unwrap();
+        let tag_texts = vec!["a".to_owned()];
+
+        match store.tag_scribble(trashed.id, &tag_texts) {
+            Err(Error::ScribbleNotFound) => {},
+            result => panic!("expected ScribbleNotFound, got {:?}", result),
+        }
+        match store.tag_scribbles(&[live.id, trashed.id], &tag_texts) {
+            Err(Error::ScribbleNotFound) => {},
+            result => panic!("expected ScribbleNotFound, got {:?}", result),
+        }
+        assert!(store.tags_of(live.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn expired_scribbles_cannot_be_changed() {
+        let store = MemoryStore::new();
+        let expired = store.create_scribble("expired", Some(timestamp::now() - Duration::seconds(1))).unwrap();
+
+        match store.update_scribble(expired.id, "changed", None) {
+            Err(Error::ScribbleNotFound) => {},
+            result => panic!("expected ScribbleNotFound, got {:?}", result),
+        }
+        match store.delete_scribble(expired.id, None) {
+            Err(Error::ScribbleNotFound) => {},
+            result => panic!("expected ScribbleNotFound, got {:?}", result),
+        }
+    }
+}
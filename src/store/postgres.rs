@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+
+use crate::batch::{Mode, Operation, Outcome};
+use crate::doctor::Finding;
+use crate::models::{IdempotencyKey, Scribble, ScribbleRevision, Tag, TagAlias, Tagging, TaggingResult, TagUsage};
+use crate::period::Period;
+use crate::query::Query;
+use crate::sort::Sort;
+use crate::tag_filter::TagFilter;
+use crate::tag_rules::{FoldPlan, TagRules};
+use crate::{Error, Result, Seek, Page, SearchPage};
+
+use super::ScribbleStore;
+
+
+/// A store backed by PostgreSQL, whose schema is managed with the Diesel CLI
+/// from `migrations/`.
+pub struct PgStore {
+    pool:  Pool<ConnectionManager<PgConnection>>,
+    rules: TagRules,
+}
+
+impl PgStore {
+    pub fn new(database_url: &str) -> PgStore {
+        let manager = ConnectionManager::<PgConnection>::new(database_url);
+        let pool = Pool::builder()
+            .build(manager)
+            .expect("Failed to create pool.");
+        PgStore::with_pool(pool)
+    }
+
+    pub fn with_pool(pool: Pool<ConnectionManager<PgConnection>>) -> PgStore {
+        PgStore { pool, rules: TagRules::default() }
+    }
+
+    /// Normalizes tags by `rules` rather than by the default ones.
+    pub fn with_tag_rules(self, rules: TagRules) -> PgStore {
+        PgStore { rules, ..self }
+    }
+
+    fn conn(&self) -> Result<PooledConnection<ConnectionManager<PgConnection>>> {
+        self.pool.get().map_err(Error::PoolError)
+    }
+}
+
+impl ScribbleStore for PgStore {
+    fn get_scribble(&self, scribble_id: i64) -> Result<Scribble> {
+        crate::get_scribble(&*self.conn()?, scribble_id)
+    }
+
+    fn create_scribble(&self, text: &str, expires_at: Option<DateTime<Utc>>) -> Result<Scribble> {
+        crate::create_scribble(&*self.conn()?, text, expires_at)
+    }
+
+    fn update_scribble(&self, scribble_id: i64, text: &str, expected_version: Option<i32>) -> Result<Scribble> {
+        crate::update_scribble(&*self.conn()?, scribble_id, text, expected_version)
+    }
+
+    fn delete_scribble(&self, scribble_id: i64, expected_version: Option<i32>) -> Result<()> {
+        crate::delete_scribble(&*self.conn()?, scribble_id, expected_version)
+    }
+
+    fn undelete_scribble(&self, scribble_id: i64) -> Result<Scribble> {
+        crate::undelete_scribble(&*self.conn()?, scribble_id)
+    }
+
+    fn trash(&self) -> Result<Vec<Scribble>> {
+        crate::trash(&*self.conn()?)
+    }
+
+    fn empty_trash(&self, deleted_before: Option<DateTime<Utc>>) -> Result<usize> {
+        crate::empty_trash(&*self.conn()?, deleted_before)
+    }
+
+    fn purge_expired(&self, now: DateTime<Utc>) -> Result<usize> {
+        crate::purge_expired(&*self.conn()?, now)
+    }
+
+    fn claim_idempotency_key(&self, key: &str, fingerprint: &str, expired_before: DateTime<Utc>) -> Result<Option<IdempotencyKey>> {
+        crate::claim_idempotency_key(&*self.conn()?, key, fingerprint, expired_before)
+    }
+
+    fn save_idempotent_response(&self, key: &str, status: i32, body: &str) -> Result<()> {
+        crate::save_idempotent_response(&*self.conn()?, key, status, body)
+    }
+
+    fn release_idempotency_key(&self, key: &str) -> Result<()> {
+        crate::release_idempotency_key(&*self.conn()?, key)
+    }
+
+    fn purge_idempotency_keys(&self, created_before: DateTime<Utc>) -> Result<usize> {
+        crate::purge_idempotency_keys(&*self.conn()?, created_before)
+    }
+
+    fn revisions(&self, scribble_id: i64) -> Result<Vec<ScribbleRevision>> {
+        crate::revisions(&*self.conn()?, scribble_id)
+    }
+
+    fn restore_revision(&self, scribble_id: i64, revision: i32) -> Result<Scribble> {
+        crate::restore_revision(&*self.conn()?, scribble_id, revision)
+    }
+
+    fn create_tag(&self, text: &str) -> Result<Tag> {
+        crate::create_tag(&*self.conn()?, &self.rules.normalize(text)?)
+    }
+
+    fn tag_scribble(&self, scribble_id: i64, tag_texts: &[String]) -> Result<Vec<Tagging>> {
+        crate::tag_scribble(&*self.conn()?, scribble_id, &self.rules.normalize_all(tag_texts)?)
+    }
+
+    fn tag_scribbles(&self, scribble_ids: &[i64], tag_texts: &[String]) -> Result<Vec<TaggingResult>> {
+        crate::tag_scribbles(&*self.conn()?, scribble_ids, &self.rules.normalize_all(tag_texts)?)
+    }
+
+    fn untag_scribble(&self, scribble_id: i64, tag_text: &str) -> Result<()> {
+        crate::untag_scribble(&*self.conn()?, scribble_id, &self.rules.normalize(tag_text)?)
+    }
+
+    fn rename_tag(&self, from: &str, to: &str) -> Result<Option<Tag>> {
+        crate::rename_tag(&*self.conn()?, &self.rules.normalize(from)?, &self.rules.normalize(to)?)
+    }
+
+    fn merge_tags(&self, from: &str, into: &str) -> Result<Tag> {
+        crate::merge_tags(&*self.conn()?, &self.rules.normalize(from)?, &self.rules.normalize(into)?)
+    }
+
+    fn delete_tag(&self, tag_text: &str, force: bool) -> Result<()> {
+        crate::delete_tag(&*self.conn()?, &self.rules.normalize(tag_text)?, force)
+    }
+
+    fn batch(&self, operations: &[Operation], mode: Mode) -> Result<Vec<Result<Outcome>>> {
+        crate::batch(&*self.conn()?, &self.rules, operations, mode)
+    }
+
+    fn check_integrity(&self, repair: bool) -> Result<Vec<Finding>> {
+        crate::check_integrity(&*self.conn()?, &self.rules, repair)
+    }
+
+    fn set_tag_ttl(&self, tag_text: &str, ttl: Option<i64>) -> Result<Tag> {
+        crate::set_tag_ttl(&*self.conn()?, &self.rules.normalize(tag_text)?, ttl)
+    }
+
+    fn fold_tags(&self, rules: &TagRules) -> Result<FoldPlan> {
+        crate::fold_tags(&*self.conn()?, rules)
+    }
+
+    fn tag_aliases(&self) -> Result<Vec<TagAlias>> {
+        crate::tag_aliases(&*self.conn()?)
+    }
+
+    fn alias_tag(&self, alias: &str, tag_text: &str) -> Result<TagAlias> {
+        crate::alias_tag(&*self.conn()?, &self.rules.normalize(alias)?, &self.rules.normalize(tag_text)?)
+    }
+
+    fn unalias_tag(&self, alias: &str) -> Result<()> {
+        crate::unalias_tag(&*self.conn()?, &self.rules.normalize(alias)?)
+    }
+
+    fn tags(&self) -> Result<Vec<Tag>> {
+        crate::tags(&*self.conn()?)
+    }
+
+    fn tag_usage(&self) -> Result<Vec<TagUsage>> {
+        crate::tag_usage(&*self.conn()?)
+    }
+
+    fn list(&self, size: Option<usize>, seek: Option<Seek>, filter: Option<&Query>, period: &Period, tags: &TagFilter, sort: Sort) -> Result<Page> {
+        let conn = self.conn()?;
+        let canonical = |text: &str| crate::resolve_alias(&*conn, &self.rules.normalize(text)?);
+        let filter = filter.map(|filter| filter.map_tags(canonical)).transpose()?;
+        let tags = tags.map_texts(canonical)?;
+        crate::list(&*conn, size, seek, filter.as_ref(), period, &tags, sort)
+    }
+
+    fn tags_of(&self, scribble_id: i64) -> Result<Vec<Tag>> {
+        crate::tags_of(&*self.conn()?, scribble_id)
+    }
+
+    fn tags_of_many(&self, scribble_ids: &[i64]) -> Result<HashMap<i64, Vec<Tag>>> {
+        crate::tags_of_many(&*self.conn()?, scribble_ids)
+    }
+
+    fn search(&self, query: &str, size: Option<usize>, offset: usize) -> Result<SearchPage> {
+        crate::search(&*self.conn()?, query, size, offset)
+    }
+}
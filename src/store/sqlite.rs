@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+
+use chrono::prelude::*;
+use diesel::connection::SimpleConnection;
+use diesel::dsl::{not, sql};
+use diesel::expression::BoxableExpression;
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager, CustomizeConnection, Pool, PooledConnection};
+use diesel::sqlite::Sqlite;
+use diesel::sql_types::{BigInt, Bool, Text};
+
+use crate::batch::{self, Operation, Outcome};
+use crate::doctor::Finding;
+use crate::models::{IdempotencyKey, NewIdempotencyKey, Scribble, NewScribble, ScribbleRevision, Tag, NewTag, TagAlias, NewTagAlias, Tagging, NewTagging, TaggingResult, TagUsage, SearchHit};
+use crate::period::Period;
+use crate::query::{Query, Term};
+use crate::schema::{idempotency_keys, scribbles, tag_aliases, taggings, tags};
+use crate::sort::{self, KeySql, Sort};
+use crate::tag_filter::TagFilter;
+use crate::tag_rules::{FoldPlan, TagRules};
+use crate::timestamp::{self, SqlTimestamp, Timestamptz};
+use crate::{Dialect, Error, Result, Seek, Page, SearchPage};
+
+use super::{ScribbleStore, SearchTerms};
+
+
+embed_migrations!("migrations-sqlite");
+
+no_arg_sql_function!(last_insert_rowid, BigInt);
+
+/// A store backed by a single SQLite database file, whose schema is migrated
+/// from `migrations-sqlite/` when the store is opened.
+pub struct SqliteStore {
+    pool:  Pool<ConnectionManager<SqliteConnection>>,
+    rules: TagRules,
+}
+
+#[derive(Debug)]
+struct ConnectionOptions;
+
+impl CustomizeConnection<SqliteConnection, r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> std::result::Result<(), r2d2::Error> {
+        // Let the server's connections wait for each other instead of failing
+        // with SQLITE_BUSY, and enforce foreign keys which SQLite leaves off
+        // by default
+        conn.batch_execute("PRAGMA busy_timeout = 5000; PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")
+            .map_err(r2d2::Error::QueryError)
+    }
+}
+
+impl SqliteStore {
+    pub fn new(path: &str) -> SqliteStore {
+        let manager = ConnectionManager::<SqliteConnection>::new(path);
+        let pool = Pool::builder()
+            .connection_customizer(Box::new(ConnectionOptions))
+            .build(manager)
+            .expect("Failed to create pool.");
+        let conn = pool.get()
+            .expect("Failed to get a connection.");
+        embedded_migrations::run(&*conn)
+            .expect("Failed to run migrations.");
+        SqliteStore { pool, rules: TagRules::default() }
+    }
+
+    /// Normalizes tags by `rules` rather than by the default ones.
+    pub fn with_tag_rules(self, rules: TagRules) -> SqliteStore {
+        SqliteStore { rules, ..self }
+    }
+
+    fn conn(&self) -> Result<PooledConnection<ConnectionManager<SqliteConnection>>> {
+        self.pool.get().map_err(Error::PoolError)
+    }
+}
+
+impl ScribbleStore for SqliteStore {
+    fn get_scribble(&self, scribble_id: i64) -> Result<Scribble> {
+        crate::get_scribble(&*self.conn()?, scribble_id)
+    }
+
+    fn create_scribble(&self, text: &str, expires_at: Option<DateTime<Utc>>) -> Result<Scribble> {
+        crate::create_scribble(&*self.conn()?, text, expires_at)
+    }
+
+    fn update_scribble(&self, scribble_id: i64, text: &str, expected_version: Option<i32>) -> Result<Scribble> {
+        crate::update_scribble(&*self.conn()?, scribble_id, text, expected_version)
+    }
+
+    fn delete_scribble(&self, scribble_id: i64, expected_version: Option<i32>) -> Result<()> {
+        crate::delete_scribble(&*self.conn()?, scribble_id, expected_version)
+    }
+
+    fn undelete_scribble(&self, scribble_id: i64) -> Result<Scribble> {
+        crate::undelete_scribble(&*self.conn()?, scribble_id)
+    }
+
+    fn trash(&self) -> Result<Vec<Scribble>> {
+        crate::trash(&*self.conn()?)
+    }
+
+    fn empty_trash(&self, deleted_before: Option<DateTime<Utc>>) -> Result<usize> {
+        crate::empty_trash(&*self.conn()?, deleted_before)
+    }
+
+    fn purge_expired(&self, now: DateTime<Utc>) -> Result<usize> {
+        crate::purge_expired(&*self.conn()?, now)
+    }
+
+    fn claim_idempotency_key(&self, key: &str, fingerprint: &str, expired_before: DateTime<Utc>) -> Result<Option<IdempotencyKey>> {
+        crate::claim_idempotency_key(&*self.conn()?, key, fingerprint, expired_before)
+    }
+
+    fn save_idempotent_response(&self, key: &str, status: i32, body: &str) -> Result<()> {
+        crate::save_idempotent_response(&*self.conn()?, key, status, body)
+    }
+
+    fn release_idempotency_key(&self, key: &str) -> Result<()> {
+        crate::release_idempotency_key(&*self.conn()?, key)
+    }
+
+    fn purge_idempotency_keys(&self, created_before: DateTime<Utc>) -> Result<usize> {
+        crate::purge_idempotency_keys(&*self.conn()?, created_before)
+    }
+
+    fn revisions(&self, scribble_id: i64) -> Result<Vec<ScribbleRevision>> {
+        crate::revisions(&*self.conn()?, scribble_id)
+    }
+
+    fn restore_revision(&self, scribble_id: i64, revision: i32) -> Result<Scribble> {
+        crate::restore_revision(&*self.conn()?, scribble_id, revision)
+    }
+
+    fn create_tag(&self, text: &str) -> Result<Tag> {
+        crate::create_tag(&*self.conn()?, &self.rules.normalize(text)?)
+    }
+
+    fn tag_scribble(&self, scribble_id: i64, tag_texts: &[String]) -> Result<Vec<Tagging>> {
+        crate::tag_scribble(&*self.conn()?, scribble_id, &self.rules.normalize_all(tag_texts)?)
+    }
+
+    fn tag_scribbles(&self, scribble_ids: &[i64], tag_texts: &[String]) -> Result<Vec<TaggingResult>> {
+        crate::tag_scribbles(&*self.conn()?, scribble_ids, &self.rules.normalize_all(tag_texts)?)
+    }
+
+    fn untag_scribble(&self, scribble_id: i64, tag_text: &str) -> Result<()> {
+        crate::untag_scribble(&*self.conn()?, scribble_id, &self.rules.normalize(tag_text)?)
+    }
+
+    fn rename_tag(&self, from: &str, to: &str) -> Result<Option<Tag>> {
+        crate::rename_tag(&*self.conn()?, &self.rules.normalize(from)?, &self.rules.normalize(to)?)
+    }
+
+    fn merge_tags(&self, from: &str, into: &str) -> Result<Tag> {
+        crate::merge_tags(&*self.conn()?, &self.rules.normalize(from)?, &self.rules.normalize(into)?)
+    }
+
+    fn delete_tag(&self, tag_text: &str, force: bool) -> Result<()> {
+        crate::delete_tag(&*self.conn()?, &self.rules.normalize(tag_text)?, force)
+    }
+
+    fn batch(&self, operations: &[Operation], mode: batch::Mode) -> Result<Vec<Result<Outcome>>> {
+        crate::batch(&*self.conn()?, &self.rules, operations, mode)
+    }
+
+    fn check_integrity(&self, repair: bool) -> Result<Vec<Finding>> {
+        crate::check_integrity(&*self.conn()?, &self.rules, repair)
+    }
+
+    fn set_tag_ttl(&self, tag_text: &str, ttl: Option<i64>) -> Result<Tag> {
+        crate::set_tag_ttl(&*self.conn()?, &self.rules.normalize(tag_text)?, ttl)
+    }
+
+    fn fold_tags(&self, rules: &TagRules) -> Result<FoldPlan> {
+        crate::fold_tags(&*self.conn()?, rules)
+    }
+
+    fn tag_aliases(&self) -> Result<Vec<TagAlias>> {
+        crate::tag_aliases(&*self.conn()?)
+    }
+
+    fn alias_tag(&self, alias: &str, tag_text: &str) -> Result<TagAlias> {
+        crate::alias_tag(&*self.conn()?, &self.rules.normalize(alias)?, &self.rules.normalize(tag_text)?)
+    }
+
+    fn unalias_tag(&self, alias: &str) -> Result<()> {
+        crate::unalias_tag(&*self.conn()?, &self.rules.normalize(alias)?)
+    }
+
+    fn tags(&self) -> Result<Vec<Tag>> {
+        crate::tags(&*self.conn()?)
+    }
+
+    fn tag_usage(&self) -> Result<Vec<TagUsage>> {
+        crate::tag_usage(&*self.conn()?)
+    }
+
+    fn list(&self, size: Option<usize>, seek: Option<Seek>, filter: Option<&Query>, period: &Period, tags: &TagFilter, sort: Sort) -> Result<Page> {
+        let conn = self.conn()?;
+        let canonical = |text: &str| crate::resolve_alias(&*conn, &self.rules.normalize(text)?);
+        let filter = filter.map(|filter| filter.map_tags(canonical)).transpose()?;
+        let tags = tags.map_texts(canonical)?;
+        crate::list(&*conn, size, seek, filter.as_ref(), period, &tags, sort)
+    }
+
+    fn tags_of(&self, scribble_id: i64) -> Result<Vec<Tag>> {
+        crate::tags_of(&*self.conn()?, scribble_id)
+    }
+
+    fn tags_of_many(&self, scribble_ids: &[i64]) -> Result<HashMap<i64, Vec<Tag>>> {
+        crate::tags_of_many(&*self.conn()?, scribble_ids)
+    }
+
+    fn search(&self, query: &str, size: Option<usize>, offset: usize) -> Result<SearchPage> {
+        search(&*self.conn()?, query, size, offset)
+    }
+}
+
+impl Dialect for SqliteConnection {
+    fn insert_scribble(&self, new_scribble: &NewScribble) -> QueryResult<Scribble> {
+        self.transaction(|| {
+            diesel::insert_into(scribbles::table)
+                .values(new_scribble)
+                .execute(self)?;
+            scribbles::table
+                .find(last_insert_id(self)?)
+                .first(self)
+        })
+    }
+
+    fn insert_tag(&self, new_tag: &NewTag) -> QueryResult<Tag> {
+        self.transaction(|| {
+            diesel::insert_into(tags::table)
+                .values(new_tag)
+                .execute(self)?;
+            tags::table
+                .find(last_insert_id(self)?)
+                .first(self)
+        })
+    }
+
+    fn insert_tag_if_missing(&self, new_tag: &NewTag) -> QueryResult<()> {
+        // INSERT OR IGNORE is what ON CONFLICT DO NOTHING is to SQLite, while
+        // foreign keys are still enforced
+        diesel::insert_or_ignore_into(tags::table)
+            .values(new_tag)
+            .execute(self)?;
+        Ok(())
+    }
+
+    fn insert_tagging(&self, new_tagging: &NewTagging) -> QueryResult<Option<Tagging>> {
+        let inserted = diesel::insert_or_ignore_into(taggings::table)
+            .values(new_tagging)
+            .execute(self)?;
+        if inserted == 0 {
+            return Ok(None);
+        }
+        taggings::table
+            .find(last_insert_id(self)?)
+            .first(self)
+            .map(Some)
+    }
+
+    fn insert_tag_alias(&self, new_alias: &NewTagAlias) -> QueryResult<()> {
+        diesel::insert_into(tag_aliases::table)
+            .values(new_alias)
+            .execute(self)?;
+        Ok(())
+    }
+
+    fn insert_idempotency_key(&self, new_key: &NewIdempotencyKey) -> QueryResult<bool> {
+        let inserted = diesel::insert_or_ignore_into(idempotency_keys::table)
+            .values(new_key)
+            .execute(self)?;
+        Ok(inserted == 1)
+    }
+
+    fn sort_sql(key: sort::Key) -> KeySql {
+        use crate::sort::Key;
+
+        // Times are stored as RFC 3339 text, which has to be taken apart to
+        // count microseconds
+        match key {
+            Key::Created => KeySql {
+                order: "scribbles.created_at",
+                value: "CAST(strftime('%s', substr(scribbles.created_at, 1, 19)) AS INTEGER) * 1000000 \
+                        + CAST(substr(scribbles.created_at, 21, 6) AS INTEGER)",
+            },
+            Key::Updated => KeySql {
+                order: "COALESCE(scribbles.updated_at, scribbles.created_at)",
+                value: "CAST(strftime('%s', substr(COALESCE(scribbles.updated_at, scribbles.created_at), 1, 19)) AS INTEGER) * 1000000 \
+                        + CAST(substr(COALESCE(scribbles.updated_at, scribbles.created_at), 21, 6) AS INTEGER)",
+            },
+            Key::Length => KeySql {
+                order: "length(scribbles.text)",
+                value: "length(scribbles.text)",
+            },
+            Key::Tags => KeySql {
+                order: sort::TAG_COUNT_SQL,
+                value: sort::TAG_COUNT_SQL,
+            },
+        }
+    }
+
+    fn apply_query<'a>(filter: &'a Query, mut query: scribbles::BoxedQuery<'a, Sqlite>) -> scribbles::BoxedQuery<'a, Sqlite> {
+        for clause in &filter.clauses {
+            let predicate = predicate(&clause.term);
+            query = if clause.negated {
+                query.filter(not(predicate))
+            }
+            else {
+                query.filter(predicate)
+            };
+        }
+        query
+    }
+}
+
+fn last_insert_id(conn: &SqliteConnection) -> QueryResult<i64> {
+    diesel::select(last_insert_rowid).get_result(conn)
+}
+
+fn search(conn: &SqliteConnection, query: &str, size: Option<usize>, offset: usize) -> Result<SearchPage> {
+    let fts_query = match fts_query(&SearchTerms::parse(query)) {
+        Some(fts_query) => fts_query,
+        None => return Ok(SearchPage::from_rows(Vec::new(), size, offset)),
+    };
+
+    // A negative limit means no limit in SQLite
+    let limit = size.map_or(-1, |size| size as i64 + 1);
+    let result = diesel::sql_query("SELECT scribbles.*, -bm25(scribbles_fts) AS rank, snippet(scribbles_fts, 0, '<mark>', '</mark>', '...', 16) AS snippet FROM scribbles_fts JOIN scribbles ON scribbles.id = scribbles_fts.rowid WHERE scribbles_fts MATCH ? AND deleted_at IS NULL AND (expires_at IS NULL OR expires_at > ?) ORDER BY rank DESC, created_at DESC, id DESC LIMIT ? OFFSET ?;")
+        .bind::<Text, _>(fts_query)
+        .bind::<Timestamptz, _>(timestamp::now())
+        .bind::<BigInt, _>(limit)
+        .bind::<BigInt, _>(offset as i64)
+        .get_results::<SearchHit>(conn);
+
+    match result {
+        Err(e) => {
+            Err(Error::DatabaseError(e))
+        },
+        Ok(selected) => {
+            Ok(SearchPage::from_rows(selected, size, offset))
+        },
+    }
+}
+
+/// Quotes `text` as a single FTS5 string, which matches it as a phrase.
+fn fts_phrase(text: &str) -> String {
+    format!("\"{}\"", text.replace('"', "\"\""))
+}
+
+/// Translates search terms into an FTS5 query, or returns `None` if nothing
+/// is left to match.
+fn fts_query(terms: &SearchTerms) -> Option<String> {
+    if terms.groups.is_empty() {
+        return None;
+    }
+    let mut fts_query = terms.groups.iter()
+        .map(|group| {
+            let phrases = group.iter().map(|term| fts_phrase(term)).collect::<Vec<_>>();
+            format!("({})", phrases.join(" OR "))
+        })
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    for term in &terms.excluded {
+        fts_query = format!("({}) NOT {}", fts_query, fts_phrase(term));
+    }
+    Some(fts_query)
+}
+
+type Predicate<'a> = Box<dyn BoxableExpression<scribbles::table, Sqlite, SqlType = Bool> + 'a>;
+
+fn predicate(term: &Term) -> Predicate<'_> {
+    match term {
+        Term::Word(text) | Term::Phrase(text) => {
+            Box::new(sql::<Bool>("scribbles.id IN (SELECT rowid FROM scribbles_fts WHERE scribbles_fts MATCH ")
+                     .bind::<Text, _>(fts_phrase(text))
+                     .sql(")"))
+        },
+        Term::Tag(tag) => {
+            let tag_ids = tags::table
+                .filter(tags::text.eq(tag.as_str()))
+                .select(tags::id);
+            let scribble_ids = taggings::table
+                .filter(taggings::tag_id.eq_any(tag_ids))
+                .select(taggings::scribble_id);
+            Box::new(scribbles::id.eq_any(scribble_ids))
+        },
+        Term::Before(time) => {
+            Box::new(scribbles::created_at.lt(SqlTimestamp(*time)))
+        },
+        Term::After(time) => {
+            Box::new(scribbles::created_at.ge(SqlTimestamp(*time)))
+        },
+        Term::Id(id) => {
+            Box::new(scribbles::id.eq(*id))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Opens a store on a new database file, removed when dropped.
+    struct TestStore {
+        store: SqliteStore,
+        path:  std::path::PathBuf,
+    }
+
+    impl TestStore {
+        fn new(name: &str) -> TestStore {
+            let path = std::env::temp_dir().join(format!("forghetti-{}-{}.db", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            TestStore { store: SqliteStore::new(path.to_str().unwrap()), path }
+        }
+    }
+
+    impl Drop for TestStore {
+        fn drop(&mut self) {
+            for suffix in &["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", self.path.display(), suffix));
+            }
+        }
+    }
+
+    fn listed(store: &SqliteStore, filter: Option<&str>, tags: TagFilter) -> Result<Vec<i64>> {
+        let filter = filter.map(|filter| filter.parse::<Query>().unwrap());
+        let page = store.list(None, None, filter.as_ref(), &Period::default(), &tags, Sort::default())?;
+        Ok(page.scribbles.iter().map(|scribble| scribble.id).collect())
+    }
+
+    #[test]
+    fn list_filters_are_normalized_and_resolve_aliases() {
+        let test = TestStore::new("list-filters");
+        let store = &test.store;
+        let tagged = store.create_scribble("tagged", None).unwrap();
+        store.create_scribble("untagged", None).unwrap();
+        store.tag_scribble(tagged.id, &["Work".to_owned(), "JavaScript".to_owned()]).unwrap();
+        store.alias_tag("js", "javascript").unwrap();
+
+        let all = |text: &str| TagFilter { all: vec![text.to_owned()], ..TagFilter::default() };
+        assert_eq!(listed(store, None, all("work")).unwrap(), vec![tagged.id]);
+        assert_eq!(listed(store, None, all("Work")).unwrap(), vec![tagged.id]);
+        assert_eq!(listed(store, None, TagFilter { any: vec!["JS".to_owned()], ..TagFilter::default() }).unwrap(), vec![tagged.id]);
+        assert_eq!(listed(store, Some("tag:Work tag:js"), TagFilter::default()).unwrap(), vec![tagged.id]);
+        match listed(store, None, all("a//b")) {
+            Err(Error::InvalidTag(text)) => assert_eq!(text, "a//b"),
+            result => panic!("expected InvalidTag, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn tag_filters_list_the_scribbles_they_match() {
+        let test = TestStore::new("tag-filters");
+        let store = &test.store;
+        let mut scribbles = Vec::new();
+        for texts in &[&[][..], &["work"], &["work", "urgent"], &["work/project"], &["home", "done"], &["workshop"]] {
+            let scribble = store.create_scribble("scribble", None).unwrap();
+            let texts: Vec<String> = texts.iter().map(|&text| text.to_owned()).collect();
+            if !texts.is_empty() {
+                store.tag_scribble(scribble.id, &texts).unwrap();
+            }
+            scribbles.push(scribble.id);
+        }
+
+        let texts = |texts: &[&str]| texts.iter().map(|&text| text.to_owned()).collect::<Vec<_>>();
+        let filters = [
+            TagFilter { all: texts(&["work", "urgent"]), ..TagFilter::default() },
+            TagFilter { any: texts(&["urgent", "home"]), ..TagFilter::default() },
+            TagFilter { none: texts(&["work", "done"]), ..TagFilter::default() },
+            TagFilter { untagged: true, ..TagFilter::default() },
+            TagFilter { all: texts(&["work"]), descendants: true, ..TagFilter::default() },
+            TagFilter { none: texts(&["work"]), descendants: true, ..TagFilter::default() },
+            TagFilter { all: texts(&["work"]), none: texts(&["urgent"]), descendants: true, ..TagFilter::default() },
+        ];
+        for filter in &filters {
+            let mut expected: Vec<i64> = scribbles.iter()
+                .copied()
+                .filter(|&scribble_id| filter.matches(&store.tags_of(scribble_id).unwrap()))
+                .collect();
+            expected.reverse();
+            assert_eq!(listed(store, None, filter.clone()).unwrap(), expected, "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn renaming_an_implicit_parent_moves_the_tags_below_it() {
+        let test = TestStore::new("rename-parent");
+        let store = &test.store;
+        let scribble = store.create_scribble("tagged", None).unwrap();
+        store.tag_scribble(scribble.id, &["work/a".to_owned(), "work/b/c".to_owned()]).unwrap();
+
+        assert!(store.rename_tag("work", "job").unwrap().is_none());
+        let mut texts: Vec<String> = store.tags().unwrap().into_iter().map(|tag| tag.text).collect();
+        texts.sort();
+        assert_eq!(texts, vec!["job/a", "job/b/c"]);
+        assert_eq!(store.rename_tag("job/a", "job/x").unwrap().unwrap().text, "job/x");
+        match store.rename_tag("work", "job") {
+            Err(Error::TagNotFound) => {},
+            result => panic!("expected TagNotFound, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Filters on the tags of listed scribbles, which can be required all
+//! together, as alternatives or not at all, or be required to be missing
+//! altogether for scribbles yet to be sorted out.
+
+use diesel::backend::Backend;
+use diesel::dsl::{exists, not};
+use diesel::prelude::*;
+use diesel::serialize::ToSql;
+use diesel::sql_types::{BigInt, HasSqlType, Integer, Text};
+
+use crate::models::Tag;
+use crate::schema::{scribbles, taggings, tags};
+use crate::tag_tree;
+use crate::Result;
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TagFilter {
+    /// Tags scribbles must have every one of
+    pub all: Vec<String>,
+    /// Tags scribbles must have at least one of, unless there are none
+    pub any: Vec<String>,
+    /// Tags scribbles must not have
+    pub none: Vec<String>,
+    /// Only scribbles without tags
+    pub untagged: bool,
+    /// Whether the tags below those given count as them
+    pub descendants: bool,
+}
+
+impl TagFilter {
+    pub(crate) fn matches(&self, tags: &[Tag]) -> bool {
+        let has = |text: &String| {
+            tags.iter().any(|tag| &tag.text == text || (self.descendants && tag_tree::is_below(&tag.text, text)))
+        };
+        self.all.iter().all(has)
+            && (self.any.is_empty() || self.any.iter().any(has))
+            && !self.none.iter().any(has)
+            && (!self.untagged || tags.is_empty())
+    }
+
+    /// Returns the filter with every tag text replaced by what `canonical`
+    /// makes of it, as stores do to look tags up the way they were tagged.
+    pub(crate) fn map_texts<F>(&self, canonical: F) -> Result<TagFilter>
+        where F: Fn(&str) -> Result<String>,
+    {
+        let map = |texts: &[String]| texts.iter().map(|text| canonical(text)).collect::<Result<Vec<_>>>();
+        Ok(TagFilter {
+            all: map(&self.all)?,
+            any: map(&self.any)?,
+            none: map(&self.none)?,
+            ..*self
+        })
+    }
+
+    /// Narrows down `query` to the scribbles with the tags asked for, looking
+    /// them up by tag through `taggings_tagid_scribbleid`.
+    pub(crate) fn apply<'a, DB>(&'a self, mut query: scribbles::BoxedQuery<'a, DB>) -> scribbles::BoxedQuery<'a, DB>
+        where DB: Backend + HasSqlType<Text> + HasSqlType<Integer> + 'a,
+              &'a String: ToSql<Text, DB>,
+              String: ToSql<Text, DB>,
+              i32: ToSql<Integer, DB>,
+    {
+        for text in &self.all {
+            query = query.filter(scribbles::id.eq_any(tagged_with(std::slice::from_ref(text), self.descendants)));
+        }
+        if !self.any.is_empty() {
+            query = query.filter(scribbles::id.eq_any(tagged_with(&self.any, self.descendants)));
+        }
+        if !self.none.is_empty() {
+            query = query.filter(not(scribbles::id.eq_any(tagged_with(&self.none, self.descendants))));
+        }
+        if self.untagged {
+            query = query.filter(not(exists(taggings::table.filter(taggings::scribble_id.eq(scribbles::id)))));
+        }
+        query
+    }
+}
+
+/// The ids of the scribbles tagged with any of `texts`, or with a tag below
+/// one of them if `descendants` is set.
+fn tagged_with<'a, DB>(texts: &'a [String], descendants: bool) -> taggings::BoxedQuery<'a, DB, BigInt>
+    where DB: Backend + HasSqlType<Text> + HasSqlType<Integer> + 'a,
+          &'a String: ToSql<Text, DB>,
+          String: ToSql<Text, DB>,
+          i32: ToSql<Integer, DB>,
+{
+    let mut tag_ids = tags::table
+        .filter(tags::text.eq_any(texts))
+        .select(tags::id)
+        .into_boxed();
+    if descendants {
+        for text in texts {
+            tag_ids = tag_ids.or_filter(tag_tree::below(text));
+        }
+    }
+    taggings::table
+        .filter(taggings::tag_id.eq_any(tag_ids))
+        .select(taggings::scribble_id)
+        .into_boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn tags(texts: &[&str]) -> Vec<Tag> {
+        texts.iter()
+            .enumerate()
+            .map(|(id, text)| Tag { id: id as i64, created_at: Utc.timestamp(0, 0), text: (*text).to_owned(), ttl: None })
+            .collect()
+    }
+
+    fn texts(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|&text| text.to_owned()).collect()
+    }
+
+    #[test]
+    fn matches_all_any_or_none_of_the_tags() {
+        let all = TagFilter { all: texts(&["work", "urgent"]), ..TagFilter::default() };
+        assert!(all.matches(&tags(&["work", "urgent", "home"])));
+        assert!(!all.matches(&tags(&["work"])));
+
+        let any = TagFilter { any: texts(&["work", "home"]), ..TagFilter::default() };
+        assert!(any.matches(&tags(&["home"])));
+        assert!(!any.matches(&tags(&["urgent"])));
+        assert!(!any.matches(&[]));
+
+        let none = TagFilter { none: texts(&["done"]), ..TagFilter::default() };
+        assert!(none.matches(&tags(&["work"])));
+        assert!(none.matches(&[]));
+        assert!(!none.matches(&tags(&["work", "done"])));
+
+        assert!(TagFilter::default().matches(&tags(&["work"])));
+        assert!(TagFilter::default().matches(&[]));
+    }
+
+    #[test]
+    fn untagged_matches_scribbles_without_tags() {
+        let untagged = TagFilter { untagged: true, ..TagFilter::default() };
+        assert!(untagged.matches(&[]));
+        assert!(!untagged.matches(&tags(&["work"])));
+    }
+
+    #[test]
+    fn descendants_count_as_the_tags_above_them() {
+        let filter = TagFilter { all: texts(&["work"]), ..TagFilter::default() };
+        assert!(!filter.matches(&tags(&["work/project"])));
+
+        let filter = TagFilter { descendants: true, ..filter };
+        assert!(filter.matches(&tags(&["work/project"])));
+        assert!(filter.matches(&tags(&["work/project/a"])));
+        assert!(!filter.matches(&tags(&["workshop"])));
+
+        let none = TagFilter { none: texts(&["work"]), descendants: true, ..TagFilter::default() };
+        assert!(!none.matches(&tags(&["work/project"])));
+    }
+
+    #[test]
+    fn combined_filters_all_have_to_match() {
+        let filter = TagFilter {
+            all: texts(&["work"]),
+            any: texts(&["urgent", "today"]),
+            none: texts(&["done"]),
+            ..TagFilter::default()
+        };
+        assert!(filter.matches(&tags(&["work", "today"])));
+        assert!(!filter.matches(&tags(&["work"])));
+        assert!(!filter.matches(&tags(&["urgent", "today"])));
+        assert!(!filter.matches(&tags(&["work", "urgent", "done"])));
+
+        // No scribble both has tags and has none
+        let filter = TagFilter { untagged: true, ..filter };
+        assert!(!filter.matches(&tags(&["work", "today"])));
+        assert!(!filter.matches(&[]));
+    }
+}
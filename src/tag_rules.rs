@@ -0,0 +1,193 @@
+//! How tag texts are normalized before tags are created or looked up, so that
+//! `Work`, `work` and ` work` end up as the same tag.
+//!
+//! The rules come from the environment:
+//!
+//! - `TAG_NORMALIZATION` lists the steps applied, out of `nfc` (Unicode
+//!   normalization form C), `fold_case` and `trim` (whitespace around every
+//!   part between slashes), separated by commas. All of them apply unless it
+//!   is set, and `none` turns them all off.
+//! - `TAG_CHARACTERS` lists the characters allowed besides letters, digits
+//!   and `/`, such as `-_.`. Anything but control characters is allowed
+//!   unless it is set.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::str::FromStr;
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::models::{Tag, TagAlias};
+use crate::{Error, Result};
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagRules {
+    pub nfc:        bool,
+    pub fold_case:  bool,
+    pub trim:       bool,
+    /// Characters allowed besides letters, digits and `/`, or `None` for
+    /// anything but control characters
+    pub characters: Option<String>,
+}
+
+impl Default for TagRules {
+    fn default() -> TagRules {
+        TagRules { nfc: true, fold_case: true, trim: true, characters: None }
+    }
+}
+
+impl FromStr for TagRules {
+    type Err = String;
+
+    /// Parses the steps of `TAG_NORMALIZATION`, allowing any character.
+    fn from_str(s: &str) -> std::result::Result<TagRules, String> {
+        let mut rules = TagRules { nfc: false, fold_case: false, trim: false, characters: None };
+        for step in s.split(',').map(str::trim).filter(|step| !step.is_empty()) {
+            match step {
+                "nfc" => rules.nfc = true,
+                "fold_case" => rules.fold_case = true,
+                "trim" => rules.trim = true,
+                "none" => {},
+                _ => return Err(format!("expected `nfc`, `fold_case`, `trim` or `none`, not `{}`", step)),
+            }
+        }
+        Ok(rules)
+    }
+}
+
+impl TagRules {
+    /// Reads the rules from `TAG_NORMALIZATION` and `TAG_CHARACTERS`.
+    pub fn from_env() -> std::result::Result<TagRules, String> {
+        let mut rules = match env::var("TAG_NORMALIZATION") {
+            Ok(steps) => steps.parse()?,
+            Err(_) => TagRules::default(),
+        };
+        rules.characters = env::var("TAG_CHARACTERS").ok();
+        Ok(rules)
+    }
+
+    fn allows(&self, c: char) -> bool {
+        match &self.characters {
+            None => !c.is_control(),
+            Some(characters) => c.is_alphanumeric() || c == '/' || characters.contains(c),
+        }
+    }
+
+    /// Returns `text` normalized, failing with `InvalidTag` if it is empty,
+    /// has an empty part between slashes or a character not allowed.
+    pub fn normalize(&self, text: &str) -> Result<String> {
+        let mut normalized: String = if self.nfc { text.nfc().collect() } else { text.to_owned() };
+        if self.fold_case {
+            normalized = normalized.to_lowercase();
+        }
+        if self.trim {
+            normalized = normalized.split('/').map(str::trim).collect::<Vec<_>>().join("/");
+        }
+
+        if normalized.split('/').any(str::is_empty) || !normalized.chars().all(|c| self.allows(c)) {
+            return Err(Error::InvalidTag(text.to_owned()));
+        }
+        Ok(normalized)
+    }
+
+    pub fn normalize_all(&self, texts: &[String]) -> Result<Vec<String>> {
+        texts.iter().map(|text| self.normalize(text)).collect()
+    }
+}
+
+/// A tag to merge into another, whose text it has once normalized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fold {
+    pub from: String,
+    pub into: String,
+}
+
+/// What folding the existing tags together would do.
+#[derive(Debug, Default)]
+pub struct FoldPlan {
+    /// Merges in the order to make them
+    pub folds:   Vec<Fold>,
+    /// Tags left alone as they cannot be normalized
+    pub invalid: Vec<String>,
+}
+
+/// Plans merging every tag into the tag named by its normalized text, or
+/// into the tag that text is an alias of. The first merge into a tag that
+/// does not exist yet renames the tag instead, which is the oldest one.
+pub fn plan_folds(rules: &TagRules, tags: &[Tag], aliases: &[TagAlias]) -> FoldPlan {
+    let mut plan = FoldPlan::default();
+    let mut tags: Vec<&Tag> = tags.iter().collect();
+    tags.sort_by_key(|tag| tag.id);
+
+    let mut groups: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for tag in tags {
+        match rules.normalize(&tag.text) {
+            Ok(normalized) => groups.entry(normalized).or_default().push(&tag.text),
+            Err(_) => plan.invalid.push(tag.text.clone()),
+        }
+    }
+
+    for (normalized, texts) in groups {
+        let into = aliases.iter()
+            .find(|alias| alias.alias == normalized)
+            .map_or(normalized, |alias| alias.tag.text.clone());
+        plan.folds.extend(texts.into_iter()
+                          .filter(|&text| text != into)
+                          .map(|text| Fold { from: text.to_owned(), into: into.clone() }));
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn tag(id: i64, text: &str) -> Tag {
+        Tag { id, created_at: Utc.timestamp(id, 0), text: text.to_owned(), ttl: None }
+    }
+
+    fn fold(from: &str, into: &str) -> Fold {
+        Fold { from: from.to_owned(), into: into.to_owned() }
+    }
+
+    #[test]
+    fn normalizes_by_the_default_rules() {
+        let rules = TagRules::default();
+        assert_eq!(rules.normalize(" Work / To Do ").unwrap(), "work/to do");
+        // "e" followed by a combining acute accent
+        assert_eq!(rules.normalize("Cafe\u{301}").unwrap(), "caf\u{e9}");
+        for text in &["", " ", "a//b", "a/", "/a", "a\tb\u{7}"] {
+            assert!(matches!(rules.normalize(text), Err(Error::InvalidTag(t)) if t == *text), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn applies_only_the_steps_set() {
+        let rules: TagRules = "trim".parse().unwrap();
+        assert_eq!(rules.normalize(" Work ").unwrap(), "Work");
+        let rules: TagRules = "none".parse().unwrap();
+        assert_eq!(rules.normalize(" Work ").unwrap(), " Work ");
+        assert!("trim,shout".parse::<TagRules>().is_err());
+
+        let rules = TagRules { characters: Some("-".to_owned()), ..TagRules::default() };
+        assert_eq!(rules.normalize("to-do/x1").unwrap(), "to-do/x1");
+        assert!(rules.normalize("to do").is_err());
+    }
+
+    #[test]
+    fn plans_folds_into_normalized_texts_and_aliases() {
+        let tags = [tag(3, "work"), tag(1, "Work"), tag(2, " WORK"), tag(4, "Home"), tag(5, "JS"), tag(6, "javascript"), tag(7, "a//b")];
+        let aliases = [TagAlias { alias: "js".to_owned(), created_at: Utc.timestamp(0, 0), tag: tag(6, "javascript") }];
+
+        let plan = plan_folds(&TagRules::default(), &tags, &aliases);
+        assert_eq!(plan.folds, vec![
+            fold("Home", "home"),
+            fold("JS", "javascript"),
+            fold("Work", "work"),
+            fold(" WORK", "work"),
+        ]);
+        assert_eq!(plan.invalid, vec!["a//b".to_owned()]);
+    }
+}
@@ -0,0 +1,153 @@
+//! Tags read as paths in a hierarchy, `project/forghetti/bugs` being below
+//! `project/forghetti`, which is below `project`. The tags above a tag need
+//! not exist for it to be below them.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::{DateTime, Utc};
+use diesel::backend::Backend;
+use diesel::dsl::sql;
+use diesel::expression::BoxableExpression;
+use diesel::serialize::ToSql;
+use diesel::sql_types::{Bool, HasSqlType, Integer, Text};
+
+use crate::models::{Tag, TagUsage};
+use crate::schema::tags;
+
+
+/// Whether `text` is below `ancestor`, at any depth.
+pub fn is_below(text: &str, ancestor: &str) -> bool {
+    text.len() > ancestor.len() + 1 && text.starts_with(ancestor) && text[ancestor.len()..].starts_with('/')
+}
+
+/// Returns the text a tag below `from` gets when `from` becomes `to`.
+pub(crate) fn moved(text: &str, from: &str, to: &str) -> String {
+    format!("{}{}", to, &text[from.len()..])
+}
+
+/// Matches the tags below `text`, in SQL that both backends understand.
+///
+/// `LIKE` is left alone as it ignores case in SQLite.
+pub(crate) fn below<DB>(text: &str) -> Box<dyn BoxableExpression<tags::table, DB, SqlType = Bool>>
+    where DB: Backend + HasSqlType<Integer> + HasSqlType<Text>,
+          i32: ToSql<Integer, DB>,
+          String: ToSql<Text, DB>,
+{
+    let prefix = format!("{}/", text);
+    Box::new(sql::<Bool>("substr(tags.text, 1, ")
+             .bind::<Integer, _>(prefix.chars().count() as i32)
+             .sql(") = ")
+             .bind::<Text, _>(prefix))
+}
+
+/// A tag in the hierarchy along with those below it.
+#[derive(Serialize, Clone, Debug)]
+pub struct TagNode {
+    /// The last part of the path
+    pub name:         String,
+    /// The whole path, which is the text of the tag
+    pub path:         String,
+    /// The tag, unless the node only stands for the tags below it
+    pub tag:          Option<Tag>,
+    /// How many scribbles carry the tag itself
+    pub count:        i64,
+    /// How many times the tag and those below it were put on scribbles, a
+    /// scribble counting once for each of them it carries
+    pub total:        i64,
+    /// When the tag or one below it was last put on a scribble
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub children:     Vec<TagNode>,
+}
+
+/// Arranges the usage of tags into trees, adding nodes for the tags above
+/// them that do not exist. Siblings are in the order of their names.
+pub fn build(usage: Vec<TagUsage>) -> Vec<TagNode> {
+    let mut paths = BTreeSet::new();
+    for usage in &usage {
+        let text = usage.tag.text.as_str();
+        paths.extend(text.match_indices('/').map(|(i, _)| &text[..i]));
+        paths.insert(text);
+    }
+    let mut usage: BTreeMap<String, TagUsage> = usage.iter()
+        .map(|usage| (usage.tag.text.clone(), usage.clone()))
+        .collect();
+    children(None, &paths, &mut usage)
+}
+
+fn parent(path: &str) -> Option<&str> {
+    path.rfind('/').map(|i| &path[..i])
+}
+
+fn children(of: Option<&str>, paths: &BTreeSet<&str>, usage: &mut BTreeMap<String, TagUsage>) -> Vec<TagNode> {
+    paths.iter()
+        .filter(|path| parent(path) == of)
+        .map(|path| node(path, paths, usage))
+        .collect()
+}
+
+fn node(path: &str, paths: &BTreeSet<&str>, usage: &mut BTreeMap<String, TagUsage>) -> TagNode {
+    let children = children(Some(path), paths, usage);
+    let own = usage.remove(path);
+    let count = own.as_ref().map_or(0, |own| own.count);
+    let last_used_at = own.as_ref().and_then(|own| own.last_used_at)
+        .into_iter()
+        .chain(children.iter().filter_map(|child| child.last_used_at))
+        .max();
+    TagNode {
+        name: path[parent(path).map_or(0, |parent| parent.len() + 1)..].to_owned(),
+        path: path.to_owned(),
+        tag: own.map(|own| own.tag),
+        count,
+        total: count + children.iter().map(|child| child.total).sum::<i64>(),
+        last_used_at,
+        children,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn usage(id: i64, text: &str, count: i64, last_used_at: Option<DateTime<Utc>>) -> TagUsage {
+        let tag = Tag { id, created_at: Utc.timestamp(0, 0), text: text.to_owned(), ttl: None };
+        TagUsage { tag, count, last_used_at }
+    }
+
+    #[test]
+    fn tells_tags_below_others() {
+        assert!(is_below("work/a", "work"));
+        assert!(is_below("work/a/b", "work"));
+        assert!(!is_below("work", "work"));
+        assert!(!is_below("workshop", "work"));
+        assert!(!is_below("work/", "work"));
+        assert!(!is_below("home/work", "work"));
+    }
+
+    #[test]
+    fn moves_texts_below_a_renamed_tag() {
+        assert_eq!(moved("work/a/b", "work", "job"), "job/a/b");
+        assert_eq!(moved("work/a", "work", "old/work"), "old/work/a");
+    }
+
+    #[test]
+    fn builds_trees_with_implicit_parents() {
+        let early = Utc.timestamp(1_000, 0);
+        let late = Utc.timestamp(2_000, 0);
+        let trees = build(vec![
+            usage(1, "work/b", 2, Some(early)),
+            usage(2, "work/a/x", 1, Some(late)),
+            usage(3, "home", 0, None),
+        ]);
+
+        let names: Vec<&str> = trees.iter().map(|node| node.name.as_str()).collect();
+        assert_eq!(names, vec!["home", "work"]);
+        let work = &trees[1];
+        assert!(work.tag.is_none());
+        assert_eq!((work.count, work.total, work.last_used_at), (0, 3, Some(late)));
+        let paths: Vec<&str> = work.children.iter().map(|node| node.path.as_str()).collect();
+        assert_eq!(paths, vec!["work/a", "work/b"]);
+        assert_eq!(work.children[0].children[0].tag.as_ref().unwrap().id, 2);
+        assert_eq!(work.children[1].count, 2);
+    }
+}
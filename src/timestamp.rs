@@ -0,0 +1,137 @@
+//! Points in time, kept as `DateTime<Utc>` and written as RFC 3339 in JSON.
+//!
+//! PostgreSQL stores them as `timestamptz`. SQLite has no such type, so they
+//! are stored there as RFC 3339 text in UTC with six fractional digits, which
+//! sorts in the same order as the times it stands for.
+
+use std::io::Write;
+
+use chrono::prelude::*;
+use chrono::{Duration, SubsecRound};
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::Pg;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types;
+use diesel::sqlite::Sqlite;
+use serde::{Deserialize, Deserializer};
+
+
+/// The SQL type of timestamp columns, `timestamptz` in PostgreSQL and text in
+/// SQLite.
+#[derive(SqlType, QueryId, Debug, Clone, Copy)]
+#[postgres(oid = "1184", array_oid = "1185")]
+#[sqlite_type = "Text"]
+pub struct Timestamptz;
+
+// `table!` gives columns named after date and time types arithmetic with
+// intervals, as in PostgreSQL
+impl sql_types::ops::Add for Timestamptz {
+    type Rhs = sql_types::Interval;
+    type Output = Timestamptz;
+}
+
+impl sql_types::ops::Sub for Timestamptz {
+    type Rhs = sql_types::Interval;
+    type Output = Timestamptz;
+}
+
+/// A timestamp to compare columns with or to write to them.
+///
+/// Diesel cannot turn `DateTime<Utc>` into an expression of a SQL type
+/// defined outside of it, so queries built with its DSL take this instead.
+/// Rows still come back as `DateTime<Utc>`.
+#[derive(AsExpression, Debug, Clone, Copy)]
+#[sql_type = "Timestamptz"]
+pub struct SqlTimestamp(pub DateTime<Utc>);
+
+impl FromSql<Timestamptz, Pg> for DateTime<Utc> {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        FromSql::<sql_types::Timestamptz, Pg>::from_sql(bytes)
+    }
+}
+
+impl ToSql<Timestamptz, Pg> for DateTime<Utc> {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        ToSql::<sql_types::Timestamptz, Pg>::to_sql(self, out)
+    }
+}
+
+impl FromSql<Timestamptz, Sqlite> for DateTime<Utc> {
+    fn from_sql(value: Option<&<Sqlite as Backend>::RawValue>) -> deserialize::Result<Self> {
+        let text: String = FromSql::<sql_types::Text, Sqlite>::from_sql(value)?;
+        Ok(DateTime::parse_from_rfc3339(&text)?.with_timezone(&Utc))
+    }
+}
+
+impl ToSql<Timestamptz, Sqlite> for DateTime<Utc> {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Sqlite>) -> serialize::Result {
+        let text = self.format("%Y-%m-%dT%H:%M:%S%.6fZ").to_string();
+        ToSql::<sql_types::Text, Sqlite>::to_sql(&text, out)
+    }
+}
+
+impl<DB: Backend> ToSql<Timestamptz, DB> for SqlTimestamp where DateTime<Utc>: ToSql<Timestamptz, DB> {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, DB>) -> serialize::Result {
+        self.0.to_sql(out)
+    }
+}
+
+/// Returns the current time to the microsecond, which is as precise as the
+/// databases get.
+pub fn now() -> DateTime<Utc> {
+    Utc::now().trunc_subsecs(6)
+}
+
+/// Returns the latest time that can be stored, the end of year 9999.
+pub fn end_of_time() -> DateTime<Utc> {
+    Utc.ymd(9999, 12, 31).and_hms_micro(23, 59, 59, 999_999)
+}
+
+/// Adds `duration` to `time`, capped at the end of time.
+pub fn saturating_add(time: DateTime<Utc>, duration: Duration) -> DateTime<Utc> {
+    time.checked_add_signed(duration)
+        .filter(|sum| *sum < end_of_time())
+        .unwrap_or_else(end_of_time)
+}
+
+/// Returns the time `nanos` nanoseconds after the epoch, as timestamps used to
+/// be given, to the microsecond.
+pub fn from_nanos(nanos: i64) -> DateTime<Utc> {
+    Utc.timestamp(nanos.div_euclid(1_000_000_000), nanos.rem_euclid(1_000_000_000) as u32).trunc_subsecs(6)
+}
+
+/// Returns the nanoseconds since the epoch of `time`, capped at the largest
+/// `i64` after the year 2262.
+pub fn to_nanos(time: DateTime<Utc>) -> i64 {
+    time.timestamp()
+        .checked_mul(1_000_000_000)
+        .and_then(|nanos| nanos.checked_add(i64::from(time.timestamp_subsec_nanos())))
+        .unwrap_or(i64::MAX)
+}
+
+/// Returns the microseconds since the epoch of `time`.
+pub(crate) fn to_micros(time: DateTime<Utc>) -> i64 {
+    time.timestamp() * 1_000_000 + i64::from(time.timestamp_subsec_micros())
+}
+
+/// Returns the time `micros` microseconds after the epoch, if there is one.
+pub(crate) fn from_micros(micros: i64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(micros.div_euclid(1_000_000), micros.rem_euclid(1_000_000) as u32 * 1000).single()
+}
+
+/// Deserializes an optional timestamp given either in RFC 3339 or, as it used
+/// to be, in nanoseconds since the epoch.
+pub fn deserialize_option<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Timestamp {
+        Nanos(i64),
+        Rfc3339(DateTime<Utc>),
+    }
+
+    Ok(Option::<Timestamp>::deserialize(deserializer)?.map(|timestamp| match timestamp {
+        Timestamp::Nanos(nanos) => from_nanos(nanos),
+        Timestamp::Rfc3339(time) => time.trunc_subsecs(6),
+    }))
+}